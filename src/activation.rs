@@ -0,0 +1,206 @@
+//! systemd socket activation: listening sockets passed in by systemd instead of bound by the
+//! bridge, see sd_listen_fds(3). TCP sockets named `data`, `error`, `http` or `websocket` with
+//! `FileDescriptorName=` are used for those listeners; the first other one for the data socket.
+//! A Unix socket is used for `--unix-socket`.
+
+use std::net::TcpListener as StdTcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener as StdUnixListener;
+
+use log::{info, warn};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::time::Duration;
+
+use crate::server::bind_listener;
+
+/// An inherited listening socket, by its address family
+#[derive(Debug)]
+enum Listener {
+    Tcp(StdTcpListener),
+    #[cfg(unix)]
+    Unix(StdUnixListener),
+}
+
+/// Whether `fd` is a socket bound to a Unix socket address rather than an IP address
+#[cfg(unix)]
+fn is_unix_socket(fd: i32) -> bool {
+    let mut address: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result =
+        unsafe { libc::getsockname(fd, &mut address as *mut _ as *mut libc::sockaddr, &mut len) };
+    result == 0 && i32::from(address.ss_family) == libc::AF_UNIX
+}
+
+/// The environment variables systemd passes the sockets with
+#[derive(Debug, Default)]
+pub struct ListenEnv {
+    pid: Option<String>,
+    count: Option<String>,
+    names: String,
+}
+
+impl ListenEnv {
+    /// Reads the variables and clears them so they don't leak to anything started later.
+    /// Changing the environment is only sound while no other thread may be reading it, so this
+    /// has to run before the runtime is built.
+    pub fn take() -> ListenEnv {
+        let env = ListenEnv {
+            pid: std::env::var("LISTEN_PID").ok(),
+            count: std::env::var("LISTEN_FDS").ok(),
+            names: std::env::var("LISTEN_FDNAMES").unwrap_or_default(),
+        };
+        for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(var);
+        }
+        env
+    }
+}
+
+/// Listening sockets inherited from systemd, with their names
+#[derive(Debug, Default)]
+pub struct Inherited {
+    listeners: Vec<(String, Listener)>,
+}
+
+impl Inherited {
+    /// Picks up the sockets systemd passed to this process, if any
+    #[cfg(unix)]
+    pub fn from_env(env: ListenEnv) -> Inherited {
+        use std::os::unix::io::FromRawFd;
+
+        const SD_LISTEN_FDS_START: i32 = 3;
+
+        let ListenEnv { pid, count, names } = env;
+        let (pid, count) = match (pid, count) {
+            (Some(pid), Some(count)) => (pid, count),
+            _ => return Inherited::default(),
+        };
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            warn!("Ignoring LISTEN_FDS meant for process {}", pid);
+            return Inherited::default();
+        }
+        let count: i32 = match count.parse() {
+            Ok(count) => count,
+            Err(_) => {
+                warn!("Ignoring malformed LISTEN_FDS {:?}", count);
+                return Inherited::default();
+            }
+        };
+
+        let mut names = names.split(':');
+        let listeners = (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+            .map(|fd| {
+                let name = names.next().unwrap_or("").to_string();
+                // systemd hands these over for this process to own
+                let listener = if is_unix_socket(fd) {
+                    Listener::Unix(unsafe { StdUnixListener::from_raw_fd(fd) })
+                } else {
+                    Listener::Tcp(unsafe { StdTcpListener::from_raw_fd(fd) })
+                };
+                (name, listener)
+            })
+            .collect::<Vec<_>>();
+        info!(
+            "Socket activated with {} socket(s): {:?}",
+            listeners.len(),
+            listeners.iter().map(|(name, _)| name).collect::<Vec<_>>()
+        );
+        Inherited { listeners }
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_env(_env: ListenEnv) -> Inherited {
+        Inherited::default()
+    }
+
+    fn take(&mut self, name: &str) -> Option<StdTcpListener> {
+        let tcp = |listener: &Listener| matches!(listener, Listener::Tcp(_));
+        let index = self
+            .listeners
+            .iter()
+            .position(|(n, l)| n == name && tcp(l))
+            .or_else(|| match name {
+                "data" => self.listeners.iter().position(|(n, l)| {
+                    tcp(l) && !matches!(n.as_str(), "error" | "http" | "websocket")
+                }),
+                _ => None,
+            })?;
+        match self.listeners.remove(index).1 {
+            Listener::Tcp(listener) => Some(listener),
+            #[cfg(unix)]
+            Listener::Unix(_) => unreachable!("only TCP listeners are looked up"),
+        }
+    }
+
+    #[cfg(unix)]
+    fn take_unix(&mut self) -> Option<StdUnixListener> {
+        let index = self
+            .listeners
+            .iter()
+            .position(|(_, l)| matches!(l, Listener::Unix(_)))?;
+        match self.listeners.remove(index).1 {
+            Listener::Unix(listener) => Some(listener),
+            Listener::Tcp(_) => unreachable!("only Unix listeners are looked up"),
+        }
+    }
+}
+
+/// Uses the inherited socket for `name` if systemd passed one, and binds `addr` otherwise
+pub async fn listen(
+    inherited: &mut Inherited,
+    name: &str,
+    addr: &str,
+    retries: u32,
+    retry_delay: Duration,
+) -> std::io::Result<TcpListener> {
+    match inherited.take(name) {
+        Some(listener) => {
+            info!(
+                "Using the {} socket passed by systemd instead of {:?}",
+                name, addr
+            );
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)
+        }
+        None => bind_listener(addr, retries, retry_delay).await,
+    }
+}
+
+/// Uses the inherited Unix socket if systemd passed one, and binds `path` otherwise
+#[cfg(unix)]
+pub fn listen_unix(
+    inherited: &mut Inherited,
+    path: &std::path::Path,
+) -> std::io::Result<UnixListener> {
+    match inherited.take_unix() {
+        Some(listener) => {
+            info!(
+                "Using the Unix socket passed by systemd instead of {:?}",
+                path
+            );
+            listener.set_nonblocking(true)?;
+            UnixListener::from_std(listener)
+        }
+        None => crate::server::bind_unix(path),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn tells_unix_sockets_from_tcp_sockets() {
+        let path = std::env::temp_dir().join(format!("activation-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = StdUnixListener::bind(&path).unwrap();
+        let tcp = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(is_unix_socket(unix.as_raw_fd()));
+        assert!(!is_unix_socket(tcp.as_raw_fd()));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,58 @@
+//! Altitude and pressure conversions using the international barometric formula.
+//!
+//! These assume the standard atmosphere, so a station's estimated altitude drifts by tens of
+//! meters as the weather changes the actual sea-level pressure. Treat them as uncalibrated.
+
+const EXPONENT: f64 = 1.0 / 5.255;
+const SCALE_METERS: f64 = 44_330.0;
+
+/// Altitude in meters at which `pressure_pa` would be measured, given the pressure at sea level
+pub fn altitude_m(pressure_pa: f64, sea_level_pa: f64) -> f64 {
+    SCALE_METERS * (1.0 - (pressure_pa / sea_level_pa).powf(EXPONENT))
+}
+
+/// Pressure reduced to sea level from `pressure_pa` measured at `altitude_m`; the inverse of
+/// [`altitude_m`]
+pub fn sea_level_pressure_pa(pressure_pa: f64, altitude_m: f64) -> f64 {
+    pressure_pa / (1.0 - altitude_m / SCALE_METERS).powf(1.0 / EXPONENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STANDARD_PA: f64 = 101_325.0;
+
+    #[test]
+    fn altitude_at_sea_level_pressure_is_zero() {
+        assert_eq!(altitude_m(STANDARD_PA, STANDARD_PA), 0.0);
+    }
+
+    #[test]
+    fn altitude_of_standard_atmosphere_pressures() {
+        // The standard atmosphere has 898.75 hPa at 1000 m and 794.95 hPa at 2000 m
+        assert!((altitude_m(89_875.0, STANDARD_PA) - 1000.0).abs() < 1.0);
+        assert!((altitude_m(79_495.0, STANDARD_PA) - 2000.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn altitude_below_sea_level_is_negative() {
+        assert!(altitude_m(102_000.0, STANDARD_PA) < 0.0);
+    }
+
+    #[test]
+    fn sea_level_pressure_at_sea_level_is_unchanged() {
+        assert_eq!(sea_level_pressure_pa(100_044.0, 0.0), 100_044.0);
+    }
+
+    #[test]
+    fn sea_level_pressure_of_standard_atmosphere_pressure() {
+        assert!((sea_level_pressure_pa(89_875.0, 1000.0) - STANDARD_PA).abs() < 5.0);
+    }
+
+    #[test]
+    fn sea_level_pressure_inverts_altitude() {
+        let altitude = altitude_m(95_000.0, 100_500.0);
+        assert!((sea_level_pressure_pa(95_000.0, altitude) - 100_500.0).abs() < 0.01);
+    }
+}
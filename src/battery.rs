@@ -0,0 +1,58 @@
+//! Battery state against the --low-battery-mv threshold, for the warnings in the log and the
+//! battery_status field alike.
+
+use log::{info, warn};
+
+use ruuvi_sensor_protocol::{BatteryPotential, MacAddress, SensorValues};
+
+use crate::lru::MacLru;
+use crate::mac;
+
+/// `"low"` below the threshold, `"ok"` at or above it and `None` without a battery potential
+pub fn status(millivolts: Option<u16>, low_battery_mv: u16) -> Option<&'static str> {
+    millivolts.map(|mv| if mv < low_battery_mv { "low" } else { "ok" })
+}
+
+/// Remembers which tags are low on battery so each is only warned about once it gets low
+#[derive(Debug)]
+pub struct Monitor {
+    low_battery_mv: u16,
+    low: MacLru<()>,
+}
+
+impl Monitor {
+    pub fn new(low_battery_mv: u16, max_tracked_macs: usize) -> Monitor {
+        Monitor {
+            low_battery_mv,
+            low: MacLru::new(max_tracked_macs),
+        }
+    }
+
+    pub fn observe(&mut self, sv: &SensorValues) {
+        let (mac, millivolts) = match (sv.mac_address(), sv.battery_potential_as_millivolts()) {
+            (Some(mac), Some(millivolts)) => (mac, millivolts),
+            _ => return,
+        };
+        match status(Some(millivolts), self.low_battery_mv) {
+            Some("low") => {
+                let newly_low = !self.low.contains_key(&mac);
+                self.low.insert(mac, ());
+                if newly_low {
+                    warn!(
+                        "Tag {} is low on battery: {} mV",
+                        mac::format(&mac),
+                        millivolts
+                    )
+                }
+            }
+            Some("ok") if self.low.remove(&mac).is_some() => {
+                info!(
+                    "Tag {} battery is ok again: {} mV",
+                    mac::format(&mac),
+                    millivolts
+                )
+            }
+            _ => {}
+        }
+    }
+}
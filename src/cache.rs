@@ -0,0 +1,63 @@
+use std::sync::{Arc, Mutex};
+
+use log::{debug, warn};
+use tokio::sync::broadcast;
+use tokio::time::{interval, Duration};
+
+use ruuvi_sensor_protocol::MacAddress;
+
+use crate::lru::MacLru;
+use crate::reading::Reading;
+use crate::{mac, metrics, overflow};
+
+/// Newest reading per MAC address
+pub type LastValues = Arc<Mutex<MacLru<Reading>>>;
+
+pub fn new(max_tracked_macs: usize) -> LastValues {
+    Arc::new(Mutex::new(MacLru::new(max_tracked_macs)))
+}
+
+/// Keeps `cache` up to date from the broadcast until the channel closes. Readings without a MAC
+/// address (data format 3) can't be told apart, so they aren't cached.
+pub async fn run(mut receiver: broadcast::Receiver<Reading>, cache: LastValues) {
+    loop {
+        match overflow::recv(&mut receiver).await {
+            Ok(reading) => {
+                if let Some(mac) = reading.values.mac_address() {
+                    if let Some((evicted, _)) = cache.lock().unwrap().insert(mac, reading) {
+                        debug!(
+                            "Evicting {} from the last value cache, too many tags",
+                            mac::format(&evicted)
+                        );
+                    }
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("Last value cache lagged, skipped {} readings", n);
+                metrics::inc_broadcast_skipped(n);
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Sweeps every tenth of the TTL, but at least every minute and at most every second
+fn sweep_interval(ttl: Duration) -> Duration {
+    (ttl / 10).clamp(Duration::from_secs(1), Duration::from_secs(60))
+}
+
+/// Forgets tags whose newest reading is older than `ttl`, e.g. a neighbor's tag seen once, so
+/// they don't linger in the snapshot and the metrics forever
+pub async fn evict(cache: LastValues, ttl: Duration) {
+    let mut sweeps = interval(sweep_interval(ttl));
+    loop {
+        sweeps.tick().await;
+        cache.lock().unwrap().retain(|mac, reading| {
+            let fresh = reading.parsed_at.elapsed() <= ttl;
+            if !fresh {
+                debug!("Evicting {} from the last value cache", mac::format(mac));
+            }
+            fresh
+        });
+    }
+}
@@ -0,0 +1,52 @@
+//! `--catchup-secs`: the readings of the last few seconds in the order they were broadcast, for
+//! clients that reconnect and ask for what they missed with `CATCHUP`.
+
+use std::collections::VecDeque;
+
+use tokio::time::Duration;
+
+use crate::reading::Reading;
+
+/// Readings kept at most however many arrive within the window, so a burst can't grow the
+/// buffer without limit
+pub const MAX_READINGS: usize = 10_000;
+
+#[derive(Debug)]
+pub struct Recent {
+    window: Duration,
+    readings: VecDeque<Reading>,
+}
+
+impl Recent {
+    pub fn new(window: Duration) -> Recent {
+        Recent {
+            window,
+            readings: VecDeque::new(),
+        }
+    }
+
+    /// Forgets readings parsed longer ago than the window. They are kept in broadcast order, so
+    /// the oldest are at the front.
+    fn expire(&mut self) {
+        while let Some(oldest) = self.readings.front() {
+            if oldest.parsed_at.elapsed() <= self.window {
+                break;
+            }
+            self.readings.pop_front();
+        }
+    }
+
+    pub fn push(&mut self, reading: Reading) {
+        if self.readings.len() >= MAX_READINGS {
+            self.readings.pop_front();
+        }
+        self.readings.push_back(reading);
+        self.expire();
+    }
+
+    /// The readings of the window, oldest first
+    pub fn snapshot(&mut self) -> VecDeque<Reading> {
+        self.expire();
+        self.readings.clone()
+    }
+}
@@ -0,0 +1,98 @@
+//! A single comfort label from temperature and humidity, for simple displays. A heuristic with
+//! fixed bands, not a thermal comfort model: air movement, clothing and activity all matter too.
+
+/// Bands of --comfort-*, in the units of the readings
+#[derive(Clone, Copy, Debug)]
+pub struct Thresholds {
+    pub min_millicelsius: i32,
+    pub max_millicelsius: i32,
+    pub min_humidity_ppm: u32,
+    pub max_humidity_ppm: u32,
+}
+
+impl Thresholds {
+    pub fn new(
+        min_celsius: f64,
+        max_celsius: f64,
+        min_humidity_pct: f64,
+        max_humidity_pct: f64,
+    ) -> Thresholds {
+        Thresholds {
+            min_millicelsius: (min_celsius * 1000.0).round() as i32,
+            max_millicelsius: (max_celsius * 1000.0).round() as i32,
+            min_humidity_ppm: (min_humidity_pct * 10_000.0).round() as u32,
+            max_humidity_ppm: (max_humidity_pct * 10_000.0).round() as u32,
+        }
+    }
+}
+
+/// `"cold"` or `"hot"` outside the temperature band, otherwise `"dry"` or `"humid"` outside the
+/// humidity band and `"comfortable"` inside both; `None` unless both values are present.
+/// Temperature goes first as it is what people notice first.
+pub fn classify(
+    millicelsius: Option<i32>,
+    humidity_ppm: Option<u32>,
+    thresholds: &Thresholds,
+) -> Option<&'static str> {
+    let (t, h) = (millicelsius?, humidity_ppm?);
+    Some(if t < thresholds.min_millicelsius {
+        "cold"
+    } else if t > thresholds.max_millicelsius {
+        "hot"
+    } else if h < thresholds.min_humidity_ppm {
+        "dry"
+    } else if h > thresholds.max_humidity_ppm {
+        "humid"
+    } else {
+        "comfortable"
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The defaults: 18–24 °C and 30–60 %
+    fn thresholds() -> Thresholds {
+        Thresholds::new(18.0, 24.0, 30.0, 60.0)
+    }
+
+    fn label(millicelsius: i32, humidity_ppm: u32) -> Option<&'static str> {
+        classify(Some(millicelsius), Some(humidity_ppm), &thresholds())
+    }
+
+    #[test]
+    fn thresholds_in_reading_units() {
+        let t = thresholds();
+        assert_eq!((t.min_millicelsius, t.max_millicelsius), (18_000, 24_000));
+        assert_eq!((t.min_humidity_ppm, t.max_humidity_ppm), (300_000, 600_000));
+    }
+
+    #[test]
+    fn temperature_band_edges() {
+        assert_eq!(label(17_999, 450_000), Some("cold"));
+        assert_eq!(label(18_000, 450_000), Some("comfortable"));
+        assert_eq!(label(24_000, 450_000), Some("comfortable"));
+        assert_eq!(label(24_001, 450_000), Some("hot"));
+    }
+
+    #[test]
+    fn humidity_band_edges() {
+        assert_eq!(label(21_000, 299_999), Some("dry"));
+        assert_eq!(label(21_000, 300_000), Some("comfortable"));
+        assert_eq!(label(21_000, 600_000), Some("comfortable"));
+        assert_eq!(label(21_000, 600_001), Some("humid"));
+    }
+
+    #[test]
+    fn temperature_goes_before_humidity() {
+        assert_eq!(label(10_000, 900_000), Some("cold"));
+        assert_eq!(label(30_000, 100_000), Some("hot"));
+    }
+
+    #[test]
+    fn needs_both_values() {
+        assert_eq!(classify(None, Some(450_000), &thresholds()), None);
+        assert_eq!(classify(Some(21_000), None, &thresholds()), None);
+    }
+}
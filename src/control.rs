@@ -0,0 +1,422 @@
+//! Line based commands clients can send over the data socket.
+//!
+//! Replies are JSON lines with an `"event"` key so they can be told apart from readings:
+//! `{"event":"ok","command":"FILTER"}` or `{"event":"error","message":"..."}`.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::filter::{self, Filter};
+use crate::lru::MacLru;
+use crate::{mac, output};
+
+#[derive(Debug)]
+pub enum Command {
+    /// Only send readings matching the filter; `None` clears it
+    Filter(Option<Filter>),
+    /// Only include the listed keys in readings; `None` restores the full object
+    Fields(Option<Vec<String>>),
+    /// Add a tag to the connection's subscriptions
+    Subscribe([u8; 6]),
+    /// Remove a tag from the connection's subscriptions
+    Unsubscribe([u8; 6]),
+    /// Encode what follows in this format
+    Format(output::Format),
+    /// Send at most one reading per tag per interval; `None` sends them all
+    Rate(Option<Duration>),
+    /// Precede each reading line with the channel token of its tag
+    Multiplex(bool),
+    /// Replay the readings of the catch-up window before going on with the live ones
+    Catchup,
+    /// Pause or resume the Bluetooth scan, given the admin token
+    Scan { pause: bool, token: Token },
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Filter(_) => "FILTER",
+            Command::Fields(_) => "FIELDS",
+            Command::Subscribe(_) => "SUBSCRIBE",
+            Command::Unsubscribe(_) => "UNSUBSCRIBE",
+            Command::Format(_) => "FORMAT",
+            Command::Rate(_) => "RATE",
+            Command::Multiplex(_) => "MULTIPLEX",
+            Command::Catchup => "CATCHUP",
+            Command::Scan { .. } => "SCAN",
+        }
+    }
+}
+
+/// A command the bridge couldn't make sense of
+#[derive(Debug)]
+pub struct Rejected {
+    /// The uppercased command word, so callers can reset state tied to that command
+    pub verb: String,
+    pub message: String,
+}
+
+pub fn parse(line: &str) -> Result<Command, Rejected> {
+    let line = line.trim();
+    let (verb, args) = match line.split_once(char::is_whitespace) {
+        Some((verb, args)) => (verb, args.trim()),
+        None => (line, ""),
+    };
+    let verb = verb.to_ascii_uppercase();
+    let parsed = match verb.as_str() {
+        "FILTER" if args.is_empty() => Ok(Command::Filter(None)),
+        "FILTER" => filter::parse(args).map(|f| Command::Filter(Some(f))),
+        "FIELDS" if args.is_empty() => Ok(Command::Fields(None)),
+        "FIELDS" => parse_fields(args).map(|f| Command::Fields(Some(f))),
+        "SUBSCRIBE" => parse_mac(args).map(Command::Subscribe),
+        "UNSUBSCRIBE" => parse_mac(args).map(Command::Unsubscribe),
+        "FORMAT" => args.to_ascii_lowercase().parse().map(Command::Format),
+        "RATE" if args.is_empty() => Ok(Command::Rate(None)),
+        "RATE" => parse_rate(args).map(Command::Rate),
+        "MULTIPLEX" => parse_switch(args).map(Command::Multiplex),
+        "CATCHUP" if args.is_empty() => Ok(Command::Catchup),
+        "CATCHUP" => Err(format!("CATCHUP takes no arguments, got {:?}", args)),
+        "SCAN" => parse_scan(args),
+        _ => Err(format!("unknown command {:?}", verb)),
+    };
+    parsed.map_err(|message| Rejected { verb, message })
+}
+
+fn parse_fields(args: &str) -> Result<Vec<String>, String> {
+    args.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| match name {
+            "mac" => Ok("mac_address".to_string()),
+            _ if output::FIELDS.contains(&name) => Ok(name.to_string()),
+            _ => Err(format!(
+                "unknown field {:?}, expected one of {}",
+                name,
+                output::FIELDS.join(", ")
+            )),
+        })
+        .collect()
+}
+
+fn parse_mac(args: &str) -> Result<[u8; 6], String> {
+    mac::parse(args).ok_or_else(|| format!("malformed MAC address {:?}", args))
+}
+
+fn parse_rate(args: &str) -> Result<Option<Duration>, String> {
+    match args.parse::<u64>() {
+        Ok(0) => Ok(None),
+        Ok(ms) => Ok(Some(Duration::from_millis(ms))),
+        Err(_) => Err(format!("expected milliseconds, got {:?}", args)),
+    }
+}
+
+fn parse_switch(args: &str) -> Result<bool, String> {
+    match args.to_ascii_uppercase().as_str() {
+        "" | "ON" => Ok(true),
+        "OFF" => Ok(false),
+        _ => Err(format!("expected ON or OFF, got {:?}", args)),
+    }
+}
+
+/// `PAUSE <token>` or `RESUME <token>`; a missing token is left for the check to reject
+fn parse_scan(args: &str) -> Result<Command, String> {
+    let (action, token) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let pause = match action.to_ascii_uppercase().as_str() {
+        "PAUSE" => true,
+        "RESUME" => false,
+        _ => return Err(format!("expected PAUSE or RESUME, got {:?}", action)),
+    };
+    let token = Token(token.trim().to_string());
+    Ok(Command::Scan { pause, token })
+}
+
+/// The token of an `AUTH <token>` line, `None` for any other line
+pub fn parse_auth(line: &str) -> Option<Token> {
+    let (verb, token) = line.trim().split_once(char::is_whitespace)?;
+    verb.eq_ignore_ascii_case("AUTH")
+        .then(|| Token(token.trim().to_string()))
+}
+
+/// A shared secret, of --auth-token or --admin-token
+#[derive(Clone)]
+pub struct Token(String);
+
+impl Token {
+    /// Compares in constant time for tokens of the same length, so the time taken doesn't
+    /// tell how much of a guess was right
+    pub fn matches(&self, given: &Token) -> bool {
+        let (expected, given) = (self.0.as_bytes(), given.0.as_bytes());
+        expected.len() == given.len()
+            && expected
+                .iter()
+                .zip(given)
+                .fold(0, |differences, (a, b)| differences | (a ^ b))
+                == 0
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never the token itself, commands and options are logged
+        f.write_str("Token(..)")
+    }
+}
+
+impl FromStr for Token {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.contains(char::is_whitespace) {
+            return Err("expected a token without whitespace".to_string());
+        }
+        Ok(Token(s.to_string()))
+    }
+}
+
+/// The channel of a tag on a multiplexed connection: the 32-bit FNV-1a hash of its MAC address
+/// bytes as 8 lowercase hex digits, so it stays the same across restarts and versions. `-` for
+/// readings without a MAC address.
+pub fn channel_token(mac: Option<&[u8; 6]>) -> String {
+    match mac {
+        Some(mac) => {
+            let hash = mac.iter().fold(0x811c_9dc5u32, |hash, byte| {
+                (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+            });
+            format!("{:08x}", hash)
+        }
+        None => "-".to_string(),
+    }
+}
+
+pub fn ok_reply(command: &Command) -> Value {
+    let mut reply = json!({"event": "ok", "command": command.name()});
+    if let Command::Subscribe(mac) | Command::Unsubscribe(mac) = command {
+        reply["mac"] = json!(mac::format(mac));
+    }
+    if let Command::Scan { pause, .. } = command {
+        reply["scan"] = json!(if *pause { "paused" } else { "active" });
+    }
+    reply
+}
+
+pub fn error_reply(message: &str) -> Value {
+    json!({"event": "error", "message": message})
+}
+
+/// What a connection gets while it isn't subscribed to any tag
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DefaultSubscription {
+    All,
+    None,
+}
+
+impl FromStr for DefaultSubscription {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(DefaultSubscription::All),
+            "none" => Ok(DefaultSubscription::None),
+            _ => Err(format!("expected all or none, got {:?}", s)),
+        }
+    }
+}
+
+/// The tags a connection subscribed to with `SUBSCRIBE`
+#[derive(Debug)]
+pub struct Subscriptions {
+    macs: HashSet<[u8; 6]>,
+    default: DefaultSubscription,
+}
+
+impl Subscriptions {
+    pub fn new(default: DefaultSubscription) -> Subscriptions {
+        Subscriptions {
+            macs: HashSet::new(),
+            default,
+        }
+    }
+
+    pub fn subscribe(&mut self, mac: [u8; 6]) {
+        self.macs.insert(mac);
+    }
+
+    pub fn unsubscribe(&mut self, mac: &[u8; 6]) {
+        self.macs.remove(mac);
+    }
+
+    /// Whether readings of the tag go to the connection. A reading without a MAC address only
+    /// does while nothing is subscribed and the default is all.
+    pub fn includes(&self, mac: Option<&[u8; 6]>) -> bool {
+        if self.macs.is_empty() {
+            return self.default == DefaultSubscription::All;
+        }
+        mac.is_some_and(|mac| self.macs.contains(mac))
+    }
+}
+
+/// Per-tag rate limit of a connection set with `RATE`
+#[derive(Debug)]
+pub struct Throttle {
+    interval: Option<Duration>,
+    last_sent: MacLru<Instant>,
+}
+
+impl Throttle {
+    pub fn new(max_tracked_macs: usize) -> Throttle {
+        Throttle {
+            interval: None,
+            last_sent: MacLru::new(max_tracked_macs),
+        }
+    }
+
+    pub fn set_interval(&mut self, interval: Option<Duration>) {
+        self.interval = interval;
+        self.last_sent.clear();
+    }
+
+    /// Whether a reading of the tag may be sent now, recording it as sent if so. Readings
+    /// without a MAC address are never held back.
+    pub fn allow(&mut self, mac: Option<[u8; 6]>, now: Instant) -> bool {
+        let (interval, mac) = match (self.interval, mac) {
+            (Some(interval), Some(mac)) => (interval, mac),
+            _ => return true,
+        };
+        match self.last_sent.get(&mac) {
+            Some(sent) if now.duration_since(*sent) < interval => false,
+            _ => {
+                self.last_sent.insert(mac, now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(line: &str) -> Rejected {
+        parse(line).unwrap_err()
+    }
+
+    #[test]
+    fn parses_commands_case_insensitively() {
+        assert!(matches!(parse("filter"), Ok(Command::Filter(None))));
+        assert!(matches!(
+            parse("FILTER temperature_c>25"),
+            Ok(Command::Filter(Some(_)))
+        ));
+        assert!(matches!(parse(" FIELDS "), Ok(Command::Fields(None))));
+        match parse("fields mac, temperature_as_millicelsius,") {
+            Ok(Command::Fields(Some(fields))) => {
+                assert_eq!(fields, ["mac_address", "temperature_as_millicelsius"])
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            parse("SUBSCRIBE cb:b8:33:4c:88:4f"),
+            Ok(Command::Subscribe([0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F]))
+        ));
+        assert!(matches!(
+            parse("Unsubscribe CB-B8-33-4C-88-4F"),
+            Ok(Command::Unsubscribe([0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F]))
+        ));
+        assert!(matches!(
+            parse("FORMAT CSV"),
+            Ok(Command::Format(output::Format::Csv))
+        ));
+        assert!(matches!(parse("RATE"), Ok(Command::Rate(None))));
+        assert!(matches!(parse("RATE 0"), Ok(Command::Rate(None))));
+        assert!(matches!(
+            parse("RATE 1500"),
+            Ok(Command::Rate(Some(d))) if d == Duration::from_millis(1500)
+        ));
+        assert!(matches!(parse("MULTIPLEX"), Ok(Command::Multiplex(true))));
+        assert!(matches!(
+            parse("multiplex off"),
+            Ok(Command::Multiplex(false))
+        ));
+        assert!(matches!(parse("CATCHUP"), Ok(Command::Catchup)));
+        assert!(matches!(
+            parse("SCAN pause secret"),
+            Ok(Command::Scan { pause: true, .. })
+        ));
+        assert!(matches!(
+            parse("SCAN RESUME"),
+            Ok(Command::Scan { pause: false, .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_command() {
+        let hello = rejected("hello there");
+        assert_eq!(hello.verb, "HELLO");
+        assert_eq!(hello.message, "unknown command \"HELLO\"");
+        assert_eq!(rejected("").message, "unknown command \"\"");
+    }
+
+    #[test]
+    fn rejects_malformed_arguments() {
+        assert_eq!(rejected("filter temperature").verb, "FILTER");
+        assert!(rejected("FIELDS mac,bogus")
+            .message
+            .starts_with("unknown field \"bogus\""));
+        assert_eq!(
+            rejected("SUBSCRIBE cb:b8:33:4c:88").message,
+            "malformed MAC address \"cb:b8:33:4c:88\""
+        );
+        assert!(rejected("FORMAT yaml")
+            .message
+            .starts_with("unknown format \"yaml\""));
+        assert_eq!(
+            rejected("RATE soon").message,
+            "expected milliseconds, got \"soon\""
+        );
+        assert_eq!(
+            rejected("MULTIPLEX maybe").message,
+            "expected ON or OFF, got \"maybe\""
+        );
+        assert_eq!(
+            rejected("CATCHUP 10").message,
+            "CATCHUP takes no arguments, got \"10\""
+        );
+        assert_eq!(
+            rejected("SCAN stop secret").message,
+            "expected PAUSE or RESUME, got \"stop\""
+        );
+    }
+
+    #[test]
+    fn parses_auth_lines() {
+        let expected: Token = "secret".parse().unwrap();
+        assert!(expected.matches(&parse_auth("auth  secret ").unwrap()));
+        assert!(parse_auth("AUTH").is_none());
+        assert!(parse_auth("FILTER secret").is_none());
+    }
+
+    #[test]
+    fn tokens_match_only_when_equal() {
+        let expected: Token = "secret".parse().unwrap();
+        assert!(expected.matches(&Token("secret".to_string())));
+        assert!(!expected.matches(&Token("secreT".to_string())));
+        assert!(!expected.matches(&Token("secre".to_string())));
+        assert!(!expected.matches(&Token("secrets".to_string())));
+        assert!(!expected.matches(&Token(String::new())));
+        match parse("SCAN PAUSE") {
+            Ok(Command::Scan { token, .. }) => assert!(!expected.matches(&token)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_tokens_with_whitespace() {
+        assert!("".parse::<Token>().is_err());
+        assert!("two words".parse::<Token>().is_err());
+        assert_eq!(format!("{:?}", Token("secret".to_string())), "Token(..)");
+    }
+}
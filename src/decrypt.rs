@@ -0,0 +1,268 @@
+//! Data format 8, which carries the values of data format 5 but acceleration AES-128 encrypted
+//! with a key of each tag's own, https://docs.ruuvi.com/ruuvi-sensor-protocols/data-format-8
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::Aes128;
+use log::{debug, info};
+
+use crate::lru::MacLru;
+use crate::mac;
+
+pub const RUUVI_MANUFACTURER_ID: u16 = 0x0499;
+pub const DATA_FORMAT_8: u8 = 8;
+const DATA_FORMAT_5: u8 = 5;
+/// Format byte, 16 encrypted bytes, CRC8 of the encrypted bytes and the MAC address
+const PAYLOAD_LEN: usize = 24;
+const ENCRYPTED: std::ops::Range<usize> = 1..17;
+const CHECKSUM: usize = 17;
+const MAC: std::ops::Range<usize> = 18..24;
+
+#[derive(Debug)]
+pub enum DecryptError {
+    Truncated(usize),
+    NoKey([u8; 6]),
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecryptError::Truncated(len) => {
+                write!(f, "{} bytes instead of {}", len, PAYLOAD_LEN)
+            }
+            DecryptError::NoKey(mac) => write!(f, "no key for {}", mac::format(mac)),
+        }
+    }
+}
+
+/// CRC-8 with the polynomial 0x07 and no reflection, starting from 0
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Whether the CRC8 of a data format 8 payload matches its encrypted bytes, which doesn't need
+/// the key. A truncated payload doesn't match.
+pub fn checksum_matches(payload: &[u8]) -> bool {
+    match (payload.get(ENCRYPTED), payload.get(CHECKSUM)) {
+        (Some(encrypted), Some(checksum)) => crc8(encrypted) == *checksum,
+        _ => false,
+    }
+}
+
+/// The MAC address a data format 8 payload carries in plain text
+fn mac_address(payload: &[u8]) -> Option<[u8; 6]> {
+    <[u8; 6]>::try_from(payload.get(MAC)?).ok()
+}
+
+/// Tags whose data format 8 advertisements were skipped, so that each is logged at `info` only
+/// the first time and at `debug` after that
+pub struct Skipped(MacLru<()>);
+
+impl Skipped {
+    pub fn new(max_tracked_macs: usize) -> Skipped {
+        Skipped(MacLru::new(max_tracked_macs))
+    }
+
+    pub fn log(&mut self, payload: &[u8], reason: &dyn fmt::Display) {
+        match mac_address(payload) {
+            Some(mac) if !self.0.contains_key(&mac) => {
+                info!(
+                    "Skipping data format 8 advertisements from {}: {}",
+                    mac::format(&mac),
+                    reason
+                );
+                self.0.insert(mac, ());
+            }
+            _ => debug!("Skipping data format 8 advertisement: {}", reason),
+        }
+    }
+}
+
+/// Decryption keys by MAC address
+pub struct Keys(HashMap<[u8; 6], Aes128>);
+
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never the keys themselves
+        f.debug_list()
+            .entries(self.0.keys().map(mac::format))
+            .finish()
+    }
+}
+
+fn parse_key(s: &str) -> Option<[u8; 16]> {
+    if s.len() != 32 || !s.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; 16];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+impl Keys {
+    /// Reads a file of `AA:BB:CC:DD:EE:FF 00112233445566778899AABBCCDDEEFF` lines, a MAC address
+    /// and its key in hex. Empty lines and lines starting with `#` are skipped.
+    pub fn load(path: &Path) -> Result<Keys, Box<dyn Error>> {
+        let mut keys = HashMap::new();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = line
+                .split_once(char::is_whitespace)
+                .and_then(|(mac, key)| Some((mac::parse(mac)?, parse_key(key.trim())?)));
+            match parsed {
+                Some((mac, key)) => {
+                    keys.insert(mac, Aes128::new(GenericArray::from_slice(&key)));
+                }
+                None => {
+                    let message = format!(
+                        "{:?} line {}: expected a MAC address and a key of 32 hex digits",
+                        path,
+                        number + 1
+                    );
+                    return Err(message.into());
+                }
+            }
+        }
+        Ok(Keys(keys))
+    }
+
+    /// Decrypts a data format 8 payload into the data format 5 payload carrying the same
+    /// values, for the usual parser to take from there
+    pub fn decrypt(&self, payload: &[u8]) -> Result<Vec<u8>, DecryptError> {
+        if payload.len() != PAYLOAD_LEN {
+            return Err(DecryptError::Truncated(payload.len()));
+        }
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(&payload[MAC]);
+        let cipher = self.0.get(&mac).ok_or(DecryptError::NoKey(mac))?;
+        let mut block = GenericArray::clone_from_slice(&payload[ENCRYPTED]);
+        cipher.decrypt_block(&mut block);
+
+        // Temperature, humidity and pressure, then the power info, movement counter and
+        // measurement sequence number, laid out as in data format 5 but without acceleration
+        let mut decrypted = vec![DATA_FORMAT_5];
+        decrypted.extend_from_slice(&block[0..6]);
+        for _ in 0..3 {
+            decrypted.extend_from_slice(&i16::MIN.to_be_bytes());
+        }
+        decrypted.extend_from_slice(&block[6..11]);
+        decrypted.extend_from_slice(&mac);
+        Ok(decrypted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use aes::cipher::BlockEncrypt;
+    use ruuvi_sensor_protocol::{
+        Humidity, MeasurementSequenceNumber, Pressure, SensorValues, Temperature,
+    };
+
+    const KEY: [u8; 16] = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE,
+        0xFF,
+    ];
+    const MAC_ADDRESS: [u8; 6] = [0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F];
+    /// The values of the data format 5 example of the protocol documentation: temperature,
+    /// humidity and pressure, power info, movement counter, measurement sequence number and
+    /// reserved bytes
+    const PLAINTEXT: [u8; 16] = [
+        0x12, 0xFC, 0x53, 0x94, 0xC3, 0x7C, 0xAC, 0x36, 0x42, 0x00, 0xCD, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ];
+
+    fn keys() -> Keys {
+        let cipher = Aes128::new(GenericArray::from_slice(&KEY));
+        Keys(HashMap::from([(MAC_ADDRESS, cipher)]))
+    }
+
+    fn payload() -> Vec<u8> {
+        let cipher = Aes128::new(GenericArray::from_slice(&KEY));
+        let mut block = GenericArray::clone_from_slice(&PLAINTEXT);
+        cipher.encrypt_block(&mut block);
+        let mut payload = vec![DATA_FORMAT_8];
+        payload.extend_from_slice(&block);
+        payload.push(crc8(&block));
+        payload.extend_from_slice(&MAC_ADDRESS);
+        payload
+    }
+
+    #[test]
+    fn crc8_check_value() {
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc8(&[]), 0);
+    }
+
+    #[test]
+    fn checksum_matches_encrypted_bytes() {
+        assert!(checksum_matches(&payload()));
+    }
+
+    #[test]
+    fn checksum_rejects_corrupted_payload() {
+        let mut bad_crc = payload();
+        bad_crc[CHECKSUM] ^= 0x01;
+        assert!(!checksum_matches(&bad_crc));
+        let mut bad_byte = payload();
+        bad_byte[3] ^= 0x80;
+        assert!(!checksum_matches(&bad_byte));
+        assert!(!checksum_matches(&payload()[..CHECKSUM]));
+    }
+
+    #[test]
+    fn decrypts_to_data_format_5() {
+        let decrypted = keys().decrypt(&payload()).unwrap();
+        assert_eq!(
+            decrypted,
+            [
+                0x05, 0x12, 0xFC, 0x53, 0x94, 0xC3, 0x7C, 0x80, 0x00, 0x80, 0x00, 0x80, 0x00, 0xAC,
+                0x36, 0x42, 0x00, 0xCD, 0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F,
+            ]
+        );
+        let sv = SensorValues::from_manufacturer_specific_data(RUUVI_MANUFACTURER_ID, &decrypted)
+            .unwrap();
+        assert_eq!(sv.temperature_as_millicelsius(), Some(24300));
+        assert_eq!(sv.humidity_as_ppm(), Some(534900));
+        assert_eq!(sv.pressure_as_pascals(), Some(100044));
+        assert_eq!(sv.measurement_sequence_number(), Some(205));
+    }
+
+    #[test]
+    fn decrypt_needs_key_for_mac() {
+        let mut payload = payload();
+        payload[MAC].copy_from_slice(&[0xAA; 6]);
+        assert!(matches!(
+            keys().decrypt(&payload),
+            Err(DecryptError::NoKey([0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA]))
+        ));
+    }
+
+    #[test]
+    fn decrypt_rejects_truncated_payload() {
+        assert!(matches!(
+            keys().decrypt(&payload()[..20]),
+            Err(DecryptError::Truncated(20))
+        ));
+    }
+}
@@ -0,0 +1,187 @@
+//! The URL data formats 2 and 4 of old RuuviTag firmware, broadcast as Eddystone-URL service
+//! data pointing at `https://ruu.vi/#<payload>` instead of as manufacturer data,
+//! https://github.com/ruuvi/ruuvi-sensor-protocols/blob/master/dataformat_04.md
+
+use btleplug::api::bleuuid::uuid_from_u16;
+use uuid::Uuid;
+
+use ruuvi_sensor_protocol::SensorValues;
+
+use crate::merge;
+
+const EDDYSTONE_SERVICE: u16 = 0xFEAA;
+const URL_FRAME: u8 = 0x10;
+const RUUVI_URL: &str = "https://ruu.vi/#";
+
+/// URL scheme prefixes, by the code in the frame's third byte
+const SCHEMES: [&str; 4] = ["http://www.", "https://www.", "http://", "https://"];
+
+/// Expansions of the bytes 0x00 to 0x0D in the encoded URL
+const EXPANSIONS: [&str; 14] = [
+    ".com/", ".org/", ".edu/", ".net/", ".info/", ".biz/", ".gov/", ".com", ".org", ".edu", ".net",
+    ".info", ".biz", ".gov",
+];
+
+/// The URL of an Eddystone-URL frame: frame type, TX power, scheme and the encoded rest
+fn decode_url(frame: &[u8]) -> Option<String> {
+    let (scheme, encoded) = match frame {
+        [URL_FRAME, _tx_power, scheme, encoded @ ..] => (*scheme, encoded),
+        _ => return None,
+    };
+    let mut url = SCHEMES.get(usize::from(scheme))?.to_string();
+    for &b in encoded {
+        match EXPANSIONS.get(usize::from(b)) {
+            Some(expansion) => url.push_str(expansion),
+            None if b.is_ascii_graphic() => url.push(char::from(b)),
+            None => return None,
+        }
+    }
+    Some(url)
+}
+
+/// Base64 without padding, taking both the standard and the URL safe alphabet. Trailing bits
+/// that don't make up a whole byte are dropped, like the cut off random id of data format 4.
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut bits, mut bit_count) = (0u32, 0);
+    for c in s.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | u32::from(value);
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            bytes.push((bits >> bit_count) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+/// Sensor values and the data format of a RuuviTag Eddystone-URL frame; `None` for any other
+/// service data
+pub fn parse(service: &Uuid, data: &[u8]) -> Option<(SensorValues, u8)> {
+    if *service != uuid_from_u16(EDDYSTONE_SERVICE) {
+        return None;
+    }
+    let url = decode_url(data)?;
+    let payload = decode_base64(url.strip_prefix(RUUVI_URL)?)?;
+    // Humidity in 0.5 %, temperature as sign and magnitude in whole degrees plus hundredths,
+    // pressure as an offset from 50 kPa
+    let (data_format, humidity, temperature, fraction, pressure) = match payload[..] {
+        [data_format @ (2 | 4), humidity, temperature, fraction, p0, p1, ..] => (
+            data_format,
+            humidity,
+            temperature,
+            fraction,
+            u16::from_be_bytes([p0, p1]),
+        ),
+        _ => return None,
+    };
+    let magnitude = i64::from(temperature & 0x7F) * 1000 + i64::from(fraction) * 10;
+    let millicelsius = if temperature & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    };
+    let sv = merge::environmental(
+        (273_150 + millicelsius) as u32,
+        u32::from(humidity) * 5000,
+        u32::from(pressure) + 50_000,
+    );
+    Some((sv, data_format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ruuvi_sensor_protocol::{Humidity, Pressure, Temperature};
+
+    /// An Eddystone-URL frame for `https://ruu.vi/#<payload>`
+    fn frame(payload: &str) -> Vec<u8> {
+        let mut frame = vec![URL_FRAME, 0xEB, 3];
+        frame.extend_from_slice(b"ruu.vi/#");
+        frame.extend_from_slice(payload.as_bytes());
+        frame
+    }
+
+    #[test]
+    fn decode_url_expands_scheme_and_suffixes() {
+        assert_eq!(
+            decode_url(&frame("AjwYAMFc")).as_deref(),
+            Some("https://ruu.vi/#AjwYAMFc")
+        );
+        assert_eq!(
+            decode_url(&[URL_FRAME, 0, 0, b'r', b'u', b'u', b'v', b'i', 0x00]).as_deref(),
+            Some("http://www.ruuvi.com/")
+        );
+    }
+
+    #[test]
+    fn decode_url_rejects_other_frames() {
+        assert_eq!(decode_url(&[0x00, 0, 3, b'a']), None);
+        assert_eq!(decode_url(&[URL_FRAME, 0, 4, b'a']), None);
+        assert_eq!(decode_url(&[URL_FRAME, 0, 3, b' ']), None);
+        assert_eq!(decode_url(&[URL_FRAME, 0]), None);
+    }
+
+    #[test]
+    fn decode_base64_takes_both_alphabets() {
+        assert_eq!(
+            decode_base64("AjwYAMFc"),
+            Some(vec![0x02, 0x3C, 0x18, 0x00, 0xC1, 0x5C])
+        );
+        assert_eq!(decode_base64("-_8="), Some(vec![0xFB, 0xFF]));
+        assert_eq!(decode_base64("+/8"), Some(vec![0xFB, 0xFF]));
+        assert_eq!(decode_base64("a b"), None);
+    }
+
+    #[test]
+    fn decode_base64_drops_partial_byte() {
+        assert_eq!(
+            decode_base64("BDwYAMFcP"),
+            Some(vec![0x04, 0x3C, 0x18, 0x00, 0xC1, 0x5C])
+        );
+    }
+
+    #[test]
+    fn parses_data_format_2() {
+        let service = uuid_from_u16(EDDYSTONE_SERVICE);
+        let (sv, data_format) = parse(&service, &frame("AjwYAMFc")).unwrap();
+        assert_eq!(data_format, 2);
+        assert_eq!(sv.humidity_as_ppm(), Some(300_000));
+        assert_eq!(sv.temperature_as_millicelsius(), Some(24_000));
+        assert_eq!(sv.pressure_as_pascals(), Some(99_500));
+    }
+
+    #[test]
+    fn parses_data_format_4() {
+        let service = uuid_from_u16(EDDYSTONE_SERVICE);
+        let (sv, data_format) = parse(&service, &frame("BDwYAMFcP")).unwrap();
+        assert_eq!(data_format, 4);
+        assert_eq!(sv.temperature_as_millicelsius(), Some(24_000));
+    }
+
+    #[test]
+    fn parses_negative_temperature() {
+        // -1.50 °C: sign bit and 1 degree, 50 hundredths
+        let service = uuid_from_u16(EDDYSTONE_SERVICE);
+        let (sv, _) = parse(&service, &frame("AjyBMsFc")).unwrap();
+        assert_eq!(sv.temperature_as_millicelsius(), Some(-1500));
+    }
+
+    #[test]
+    fn ignores_other_services_and_urls() {
+        assert!(parse(&uuid_from_u16(0xFE9A), &frame("AjwYAMFc")).is_none());
+        let mut other = vec![URL_FRAME, 0xEB, 3];
+        other.extend_from_slice(b"example.com/#AjwYAMFc");
+        assert!(parse(&uuid_from_u16(EDDYSTONE_SERVICE), &other).is_none());
+        assert!(parse(&uuid_from_u16(EDDYSTONE_SERVICE), &frame("BQ")).is_none());
+    }
+}
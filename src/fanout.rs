@@ -0,0 +1,117 @@
+//! Fan-out of the readings to the data socket clients, each through a bounded queue of its own,
+//! so a slow client only overflows its own queue instead of holding back the others.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::Duration;
+
+use crate::catchup::Recent;
+use crate::reading::Reading;
+use crate::{metrics, overflow};
+
+/// The receiving end of a client's queue
+pub struct Client {
+    pub receiver: mpsc::Receiver<Reading>,
+    /// Readings dropped because the queue was full, since the client last took the count
+    pub dropped: Arc<AtomicU64>,
+    recent: Option<Arc<Mutex<Recent>>>,
+}
+
+impl Client {
+    /// Readings dropped since the previous call
+    pub fn take_dropped(&self) -> u64 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+
+    /// The readings of the catch-up window, oldest first; `None` without --catchup-secs. They
+    /// may overlap with the readings already in the queue.
+    pub fn catch_up(&self) -> Option<VecDeque<Reading>> {
+        Some(self.recent.as_ref()?.lock().unwrap().snapshot())
+    }
+}
+
+struct Queue {
+    peer: String,
+    sender: mpsc::Sender<Reading>,
+    dropped: Arc<AtomicU64>,
+}
+
+#[derive(Clone)]
+pub struct Fanout {
+    queues: Arc<Mutex<Vec<Queue>>>,
+    queue_size: usize,
+    recent: Option<Arc<Mutex<Recent>>>,
+}
+
+impl Fanout {
+    /// With `catchup`, the readings of that long ago are kept for clients to catch up on
+    pub fn new(queue_size: usize, catchup: Option<Duration>) -> Fanout {
+        Fanout {
+            queues: Arc::default(),
+            queue_size,
+            recent: catchup.map(|window| Arc::new(Mutex::new(Recent::new(window)))),
+        }
+    }
+
+    /// Adds a queue for a client; it goes away once the client drops the receiver
+    pub fn subscribe(&self, peer: &str) -> Client {
+        let (sender, receiver) = mpsc::channel(self.queue_size);
+        let dropped = Arc::new(AtomicU64::new(0));
+        self.queues.lock().unwrap().push(Queue {
+            peer: peer.to_string(),
+            sender,
+            dropped: dropped.clone(),
+        });
+        Client {
+            receiver,
+            dropped,
+            recent: self.recent.clone(),
+        }
+    }
+
+    /// Counts readings lost before reaching any queue against every client
+    fn lost(&self, n: u64) {
+        for queue in self.queues.lock().unwrap().iter() {
+            queue.dropped.fetch_add(n, Ordering::Relaxed);
+            metrics::inc_client_dropped(&queue.peer, n);
+        }
+    }
+
+    fn dispatch(&self, reading: &Reading) {
+        // Before the queues, so a client catching up meanwhile can tell the overlap by its end
+        if let Some(recent) = &self.recent {
+            recent.lock().unwrap().push(reading.clone());
+        }
+        self.queues
+            .lock()
+            .unwrap()
+            .retain(|queue| match queue.sender.try_send(reading.clone()) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    queue.dropped.fetch_add(1, Ordering::Relaxed);
+                    metrics::inc_client_dropped(&queue.peer, 1);
+                    true
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            });
+    }
+}
+
+/// Copies every reading from the broadcast into the client queues until the channel closes
+pub async fn run(fanout: Fanout, mut receiver: broadcast::Receiver<Reading>) {
+    loop {
+        match overflow::recv(&mut receiver).await {
+            Ok(reading) => fanout.dispatch(&reading),
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("Client fan-out lagged, skipped {} readings", n);
+                metrics::inc_broadcast_skipped(n);
+                fanout.lost(n);
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
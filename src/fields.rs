@@ -0,0 +1,33 @@
+use ruuvi_sensor_protocol::{
+    BatteryPotential, Humidity, MeasurementSequenceNumber, MovementCounter, Pressure, SensorValues,
+    Temperature, TransmitterPower,
+};
+
+/// Human friendly metric names clients can refer to in control commands
+pub const NAMES: &[&str] = &[
+    "temperature_c",
+    "humidity_pct",
+    "pressure_hpa",
+    "pressure_pa",
+    "battery_mv",
+    "tx_power_dbm",
+    "movement_counter",
+    "sequence",
+];
+
+/// Looks up a metric by its friendly name, converted to its natural unit
+pub fn value(sv: &SensorValues, name: &str) -> Option<f64> {
+    match name {
+        "temperature_c" => sv
+            .temperature_as_millicelsius()
+            .map(|t| f64::from(t) / 1000.0),
+        "humidity_pct" => sv.humidity_as_ppm().map(|h| f64::from(h) / 10_000.0),
+        "pressure_hpa" => sv.pressure_as_pascals().map(|p| f64::from(p) / 100.0),
+        "pressure_pa" => sv.pressure_as_pascals().map(f64::from),
+        "battery_mv" => sv.battery_potential_as_millivolts().map(f64::from),
+        "tx_power_dbm" => sv.tx_power_as_dbm().map(f64::from),
+        "movement_counter" => sv.movement_counter().map(f64::from),
+        "sequence" => sv.measurement_sequence_number().map(f64::from),
+        _ => None,
+    }
+}
@@ -0,0 +1,266 @@
+//! Per-client threshold filters like `temperature_c>25 OR humidity_pct<20`.
+//!
+//! Comparisons are over the metric names in [`crate::fields`]. AND binds tighter than OR and
+//! there are no parentheses. A comparison against a value the reading doesn't have is false.
+
+use ruuvi_sensor_protocol::SensorValues;
+
+use crate::fields;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Comparison {
+    name: String,
+    op: Op,
+    threshold: f64,
+}
+
+/// Disjunction of conjunctions of comparisons
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filter {
+    any: Vec<Vec<Comparison>>,
+}
+
+impl Comparison {
+    fn matches(&self, sv: &SensorValues) -> bool {
+        match fields::value(sv, &self.name) {
+            Some(v) => match self.op {
+                Op::Lt => v < self.threshold,
+                Op::Le => v <= self.threshold,
+                Op::Gt => v > self.threshold,
+                Op::Ge => v >= self.threshold,
+                Op::Eq => v == self.threshold,
+                Op::Ne => v != self.threshold,
+            },
+            None => false,
+        }
+    }
+}
+
+impl Filter {
+    pub fn matches(&self, sv: &SensorValues) -> bool {
+        self.any
+            .iter()
+            .any(|all| all.iter().all(|comparison| comparison.matches(sv)))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Name(String),
+    Number(f64),
+    Op(Op),
+    And,
+    Or,
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            tokens.push(match word.to_ascii_uppercase().as_str() {
+                "AND" => Token::And,
+                "OR" => Token::Or,
+                _ => Token::Name(word),
+            });
+        } else if c.is_ascii_digit() || c == '-' || c == '.' {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            let parsed = number
+                .parse()
+                .map_err(|_| format!("invalid number {:?}", number))?;
+            tokens.push(Token::Number(parsed));
+        } else {
+            let next = chars.get(i + 1).copied();
+            let (op, len) = match (c, next) {
+                ('<', Some('=')) => (Op::Le, 2),
+                ('>', Some('=')) => (Op::Ge, 2),
+                ('=', Some('=')) => (Op::Eq, 2),
+                ('!', Some('=')) => (Op::Ne, 2),
+                ('<', _) => (Op::Lt, 1),
+                ('>', _) => (Op::Gt, 1),
+                _ => return Err(format!("unexpected character {:?}", c)),
+            };
+            tokens.push(Token::Op(op));
+            i += len;
+        }
+    }
+    Ok(tokens)
+}
+
+pub fn parse(expr: &str) -> Result<Filter, String> {
+    let tokens = tokenize(expr)?;
+    let mut any = Vec::new();
+    let mut all = Vec::new();
+    let mut rest = tokens.as_slice();
+    loop {
+        match rest {
+            [Token::Name(name), Token::Op(op), Token::Number(threshold), tail @ ..] => {
+                if !fields::NAMES.contains(&name.as_str()) {
+                    return Err(format!(
+                        "unknown metric {:?}, expected one of {}",
+                        name,
+                        fields::NAMES.join(", ")
+                    ));
+                }
+                all.push(Comparison {
+                    name: name.clone(),
+                    op: *op,
+                    threshold: *threshold,
+                });
+                rest = tail;
+            }
+            _ => return Err("expected a comparison like temperature_c>25".to_string()),
+        }
+        match rest {
+            [] => break,
+            [Token::And, tail @ ..] => rest = tail,
+            [Token::Or, tail @ ..] => {
+                any.push(std::mem::take(&mut all));
+                rest = tail;
+            }
+            [token, ..] => return Err(format!("expected AND or OR, got {:?}", token)),
+        }
+    }
+    any.push(all);
+    Ok(Filter { any })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The valid data format 5 example of the protocol documentation: 24.3 °C, 53.49 % and
+    /// 1000.44 hPa
+    const PAYLOAD: [u8; 24] = [
+        0x05, 0x12, 0xFC, 0x53, 0x94, 0xC3, 0x7C, 0x00, 0x04, 0xFF, 0xFC, 0x04, 0x0C, 0xAC, 0x36,
+        0x42, 0x00, 0xCD, 0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F,
+    ];
+
+    fn matches(expr: &str) -> bool {
+        let sv = SensorValues::from_manufacturer_specific_data(0x0499, PAYLOAD).unwrap();
+        parse(expr).unwrap().matches(&sv)
+    }
+
+    #[test]
+    fn tokenizes_names_numbers_and_operators() {
+        assert_eq!(
+            tokenize("temperature_c>=-1.5 and humidity_pct!=2").unwrap(),
+            [
+                Token::Name("temperature_c".to_string()),
+                Token::Op(Op::Ge),
+                Token::Number(-1.5),
+                Token::And,
+                Token::Name("humidity_pct".to_string()),
+                Token::Op(Op::Ne),
+                Token::Number(2.0),
+            ]
+        );
+        assert_eq!(
+            tokenize("<= < == > OR").unwrap(),
+            [
+                Token::Op(Op::Le),
+                Token::Op(Op::Lt),
+                Token::Op(Op::Eq),
+                Token::Op(Op::Gt),
+                Token::Or,
+            ]
+        );
+    }
+
+    #[test]
+    fn compares_against_reading() {
+        assert!(matches("temperature_c>24"));
+        assert!(!matches("temperature_c<24"));
+        assert!(matches("pressure_pa==100044"));
+        assert!(matches("humidity_pct<=53.49"));
+        assert!(!matches("sequence!=205"));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        // (false AND true) OR true
+        assert!(matches(
+            "temperature_c<0 AND humidity_pct>50 OR pressure_hpa>1000"
+        ));
+        // true OR (true AND false)
+        assert!(matches(
+            "temperature_c>0 OR humidity_pct>50 AND pressure_hpa<1000"
+        ));
+        // (true AND false) OR false
+        assert!(!matches(
+            "temperature_c>0 AND humidity_pct<50 OR pressure_hpa<1000"
+        ));
+        assert_eq!(
+            parse("temperature_c<0 AND humidity_pct>50 OR sequence>1")
+                .unwrap()
+                .any
+                .iter()
+                .map(Vec::len)
+                .collect::<Vec<_>>(),
+            [2, 1]
+        );
+    }
+
+    #[test]
+    fn rejects_parentheses() {
+        assert_eq!(
+            parse("(temperature_c>25)").unwrap_err(),
+            "unexpected character '('"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_metric() {
+        assert!(parse("temperature>25")
+            .unwrap_err()
+            .starts_with("unknown metric \"temperature\", expected one of temperature_c,"));
+    }
+
+    #[test]
+    fn rejects_bad_number() {
+        assert_eq!(
+            parse("temperature_c>1.2.3").unwrap_err(),
+            "invalid number \"1.2.3\""
+        );
+        assert_eq!(
+            parse("temperature_c>-").unwrap_err(),
+            "invalid number \"-\""
+        );
+    }
+
+    #[test]
+    fn rejects_incomplete_and_trailing_tokens() {
+        let expected = "expected a comparison like temperature_c>25";
+        assert_eq!(parse("").unwrap_err(), expected);
+        assert_eq!(parse("temperature_c>").unwrap_err(), expected);
+        assert_eq!(parse("temperature_c>25 AND").unwrap_err(), expected);
+        assert_eq!(
+            parse("temperature_c>25 sequence").unwrap_err(),
+            "expected AND or OR, got Name(\"sequence\")"
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! Tiny HTTP/1.x endpoint for pollers: `GET /latest?mac=AA:BB:CC:DD:EE:FF` answers with the
+//! newest reading of that tag, `GET /snapshot` with the newest reading of every tag,
+//! `GET /stats` with per-tag statistics, `GET /metrics` with the metrics for a scraper and
+//! `GET /health` with the state of the bridge's upstream connections. One request per
+//! connection.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use log::{debug, trace};
+use serde_json::{json, Value};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+use crate::cache::LastValues;
+use crate::tracker::SeenTags;
+use crate::{mac, metrics, output, pause, snapshot};
+
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the handlers need, shared by all connections
+#[derive(Clone)]
+pub struct State {
+    pub cache: LastValues,
+    pub seen: SeenTags,
+    pub output: Arc<output::Options>,
+    pub metrics_format: metrics::Format,
+    /// Present when publishing to NATS
+    pub nats_connected: Option<Arc<AtomicBool>>,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: &'static str, body: Value) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    fn error(status: &'static str, message: &str) -> Response {
+        Response::json(status, json!({ "error": message }))
+    }
+}
+
+async fn read_request_head(socket: &mut TcpStream) -> Option<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            return None;
+        }
+        let n = socket.read(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8(head).ok()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+fn latest(query: &str, state: &State) -> Response {
+    let mac = match query_param(query, "mac") {
+        Some(mac) => mac,
+        None => return Response::error("400 Bad Request", "missing mac parameter"),
+    };
+    let mac = match mac::parse(&mac) {
+        Some(mac) => mac,
+        None => return Response::error("400 Bad Request", "malformed mac parameter"),
+    };
+    match state.cache.lock().unwrap().get(&mac) {
+        Some(reading) => Response::json(
+            "200 OK",
+            Value::Object(output::to_json(reading, &state.output)),
+        ),
+        None => Response::error("404 Not Found", "no readings for mac"),
+    }
+}
+
+/// Statistics per tag, keyed by MAC address
+fn stats(state: &State) -> Response {
+    let stats = state
+        .seen
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(mac, seen)| {
+            let tag = json!({
+                "first_seen_unix_ms": seen.first_seen_unix_ms,
+                "seen_count": seen.seen_count,
+            });
+            (mac::format(mac), tag)
+        })
+        .collect();
+    Response::json("200 OK", Value::Object(stats))
+}
+
+/// Unhealthy while the Bluetooth scan isn't running, unless it was paused on purpose, or a
+/// configured upstream is unreachable
+fn health(state: &State) -> Response {
+    let scanning = metrics::METRICS.scan_active.load(Ordering::Relaxed) == 1;
+    let paused = pause::paused();
+    let scan = match (scanning, paused) {
+        (true, _) => "active",
+        (false, true) => "paused",
+        (false, false) => "stopped",
+    };
+    let mut body = json!({
+        "status": "ok",
+        "scan": scan,
+        "scan_failures": metrics::METRICS.scan_failures.load(Ordering::Relaxed),
+        "client_dropped": metrics::METRICS.client_dropped_total.load(Ordering::Relaxed),
+    });
+    if let Some(connected) = &state.nats_connected {
+        let connected = connected.load(Ordering::Relaxed);
+        body["nats"] = json!(if connected {
+            "connected"
+        } else {
+            "disconnected"
+        });
+        if !connected {
+            body["status"] = json!("degraded");
+            return Response::json("503 Service Unavailable", body);
+        }
+    }
+    if !scanning && !paused {
+        body["status"] = json!("degraded");
+        return Response::json("503 Service Unavailable", body);
+    }
+    Response::json("200 OK", body)
+}
+
+fn route(request_line: &str, state: &State) -> Response {
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Response::error("400 Bad Request", "malformed request line"),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    match (method, path) {
+        ("GET", "/latest") => latest(query, state),
+        ("GET", "/snapshot") => {
+            Response::json("200 OK", snapshot::to_json(&state.cache, &state.output))
+        }
+        ("GET", "/stats") => stats(state),
+        ("GET", "/metrics") => Response {
+            status: "200 OK",
+            content_type: state.metrics_format.content_type(),
+            body: metrics::render(state.metrics_format, &state.cache.lock().unwrap()),
+        },
+        ("GET", "/health") => health(state),
+        (_, "/latest") | (_, "/snapshot") | (_, "/stats") | (_, "/metrics") | (_, "/health") => {
+            Response::error("405 Method Not Allowed", "only GET is supported")
+        }
+        _ => Response::error("404 Not Found", "unknown path"),
+    }
+}
+
+pub async fn handle_http(mut socket: TcpStream, state: State) {
+    let head = match timeout(REQUEST_TIMEOUT, read_request_head(&mut socket)).await {
+        Ok(Some(head)) => head,
+        _ => {
+            debug!("Dropping HTTP connection without a complete request");
+            return;
+        }
+    };
+    let request_line = head.lines().next().unwrap_or("");
+    trace!("HTTP request: {:?}", request_line);
+
+    let response = route(request_line, &state);
+    let message = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    );
+    if let Err(e) = socket.write_all(message.as_bytes()).await {
+        debug!("Failed to write HTTP response: {:?}", e);
+    }
+    let _ = socket.shutdown().await;
+}
@@ -0,0 +1,57 @@
+//! --dedup-identical: drops a reading whose values are all the same as those of its tag's
+//! previous reading, as when a tag advertises the same packet repeatedly within its sampling
+//! interval. Unlike the other deduplication this needs no sequence number, only exact repeats.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use ruuvi_sensor_protocol::{
+    Acceleration, AccelerationVector, BatteryPotential, Humidity, MacAddress,
+    MeasurementSequenceNumber, MovementCounter, Pressure, SensorValues, Temperature,
+    TransmitterPower,
+};
+
+use crate::lru::MacLru;
+
+/// Hash of every value of the reading; the bridge's own timestamps aren't part of it
+fn fingerprint(sv: &SensorValues) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    sv.acceleration_vector_as_milli_g()
+        .map(|AccelerationVector(x, y, z)| (x, y, z))
+        .hash(&mut hasher);
+    sv.battery_potential_as_millivolts().hash(&mut hasher);
+    sv.humidity_as_ppm().hash(&mut hasher);
+    sv.measurement_sequence_number().hash(&mut hasher);
+    sv.movement_counter().hash(&mut hasher);
+    sv.pressure_as_pascals().hash(&mut hasher);
+    sv.temperature_as_millikelvins().hash(&mut hasher);
+    sv.tx_power_as_dbm().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The fingerprint of each tag's previous reading
+#[derive(Debug)]
+pub struct Identical {
+    last: MacLru<u64>,
+}
+
+impl Identical {
+    pub fn new(max_tracked_macs: usize) -> Identical {
+        Identical {
+            last: MacLru::new(max_tracked_macs),
+        }
+    }
+
+    /// Whether the reading repeats its tag's previous one. Readings without a MAC address can't
+    /// be attributed and never do.
+    pub fn is_repeat(&mut self, sv: &SensorValues) -> bool {
+        let mac = match sv.mac_address() {
+            Some(mac) => mac,
+            None => return false,
+        };
+        let fingerprint = fingerprint(sv);
+        let repeat = self.last.get(&mac) == Some(&fingerprint);
+        self.last.insert(mac, fingerprint);
+        repeat
+    }
+}
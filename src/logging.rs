@@ -0,0 +1,99 @@
+//! Where log records go: env_logger's stderr output, or the local syslog.
+
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    Stderr,
+    Syslog,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stderr" => Ok(Target::Stderr),
+            "syslog" => Ok(Target::Syslog),
+            _ => Err(format!("unknown log target {:?}", s)),
+        }
+    }
+}
+
+pub fn parse_facility(s: &str) -> Result<Facility, String> {
+    Facility::from_str(s).map_err(|_| format!("unknown syslog facility {:?}", s))
+}
+
+/// Sends records to syslog, filtered by `RUST_LOG` like env_logger would
+struct SyslogLogger {
+    filter: env_logger::filter::Filter,
+    logger: Mutex<Logger<LoggerBackend, Formatter3164>>,
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        // Syslog is line oriented and many daemons cut a message at its first newline, so
+        // multi-line messages go out as one message per line, each tagged with the module
+        let message = record.args().to_string();
+        let mut logger = self.logger.lock().unwrap();
+        for line in message.lines().filter(|line| !line.trim().is_empty()) {
+            let line = format!("{}: {}", record.target(), line);
+            // Nowhere left to report failures to
+            let _ = match record.level() {
+                Level::Error => logger.err(line),
+                Level::Warn => logger.warning(line),
+                Level::Info => logger.info(line),
+                Level::Debug | Level::Trace => logger.debug(line),
+            };
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// With `quiet` only errors are logged, whatever `RUST_LOG` says
+pub fn init(
+    target: Target,
+    facility: Facility,
+    quiet: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match target {
+        Target::Stderr if quiet => env_logger::Builder::new()
+            .filter_level(LevelFilter::Error)
+            .format_timestamp(None)
+            .init(),
+        Target::Stderr => env_logger::builder().format_timestamp(None).init(),
+        Target::Syslog => {
+            let formatter = Formatter3164 {
+                facility,
+                hostname: None,
+                process: env!("CARGO_PKG_NAME").to_string(),
+                pid: std::process::id(),
+            };
+            let filter = if quiet {
+                env_logger::filter::Builder::new()
+                    .filter_level(LevelFilter::Error)
+                    .build()
+            } else {
+                env_logger::filter::Builder::from_env("RUST_LOG").build()
+            };
+            log::set_max_level(filter.filter());
+            log::set_boxed_logger(Box::new(SyslogLogger {
+                filter,
+                logger: Mutex::new(syslog::unix(formatter)?),
+            }))?;
+        }
+    }
+    Ok(())
+}
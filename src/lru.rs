@@ -0,0 +1,102 @@
+//! Per-tag maps bounded by --max-tracked-macs. A busy radio environment full of neighbors' tags
+//! would otherwise grow every per-MAC map of a long running bridge without limit, so once full
+//! the tag seen least recently is forgotten to make room, as if it had never been seen.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// A map by MAC address holding at most `capacity` tags, evicting the least recently inserted
+/// or updated one when full. Lookups with `get` don't count as use, so reading the state of a
+/// tag from outside doesn't keep it around.
+#[derive(Debug)]
+pub struct MacLru<V> {
+    capacity: usize,
+    entries: HashMap<[u8; 6], (V, u64)>,
+    /// MAC addresses by when they were last used
+    order: BTreeMap<u64, [u8; 6]>,
+    clock: u64,
+}
+
+impl<V> MacLru<V> {
+    pub fn new(capacity: usize) -> MacLru<V> {
+        MacLru {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn touch(&mut self, mac: [u8; 6]) {
+        self.clock += 1;
+        if let Some((_, used)) = self.entries.get_mut(&mac) {
+            self.order.remove(used);
+            *used = self.clock;
+            self.order.insert(self.clock, mac);
+        }
+    }
+
+    pub fn get(&self, mac: &[u8; 6]) -> Option<&V> {
+        self.entries.get(mac).map(|(value, _)| value)
+    }
+
+    /// The tag's value, marking the tag as used
+    pub fn get_mut(&mut self, mac: &[u8; 6]) -> Option<&mut V> {
+        self.touch(*mac);
+        self.entries.get_mut(mac).map(|(value, _)| value)
+    }
+
+    pub fn contains_key(&self, mac: &[u8; 6]) -> bool {
+        self.entries.contains_key(mac)
+    }
+
+    /// Inserts or replaces the tag's value, marking the tag as used. Returns the tag evicted to
+    /// make room for it, so state kept about it elsewhere can be dropped as well.
+    pub fn insert(&mut self, mac: [u8; 6], value: V) -> Option<([u8; 6], V)> {
+        if let Some(entry) = self.entries.get_mut(&mac) {
+            entry.0 = value;
+            self.touch(mac);
+            return None;
+        }
+        let evicted = if self.entries.len() >= self.capacity {
+            self.pop_oldest()
+        } else {
+            None
+        };
+        self.clock += 1;
+        self.entries.insert(mac, (value, self.clock));
+        self.order.insert(self.clock, mac);
+        evicted
+    }
+
+    fn pop_oldest(&mut self) -> Option<([u8; 6], V)> {
+        let (_, mac) = self.order.pop_first()?;
+        let (value, _) = self.entries.remove(&mac)?;
+        Some((mac, value))
+    }
+
+    pub fn remove(&mut self, mac: &[u8; 6]) -> Option<V> {
+        let (value, used) = self.entries.remove(mac)?;
+        self.order.remove(&used);
+        Some(value)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&[u8; 6], &mut V) -> bool) {
+        let order = &mut self.order;
+        self.entries.retain(|mac, (value, used)| {
+            let kept = keep(mac, value);
+            if !kept {
+                order.remove(used);
+            }
+            kept
+        });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8; 6], &V)> {
+        self.entries.iter().map(|(mac, (value, _))| (mac, value))
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
@@ -0,0 +1,121 @@
+use std::str::FromStr;
+
+use serde_json::{json, Value};
+
+/// Parses a MAC address like `AA:BB:CC:DD:EE:FF`; dashes work as separators too and case
+/// doesn't matter.
+pub fn parse(s: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut parts = s.split([':', '-']);
+    for byte in mac.iter_mut() {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    match parts.next() {
+        Some(_) => None,
+        None => Some(mac),
+    }
+}
+
+/// Formats a MAC address as `AA:BB:CC:DD:EE:FF`
+pub fn format(mac: &[u8; 6]) -> String {
+    mac.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// How the `mac_address` field is written in readings
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MacFormat {
+    /// `AA:BB:CC:DD:EE:FF`
+    ColonUpper,
+    /// `aa:bb:cc:dd:ee:ff`
+    ColonLower,
+    /// `AABBCCDDEEFF`
+    NoSep,
+    /// `[170,187,204,221,238,255]`
+    BytesArray,
+}
+
+impl FromStr for MacFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "colon-upper" => Ok(MacFormat::ColonUpper),
+            "colon-lower" => Ok(MacFormat::ColonLower),
+            "no-sep" => Ok(MacFormat::NoSep),
+            "bytes-array" => Ok(MacFormat::BytesArray),
+            _ => Err(format!(
+                "expected colon-upper, colon-lower, no-sep or bytes-array, got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+impl MacFormat {
+    pub fn to_json(self, mac: &[u8; 6]) -> Value {
+        match self {
+            MacFormat::ColonUpper => json!(format(mac)),
+            MacFormat::ColonLower => json!(format(mac).to_lowercase()),
+            MacFormat::NoSep => json!(format(mac).replace(':', "")),
+            MacFormat::BytesArray => json!(mac),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x0F];
+
+    #[test]
+    fn colon_upper() {
+        assert_eq!(
+            MacFormat::ColonUpper.to_json(&MAC),
+            json!("AA:BB:CC:DD:EE:0F")
+        );
+    }
+
+    #[test]
+    fn colon_lower() {
+        assert_eq!(
+            MacFormat::ColonLower.to_json(&MAC),
+            json!("aa:bb:cc:dd:ee:0f")
+        );
+    }
+
+    #[test]
+    fn no_sep() {
+        assert_eq!(MacFormat::NoSep.to_json(&MAC), json!("AABBCCDDEE0F"));
+    }
+
+    #[test]
+    fn bytes_array() {
+        assert_eq!(
+            MacFormat::BytesArray.to_json(&MAC),
+            json!([170, 187, 204, 221, 238, 15])
+        );
+    }
+
+    #[test]
+    fn mac_format_from_str() {
+        assert_eq!("no-sep".parse(), Ok(MacFormat::NoSep));
+        assert!("colon".parse::<MacFormat>().is_err());
+    }
+
+    #[test]
+    fn parse_takes_colons_and_dashes_in_any_case() {
+        assert_eq!(parse("aa:bb:cc:dd:ee:0f"), Some(MAC));
+        assert_eq!(parse("AA-BB-CC-DD-EE-0F"), Some(MAC));
+        assert_eq!(parse("AA:BB:CC:DD:EE"), None);
+        assert_eq!(parse("AA:BB:CC:DD:EE:0F:00"), None);
+        assert_eq!(parse("AABBCCDDEE0F"), None);
+    }
+}
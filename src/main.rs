@@ -1,33 +1,239 @@
+use std::collections::VecDeque;
 use std::error::Error;
+use std::path::PathBuf;
 use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
 use futures::stream::StreamExt;
 use log::{debug, error, info, trace, warn};
 use serde_json::json;
 use structopt::StructOpt;
 
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast;
-use tokio::time::{sleep, Duration};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::signal;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{interval, sleep, sleep_until, timeout, Duration};
 
 use btleplug::api::{Central, CentralEvent, Manager as _, ScanFilter};
 use btleplug::platform::Manager;
 
-use ruuvi_sensor_protocol::Acceleration;
-use ruuvi_sensor_protocol::AccelerationVector;
-use ruuvi_sensor_protocol::BatteryPotential;
-use ruuvi_sensor_protocol::Humidity;
 use ruuvi_sensor_protocol::MacAddress;
-use ruuvi_sensor_protocol::MeasurementSequenceNumber;
-use ruuvi_sensor_protocol::MovementCounter;
-use ruuvi_sensor_protocol::Pressure;
+use ruuvi_sensor_protocol::ParseError;
 use ruuvi_sensor_protocol::SensorValues;
-use ruuvi_sensor_protocol::Temperature;
-use ruuvi_sensor_protocol::TransmitterPower;
 
-async fn bt_event_scan(tx: broadcast::Sender<SensorValues>) -> Result<(), Box<dyn Error>> {
-    let manager = Manager::new().await.unwrap();
+use reading::{unix_millis, Reading};
+
+mod activation;
+mod allow;
+mod barometry;
+mod battery;
+mod cache;
+mod catchup;
+mod comfort;
+mod control;
+mod decrypt;
+mod eddystone;
+mod fanout;
+mod fields;
+mod filter;
+mod http;
+mod identical;
+mod logging;
+mod lru;
+mod mac;
+mod merge;
+mod metrics;
+mod model;
+#[cfg(all(unix, feature = "systemd"))]
+mod notify;
+#[cfg(feature = "otel")]
+mod otel;
+mod outlier;
+mod output;
+mod overflow;
+mod pause;
+mod preflight;
+mod pushgateway;
+mod reading;
+mod reorder;
+mod sample;
+mod selftest;
+mod server;
+mod service_data;
+mod sink;
+mod snapshot;
+mod template;
+mod thermo;
+mod timestamp;
+#[cfg(feature = "tls")]
+mod tls;
+mod tracker;
+mod validate;
+#[cfg(feature = "websocket")]
+mod websocket;
+
+use server::{accept_loop, send_shutdown_notice, write_frame};
+
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+/// Exit code when the Bluetooth scan task stops, so a supervisor can tell it from other failures
+const SCAN_TASK_EXIT_CODE: i32 = 3;
+/// Exit code when --self-test fails
+const SELF_TEST_EXIT_CODE: i32 = 4;
+/// How long a client has to send AUTH with --auth-token
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Manufacturer data that could not be parsed into sensor values
+#[derive(Clone, Debug)]
+struct ParseFailure {
+    manufacturer_id: u16,
+    error: ParseError,
+    bytes: Vec<u8>,
+}
+
+/// Everything a parsed advertisement goes through on its way to the broadcast
+struct Pipeline {
+    tx: overflow::Front,
+    tracker: tracker::Tracker,
+    on_invalid: Option<validate::OnInvalid>,
+    model_detector: Option<model::Detector>,
+    reorder: Option<reorder::Reorder>,
+    firmware_guess: bool,
+    battery_monitor: battery::Monitor,
+    identical: Option<identical::Identical>,
+    sampler: Option<sample::Sampler>,
+}
+
+impl Pipeline {
+    async fn process(
+        &mut self,
+        sv: SensorValues,
+        data_format: Option<u8>,
+        received_at_unix_ms: u64,
+    ) {
+        let reading = match self.prepare(sv, data_format, received_at_unix_ms) {
+            Some(reading) => reading,
+            None => return,
+        };
+        let passed_on = match &mut self.reorder {
+            Some(reorder) => reorder.push(reading, Instant::now(), unix_millis()),
+            None => vec![reading],
+        };
+        for reading in passed_on {
+            self.send(reading).await;
+        }
+    }
+
+    /// Passes on the readings whose --reorder-buffer-ms window has closed by `now`
+    async fn expire_reordered(&mut self, now: Instant) {
+        let readings = match &mut self.reorder {
+            Some(reorder) => reorder.expire(now),
+            None => return,
+        };
+        for reading in readings {
+            self.send(reading).await;
+        }
+    }
+
+    async fn send(&self, mut reading: Reading) {
+        reading.bridge_seq = Some(reading::next_bridge_seq());
+        self.tx.send(reading).await;
+    }
+
+    /// The reading to broadcast, unless it is dropped on the way
+    fn prepare(
+        &mut self,
+        sv: SensorValues,
+        data_format: Option<u8>,
+        received_at_unix_ms: u64,
+    ) -> Option<Reading> {
+        #[cfg(feature = "otel")]
+        let span = otel::reading_span(&sv);
+        #[cfg(feature = "otel")]
+        let _entered = span.enter();
+
+        let now = Instant::now();
+        let arrival = self.tracker.arrive(&sv, now);
+        if let Some(identical) = &mut self.identical {
+            if identical.is_repeat(&sv) {
+                metrics::inc(&metrics::METRICS.identical_dropped);
+                trace!("Dropping identical repeat from {:?}", sv.mac_address());
+                return None;
+            }
+        }
+        if let Some(sampler) = &mut self.sampler {
+            if !sampler.sample(&sv) {
+                return None;
+            }
+        }
+
+        let mut suspect = false;
+        if let (Some(on_invalid), Err(invalid)) = (self.on_invalid, validate::check(&sv)) {
+            metrics::inc(&metrics::METRICS.invalid_readings);
+            debug!("Invalid reading from {:?}: {}", sv.mac_address(), invalid);
+            if on_invalid == validate::OnInvalid::Drop {
+                return None;
+            }
+            suspect = true;
+        }
+        self.battery_monitor.observe(&sv);
+        let model_guess = self
+            .model_detector
+            .as_mut()
+            .map(|d| d.guess(data_format, &sv));
+        let firmware_guess = self
+            .firmware_guess
+            .then(|| model::firmware_guess(data_format, &sv));
+        let mut reading = self.tracker.process(sv, arrival, now)?;
+        reading.received_at_unix_ms = received_at_unix_ms;
+        reading.suspect = suspect;
+        reading.model_guess = model_guess;
+        reading.firmware_guess = firmware_guess;
+        #[cfg(feature = "otel")]
+        {
+            reading.trace_context = otel::context(&span);
+        }
+        Some(reading)
+    }
+}
+
+/// Hands a parsed advertisement to the merger if there is one, or straight to the pipeline
+async fn feed(
+    pipeline: &mut Pipeline,
+    merger: &mut Option<merge::Merger>,
+    sv: SensorValues,
+    data_format: Option<u8>,
+    received_at_unix_ms: u64,
+) {
+    match merger {
+        Some(merger) => {
+            if let Some((sv, data_format, received_at_unix_ms)) =
+                merger.add(sv, data_format, received_at_unix_ms, Instant::now())
+            {
+                pipeline.process(sv, data_format, received_at_unix_ms).await;
+            }
+        }
+        None => pipeline.process(sv, data_format, received_at_unix_ms).await,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn bt_event_scan(
+    pipeline: &mut Pipeline,
+    merger: &mut Option<merge::Merger>,
+    error_tx: Option<broadcast::Sender<ParseFailure>>,
+    include_unknown_manufacturers: bool,
+    all_adapters: bool,
+    no_event_restart: Option<Duration>,
+    ruuvi_service: Option<uuid::Uuid>,
+    keys: Option<&decrypt::Keys>,
+    verify_checksum: bool,
+    max_tracked_macs: usize,
+) -> Result<(), Box<dyn Error>> {
+    let manager = Manager::new().await?;
 
     let adapters = manager.adapters().await?;
     debug!("Listing adapters...");
@@ -35,14 +241,123 @@ async fn bt_event_scan(tx: broadcast::Sender<SensorValues>) -> Result<(), Box<dy
         debug!("{}", adapter.adapter_info().await?);
     }
 
-    let adapter = adapters.get(0).unwrap();
-    info!("Using adapter: {}", adapter.adapter_info().await?);
+    let adapters: Vec<_> = if all_adapters {
+        adapters
+    } else {
+        adapters.into_iter().take(1).collect()
+    };
+    if adapters.is_empty() {
+        return Err("no Bluetooth adapters found".into());
+    }
+
+    // Labels by adapter index, which the merged event stream carries along with each event and
+    // when it came off its adapter, the order --reorder-buffer-ms restores
+    let mut labels = Vec::new();
+    let mut scan_guard = None;
+    let mut streams = Vec::new();
+    for adapter in &adapters {
+        let adapter_info = adapter.adapter_info().await?;
+        info!("Using adapter: {}", adapter_info);
+        let label = match adapter_info.split_whitespace().next() {
+            Some(name) => name.to_string(),
+            None => format!("adapter{}", labels.len()),
+        };
+        metrics::register_adapter(&label);
+        let index = labels.len();
+        streams.push(
+            adapter
+                .events()
+                .await?
+                .map(move |event| (index, unix_millis(), event)),
+        );
+        if pause::paused() {
+            info!("Scan paused, not starting it on {}", label);
+        } else {
+            let start_result = adapter.start_scan(ScanFilter::default()).await;
+            info!("Scan started on {}: {:?}", label, start_result);
+            if start_result.is_ok() {
+                scan_guard.get_or_insert_with(metrics::ScanGuard::new);
+            }
+        }
+        labels.push(label);
+    }
+    let mut events = futures::stream::select_all(streams);
+    let mut last_event = Instant::now();
+    let mut dedup = service_data::Dedup::new(max_tracked_macs);
+    let mut skipped = decrypt::Skipped::new(max_tracked_macs);
 
-    let mut events = adapter.events().await?;
-    let start_result = adapter.start_scan(ScanFilter::default()).await;
-    info!("Scan started: {:?}", start_result);
+    loop {
+        let merge_deadline = merger.as_ref().and_then(|m| m.next_deadline());
+        let merge_window_closed = sleep_until(merge_deadline.unwrap_or_else(Instant::now).into());
+        let reorder_deadline = pipeline.reorder.as_ref().and_then(|r| r.next_deadline());
+        let reorder_window_closed =
+            sleep_until(reorder_deadline.unwrap_or_else(Instant::now).into());
+        let stalled = sleep_until((last_event + no_event_restart.unwrap_or_default()).into());
+        let (adapter, received_at, event) = tokio::select! {
+            event = events.next() => match event {
+                // Stragglers of a scan just stopped
+                Some(_) if pause::paused() => continue,
+                Some(event) => {
+                    metrics::observe_event();
+                    last_event = Instant::now();
+                    event
+                }
+                None => break,
+            },
+            _ = pause::changed() => {
+                let paused = pause::paused();
+                let mut started = false;
+                for (adapter, label) in adapters.iter().zip(&labels) {
+                    if paused {
+                        let stop_result = adapter.stop_scan().await;
+                        info!("Scan paused on {}: {:?}", label, stop_result);
+                    } else {
+                        let start_result = adapter.start_scan(ScanFilter::default()).await;
+                        info!("Scan resumed on {}: {:?}", label, start_result);
+                        started |= start_result.is_ok();
+                    }
+                }
+                if started {
+                    scan_guard.get_or_insert_with(metrics::ScanGuard::new);
+                } else {
+                    scan_guard = None;
+                }
+                last_event = Instant::now();
+                continue;
+            }
+            _ = stalled, if no_event_restart.is_some() && !pause::paused() => {
+                warn!("No Bluetooth events in {:?}, restarting the scan", last_event.elapsed());
+                metrics::inc(&metrics::METRICS.scan_restarts);
+                let mut restarted = false;
+                for (adapter, label) in adapters.iter().zip(&labels) {
+                    let stop_result = adapter.stop_scan().await;
+                    let start_result = adapter.start_scan(ScanFilter::default()).await;
+                    info!("Scan restarted on {}: {:?}, {:?}", label, stop_result, start_result);
+                    restarted |= start_result.is_ok();
+                }
+                if restarted {
+                    scan_guard.get_or_insert_with(metrics::ScanGuard::new);
+                } else {
+                    scan_guard = None;
+                }
+                last_event = Instant::now();
+                continue;
+            }
+            _ = merge_window_closed, if merge_deadline.is_some() => {
+                if let Some(merger) = merger.as_mut() {
+                    for (sv, data_format, received_at) in merger.expire(Instant::now()) {
+                        pipeline.process(sv, data_format, received_at).await;
+                    }
+                }
+                continue;
+            }
+            _ = reorder_window_closed, if reorder_deadline.is_some() => {
+                pipeline.expire_reordered(Instant::now()).await;
+                continue;
+            }
+        };
 
-    while let Some(event) = events.next().await {
+        #[allow(clippy::single_match)]
         match event {
             // https://docs.rs/btleplug/0.9.0/btleplug/api/enum.CentralEvent.html
             // TODO: add back with seen already filtering
@@ -58,22 +373,105 @@ async fn bt_event_scan(tx: broadcast::Sender<SensorValues>) -> Result<(), Box<dy
                     id, manufacturer_data
                 );
                 for (manufacturer_id, bytes) in &manufacturer_data {
-                    let parsed = SensorValues::from_manufacturer_specific_data(
-                        manufacturer_id.clone(),
-                        bytes,
-                    );
+                    metrics::inc(&metrics::METRICS.advertisements);
+                    metrics::observe_adapter(&labels[adapter]);
+                    let data_format = bytes.first().copied();
+                    if verify_checksum
+                        && *manufacturer_id == decrypt::RUUVI_MANUFACTURER_ID
+                        && data_format == Some(decrypt::DATA_FORMAT_8)
+                        && !decrypt::checksum_matches(bytes)
+                    {
+                        metrics::inc(&metrics::METRICS.checksum_failures);
+                        debug!("Dropping data format 8 advertisement with a bad checksum");
+                        continue;
+                    }
+                    let decrypted;
+                    let bytes = if *manufacturer_id == decrypt::RUUVI_MANUFACTURER_ID
+                        && data_format == Some(decrypt::DATA_FORMAT_8)
+                    {
+                        match keys.map(|keys| keys.decrypt(bytes)) {
+                            Some(Ok(payload)) => {
+                                decrypted = payload;
+                                &decrypted
+                            }
+                            Some(Err(e)) => {
+                                skipped.log(bytes, &e);
+                                continue;
+                            }
+                            None => {
+                                skipped.log(bytes, &"no --decrypt-keys-file");
+                                continue;
+                            }
+                        }
+                    } else {
+                        bytes
+                    };
+                    let parsed =
+                        SensorValues::from_manufacturer_specific_data(*manufacturer_id, bytes);
                     trace!("parsed: {:?}", parsed);
                     match parsed {
                         Ok(sv) => {
-                            let recipients = tx.send(sv);
-                            trace!("Message was sent to {:?}", recipients)
+                            let source = service_data::Source::ManufacturerData;
+                            if dedup.is_duplicate(source, &sv) {
+                                trace!("Already got {:?} as service data", sv);
+                                continue;
+                            }
+                            metrics::inc(&metrics::METRICS.readings);
+                            feed(pipeline, merger, sv, data_format, received_at).await;
                         }
-                        Err(e) => match e {
-                            ruuvi_sensor_protocol::ParseError::UnknownManufacturerId(_id) => {
+                        Err(e) => {
+                            metrics::inc_parse_error(&e);
+                            let expected_noise = matches!(e, ParseError::UnknownManufacturerId(_));
+                            if expected_noise {
                                 debug!("Got unknown manufacturer id: {:?}", e)
+                            } else {
+                                error!("Failed to parse manufacturer data advertisement: {:?}", e)
+                            }
+                            if let Some(error_tx) = &error_tx {
+                                if !expected_noise || include_unknown_manufacturers {
+                                    let _ = error_tx.send(ParseFailure {
+                                        manufacturer_id: *manufacturer_id,
+                                        error: e,
+                                        bytes: bytes.clone(),
+                                    });
+                                }
                             }
-                            _ => error!("Failed to parse manufacturer data advertisement: {:?}", e),
-                        },
+                        }
+                    }
+                }
+            }
+            CentralEvent::ServiceDataAdvertisement { id, service_data } => {
+                for (service, bytes) in &service_data {
+                    if let Some((sv, data_format)) = eddystone::parse(service, bytes) {
+                        debug!("Data format {} URL from {:?}: {:?}", data_format, id, sv);
+                        metrics::inc(&metrics::METRICS.advertisements);
+                        metrics::observe_adapter(&labels[adapter]);
+                        metrics::inc(&metrics::METRICS.readings);
+                        feed(pipeline, merger, sv, Some(data_format), received_at).await;
+                        continue;
+                    }
+                    let parsed = ruuvi_service.as_ref().and_then(|ruuvi_service| {
+                        service_data::parse(ruuvi_service, service, bytes)
+                    });
+                    match parsed {
+                        Some(Ok(sv)) => {
+                            metrics::inc(&metrics::METRICS.advertisements);
+                            metrics::observe_adapter(&labels[adapter]);
+                            let source = service_data::Source::ServiceData;
+                            if dedup.is_duplicate(source, &sv) {
+                                trace!("Already got {:?} as manufacturer data", sv);
+                                continue;
+                            }
+                            metrics::inc(&metrics::METRICS.readings);
+                            feed(pipeline, merger, sv, bytes.first().copied(), received_at).await;
+                        }
+                        Some(Err(e)) => {
+                            metrics::inc(&metrics::METRICS.advertisements);
+                            metrics::observe_adapter(&labels[adapter]);
+                            metrics::inc_parse_error(&e);
+                            debug!("Failed to parse service data from {:?}: {:?}", id, e);
+                        }
+                        None => {}
                     }
                 }
             }
@@ -81,43 +479,303 @@ async fn bt_event_scan(tx: broadcast::Sender<SensorValues>) -> Result<(), Box<dy
         }
     }
 
-    let stop_result = adapter.stop_scan().await;
-    info!("Scan stopped: {:?}", stop_result);
+    drop(scan_guard);
+    for (adapter, label) in adapters.iter().zip(&labels) {
+        let stop_result = adapter.stop_scan().await;
+        info!("Scan stopped on {}: {:?}", label, stop_result);
+    }
 
     Ok(())
 }
 
-async fn handle_socket(mut socket: TcpStream, mut receiver: broadcast::Receiver<SensorValues>) {
+async fn handle_socket<S>(
+    socket: S,
+    peer: String,
+    mut client: fanout::Client,
+    options: Arc<output::Options>,
+    default_subscription: control::DefaultSubscription,
+    mut shutdown: broadcast::Receiver<()>,
+    _shutdown_complete: mpsc::Sender<()>,
+) where
+    S: AsyncRead + AsyncWrite + std::fmt::Debug + Send + 'static,
+{
     info!("New socket connection: {:?}", socket);
+    let _client_guard = metrics::ClientGuard::new(&peer);
+    let (read_half, write_half) = tokio::io::split(socket);
+    let mut socket = server::compressed(write_half, options.compress);
+    let mut commands = BufReader::new(read_half).lines();
+    if let Some(expected) = &options.auth_token {
+        let failure = match timeout(AUTH_TIMEOUT, commands.next_line()).await {
+            Ok(Ok(Some(line))) => match control::parse_auth(&line) {
+                Some(token) if expected.matches(&token) => None,
+                Some(_) => Some("wrong token"),
+                None => Some("first line was not AUTH"),
+            },
+            Ok(Ok(None)) | Ok(Err(_)) => Some("closed before AUTH"),
+            Err(_) => Some("no AUTH in time"),
+        };
+        let reply = match failure {
+            None => json!({"event": "ok", "command": "AUTH"}),
+            Some(reason) => {
+                warn!("Authentication failed for {}: {}", peer, reason);
+                control::error_reply("authentication failed")
+            }
+        };
+        let frame = output::encode(options.format, &options.framing, &reply);
+        let _ = write_frame(&mut socket, &frame).await;
+        if failure.is_some() {
+            let _ = socket.shutdown().await;
+            return;
+        }
+    }
+    let mut reading_commands = true;
+    let mut filter: Option<filter::Filter> = None;
+    let mut projection: Option<Vec<String>> = None;
+    let mut subscriptions = control::Subscriptions::new(default_subscription);
+    let mut format = options.format;
+    let mut throttle = control::Throttle::new(options.max_tracked_macs);
+    let mut multiplex = false;
+    // Readings of CATCHUP still to be sent, and the newest of them: live readings up to it were
+    // already in the replay
+    let mut replay = VecDeque::new();
+    let mut caught_up_to: Option<Instant> = None;
+    let mut lag = options
+        .slow_client_threshold
+        .map(|threshold| server::LagTracker::new(threshold, options.slow_client_window));
+    // The CSV header last sent, repeated whenever the columns change
+    let mut csv_header: Option<String> = None;
+    // Newest reading of each tag in the current window, by MAC address
+    let mut batch = serde_json::Map::new();
+    let mut batch_ticks = interval(options.batch_by_mac.unwrap_or(Duration::from_secs(1)));
+    if options.send_header {
+        let header = json!({
+            "event": "header",
+            "schema_version": output::SCHEMA_VERSION,
+            "fields": output::keys(&options),
+            "format": format.name(),
+        });
+        let frame = output::encode(format, &options.framing, &header);
+        if let Err(e) = write_frame(&mut socket, &frame).await {
+            debug!("Failed to send header: {:?}", e);
+        }
+    }
     loop {
-        let sv = receiver.recv().await.unwrap();
-        trace!("Socket RX {:?}", sv);
+        let reading = match replay.pop_front() {
+            Some(reading) => reading,
+            None => tokio::select! {
+                reading = client.receiver.recv() => match reading {
+                    Some(reading)
+                        if caught_up_to.is_some_and(|newest| reading.parsed_at <= newest) =>
+                    {
+                        continue
+                    }
+                    Some(reading) => reading,
+                    None => break,
+                },
+                line = commands.next_line(), if reading_commands => {
+                    match line {
+                        Ok(Some(line)) if line.trim().is_empty() => {}
+                        Ok(Some(line)) => {
+                            let reply = match control::parse(&line) {
+                                Ok(command) => {
+                                    debug!("Client command: {:?}", command);
+                                    let mut reply = control::ok_reply(&command);
+                                    match command {
+                                        control::Command::Filter(f) => filter = f,
+                                        control::Command::Fields(f) => projection = f,
+                                        control::Command::Subscribe(mac) => {
+                                            subscriptions.subscribe(mac)
+                                        }
+                                        control::Command::Unsubscribe(mac) => {
+                                            subscriptions.unsubscribe(&mac)
+                                        }
+                                        control::Command::Format(f) => {
+                                            format = f;
+                                            csv_header = None;
+                                        }
+                                        control::Command::Rate(r) => throttle.set_interval(r),
+                                        control::Command::Multiplex(m) => multiplex = m,
+                                        control::Command::Catchup => match client.catch_up() {
+                                            Some(readings) => {
+                                                reply["readings"] = json!(readings.len());
+                                                if let Some(newest) = readings.back() {
+                                                    caught_up_to = Some(newest.parsed_at);
+                                                }
+                                                replay = readings;
+                                            }
+                                            None => {
+                                                reply = control::error_reply(
+                                                    "catch-up is off, see --catchup-secs",
+                                                )
+                                            }
+                                        },
+                                        control::Command::Scan { pause, token } => {
+                                            let expected = options.admin_token.as_ref();
+                                            if !expected.is_some_and(|t| t.matches(&token)) {
+                                                warn!(
+                                                    "Refused SCAN from {}, wrong admin token",
+                                                    peer
+                                                );
+                                                reply = control::error_reply(
+                                                    "SCAN needs the --admin-token of the bridge",
+                                                );
+                                            } else if pause::set(pause) {
+                                                info!(
+                                                    "Scan {} by {}",
+                                                    if pause { "paused" } else { "resumed" },
+                                                    peer
+                                                );
+                                            }
+                                        }
+                                    }
+                                    reply
+                                }
+                                Err(rejected) => {
+                                    debug!(
+                                        "Rejected client command {:?}: {}",
+                                        line, rejected.message
+                                    );
+                                    // A broken filter must not leave an older one silently in place
+                                    if rejected.verb == "FILTER" {
+                                        filter = None;
+                                    }
+                                    control::error_reply(&rejected.message)
+                                }
+                            };
+                            let frame = output::encode(format, &options.framing, &reply);
+                            let _ = write_frame(&mut socket, &frame).await;
+                        }
+                        // The client is done talking but may well still be listening
+                        Ok(None) => reading_commands = false,
+                        Err(e) => {
+                            debug!("Failed to read client command: {:?}", e);
+                            reading_commands = false;
+                        }
+                    }
+                    continue;
+                }
+                _ = batch_ticks.tick(), if options.batch_by_mac.is_some() => {
+                    if !batch.is_empty() {
+                        let object = serde_json::Value::Object(std::mem::take(&mut batch));
+                        let frame = output::encode(format, &options.framing, &object);
+                        if let Err(e) = write_frame(&mut socket, &frame).await {
+                            info!("Closing socket: {:?}", e);
+                            let _ = socket.shutdown().await;
+                            break;
+                        }
+                    }
+                    continue;
+                }
+                _ = shutdown.recv() => {
+                    send_shutdown_notice(&mut socket, format, &options.framing).await;
+                    break;
+                }
+            },
+        };
+        trace!("Socket RX {:?}", reading);
 
-        let value = json!({
-            "acceleration_vector_as_milli_g": sv.acceleration_vector_as_milli_g().map(|av| {
-            match av {
-                AccelerationVector(a, b, c) => Some(vec!(a, b, c)),
-            }
-            }),
-            "battery_potential_as_millivolts": sv.battery_potential_as_millivolts(),
-            "humidity_as_ppm": sv.humidity_as_ppm(),
-            "mac_address": sv.mac_address(),
-            "measurement_sequence_number": sv.measurement_sequence_number(),
-            "movement_counter": sv.movement_counter(),
-            "pressure_as_pascals": sv.pressure_as_pascals(),
-            "temperature_as_millikelvins": sv.temperature_as_millikelvins(),
-            "temperature_as_millicelsius": sv.temperature_as_millicelsius(),
-            "tx_power_as_dbm": sv.tx_power_as_dbm()
-        });
+        let dropped = client.take_dropped();
+        if dropped > 0 {
+            match options.on_lag {
+                server::OnLag::Skip => {
+                    warn!("Client {} fell behind, {} readings dropped", peer, dropped)
+                }
+                server::OnLag::Disconnect => {
+                    warn!(
+                        "Disconnecting client {}, it fell behind and {} readings were dropped",
+                        peer, dropped
+                    );
+                    let _ = socket.shutdown().await;
+                    break;
+                }
+                server::OnLag::Panic => {
+                    panic!("Client {} fell behind, {} readings dropped", peer, dropped)
+                }
+            }
+            if let Some(lag) = &mut lag {
+                if lag.lagged(dropped, Instant::now()) {
+                    warn!("Disconnecting slow client {}: {}", peer, lag);
+                    let _ = socket.shutdown().await;
+                    break;
+                }
+            }
+        }
 
-        let s = value.to_string();
-        let json_bytes = s.as_bytes();
-        let newline_bytes = b"\r\n";
+        if let Some(max_age) = options.max_message_age {
+            if reading.parsed_at.elapsed() > max_age {
+                trace!("Dropping reading older than {:?}", max_age);
+                continue;
+            }
+        }
 
-        let json_write_res = socket.write_all(&json_bytes).await;
-        let newline_write_res = socket.write_all(newline_bytes).await;
-        let flush_res = socket.flush().await;
-        match json_write_res.and(newline_write_res).and(flush_res) {
+        if !subscriptions.includes(reading.values.mac_address().as_ref()) {
+            continue;
+        }
+
+        if let Some(filter) = &filter {
+            if !filter.matches(&reading.values) {
+                continue;
+            }
+        }
+
+        if !throttle.allow(reading.values.mac_address(), Instant::now()) {
+            continue;
+        }
+
+        let mut value = output::to_json(&reading, &options);
+        if options.pipeline_latency {
+            let latency = reading.parsed_at.elapsed().as_secs_f64() * 1000.0;
+            value.insert(
+                options.key("pipeline_latency_ms").to_string(),
+                options.float(latency),
+            );
+        }
+        if let Some(selected) = &projection {
+            output::project(&mut value, selected, &options);
+        }
+
+        if options.batch_by_mac.is_some() {
+            // Readings without a MAC address have nothing to be keyed by
+            if let Some(mac) = reading.values.mac_address() {
+                batch.insert(mac::format(&mac), serde_json::Value::Object(value));
+            }
+            continue;
+        }
+
+        #[cfg(feature = "otel")]
+        let _write_span = otel::write_span(&reading);
+        let token =
+            multiplex.then(|| control::channel_token(reading.values.mac_address().as_ref()));
+        let token = token.as_deref();
+        let frame = match &options.template {
+            Some(template) => {
+                let line = template.render(&reading, &value, &options);
+                output::frame(
+                    &options.framing,
+                    output::with_token(token, line).into_bytes(),
+                )
+            }
+            None if format == output::Format::Csv => {
+                let header = output::csv_header(&value, &options);
+                let mut frame = Vec::new();
+                if csv_header.as_ref() != Some(&header) {
+                    frame = output::frame(&options.framing, header.clone().into_bytes());
+                    csv_header = Some(header);
+                }
+                frame.extend(output::encode_reading(
+                    format,
+                    value,
+                    reading.received_at_unix_ms,
+                    &options,
+                    token,
+                ));
+                frame
+            }
+            None => {
+                output::encode_reading(format, value, reading.received_at_unix_ms, &options, token)
+            }
+        };
+        match write_frame(&mut socket, &frame).await {
             Ok(v) => trace!("Socket write and flush: {:?}", v),
             Err(e) => match e.kind() {
                 std::io::ErrorKind::BrokenPipe => {
@@ -131,6 +789,81 @@ async fn handle_socket(mut socket: TcpStream, mut receiver: broadcast::Receiver<
     }
 }
 
+async fn handle_error_socket(
+    mut socket: TcpStream,
+    mut receiver: broadcast::Receiver<ParseFailure>,
+    mut shutdown: broadcast::Receiver<()>,
+    _shutdown_complete: mpsc::Sender<()>,
+) {
+    info!("New error socket connection: {:?}", socket);
+    loop {
+        let failure = tokio::select! {
+            failure = receiver.recv() => match failure {
+                Ok(failure) => failure,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    debug!("Error socket lagged, skipped {} records", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = shutdown.recv() => {
+                let framing = output::Framing::default();
+                send_shutdown_notice(&mut socket, output::Format::Json, &framing).await;
+                break;
+            }
+        };
+
+        let value = json!({
+            "manufacturer_id": failure.manufacturer_id,
+            "kind": metrics::parse_error_kind(&failure.error),
+            "message": failure.error.to_string(),
+            "bytes": failure.bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+        });
+
+        let frame = output::encode(output::Format::Json, &output::Framing::default(), &value);
+        if let Err(e) = write_frame(&mut socket, &frame).await {
+            info!("Closing error socket: {:?}", e);
+            let _ = socket.shutdown().await;
+            break;
+        }
+    }
+}
+
+/// What to do when no reading arrives within --initial-event-timeout
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InitialTimeoutAction {
+    Exit,
+    /// Warn once every timeout until the first reading arrives
+    Warn,
+}
+
+impl FromStr for InitialTimeoutAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exit" => Ok(InitialTimeoutAction::Exit),
+            "warn" => Ok(InitialTimeoutAction::Warn),
+            _ => Err(format!("expected exit or warn, got {:?}", s)),
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+    }
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "ruuvi-jsonl-socket-bridge",
@@ -139,27 +872,673 @@ async fn handle_socket(mut socket: TcpStream, mut receiver: broadcast::Receiver<
 )]
 struct Opt {
     /// Host/IP address to listen on
-    #[structopt(short, long, default_value = "localhost")]
+    #[structopt(
+        short,
+        long,
+        default_value = "localhost",
+        env = "RUUVI_BRIDGE_HOSTNAME"
+    )]
     hostname: String,
 
+    /// Listen on every network interface, whatever --hostname says
+    #[structopt(long)]
+    all_interfaces: bool,
+
     /// Port
-    #[structopt(short, long, default_value = "22222")]
+    #[structopt(short, long, default_value = "22222", env = "RUUVI_BRIDGE_PORT")]
     port: i16,
 
+    /// Check the configuration, files, addresses and Bluetooth adapters, then exit: 0 if the
+    /// bridge could start like this, 1 if not. Binds nothing and doesn't scan.
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Send a synthetic reading through the data socket to a client inside the bridge, check
+    /// the JSON it gets and exit: 0 if it was right, 4 if not. Needs no Bluetooth.
+    #[structopt(long)]
+    self_test: bool,
+
+    /// Scan with every Bluetooth adapter instead of just the first
+    #[structopt(long)]
+    all_adapters: bool,
+
+    /// Restart the scan if no Bluetooth events arrive for this long; catches scans that
+    /// silently stall while their event stream stays open
+    #[structopt(long, parse(try_from_str = parse_positive))]
+    no_event_restart_secs: Option<f64>,
+
+    /// Also parse service data under this UUID as RuuviTag data, for firmware that advertises
+    /// it there; a measurement that also arrives as manufacturer data is only passed on once
+    #[structopt(long)]
+    ruuvi_service_uuid: Option<uuid::Uuid>,
+
+    /// File of "<MAC address> <key in hex>" lines with the keys to decrypt data format 8 with;
+    /// advertisements of tags without a key are skipped
+    #[structopt(long, parse(from_os_str))]
+    decrypt_keys_file: Option<PathBuf>,
+
+    /// Drop readings whose values are all the same as the previous reading of their tag,
+    /// counted in ruuvi_identical_dropped
+    #[structopt(long)]
+    dedup_identical: bool,
+
+    /// Pass on only every Nth reading of each tag, starting with its first
+    #[structopt(long, parse(try_from_str = parse_positive_count))]
+    sample_every: Option<usize>,
+
+    /// Drop advertisements whose checksum doesn't match their data, counted in
+    /// ruuvi_checksum_failures; only data format 8 carries one
+    #[structopt(long)]
+    verify_checksum: bool,
+
+    /// Seconds to wait before starting the Bluetooth scan again when it fails or ends; the
+    /// sockets keep serving in the meantime
+    #[structopt(long, default_value = "5", parse(try_from_str = parse_positive))]
+    scan_retry_secs: f64,
+
+    /// Shut down when the Bluetooth scan fails or ends instead of retrying it
+    #[structopt(long)]
+    no_scan_retry: bool,
+
     /// Timeout until initial Ruuvi event; 0 for no timeout
     #[structopt(short, long, default_value = "30")]
     initial_event_timeout: u8,
+
+    /// No initial event timeout, whatever --initial-event-timeout says
+    #[structopt(long)]
+    no_initial_timeout: bool,
+
+    /// What to do on the initial event timeout: exit with code 1, or warn and keep waiting
+    #[structopt(long, default_value = "exit", possible_values = &["exit", "warn"])]
+    initial_timeout_action: InitialTimeoutAction,
+
+    /// How many times to retry binding the listener if the address is in use
+    #[structopt(long, default_value = "5")]
+    bind_retries: u32,
+
+    /// Delay before the first bind retry in milliseconds, doubled after each attempt
+    #[structopt(long, default_value = "500")]
+    bind_retry_delay_ms: u64,
+
+    /// Port for a separate JSONL stream of manufacturer data that failed to parse
+    #[structopt(long)]
+    error_port: Option<u16>,
+
+    /// Also stream unknown manufacturer ids on the error port; these are expected noise from
+    /// non-Ruuvi devices and left out by default
+    #[structopt(long)]
+    error_include_unknown_manufacturers: bool,
+
+    /// Port for an HTTP endpoint serving the latest reading per tag at /latest?mac=... and
+    /// /snapshot, and metrics at /metrics
+    #[structopt(long)]
+    http_port: Option<u16>,
+
+    /// Port for a WebSocket server streaming the readings; needs the websocket feature
+    #[structopt(long)]
+    websocket_port: Option<u16>,
+
+    /// Prometheus Pushgateway like http://pushgateway:9091 to periodically push the /metrics
+    /// metric set to
+    #[structopt(long)]
+    pushgateway_url: Option<String>,
+
+    /// Job label of pushed metrics
+    #[structopt(long, default_value = "ruuvi")]
+    pushgateway_job: String,
+
+    /// Instance label of pushed metrics; left out if not given
+    #[structopt(long)]
+    pushgateway_instance: Option<String>,
+
+    /// Interval between pushes to the Pushgateway
+    #[structopt(long, default_value = "15", parse(try_from_str = parse_positive))]
+    pushgateway_interval_secs: f64,
+
+    /// Tags whose state is kept at a time, in each per-tag map; the least recently seen tag is
+    /// forgotten to make room for a new one
+    #[structopt(long, default_value = "4096", parse(try_from_str = parse_positive_count))]
+    max_tracked_macs: usize,
+
+    /// Forget tags not heard from for this long, so they drop out of /snapshot, /latest and
+    /// /metrics
+    #[structopt(long, parse(try_from_str = parse_positive))]
+    cache_ttl_secs: Option<f64>,
+
+    /// Exposition format of /metrics: prometheus or openmetrics
+    #[structopt(long, default_value = "prometheus", possible_values = &["prometheus", "openmetrics"])]
+    metrics_format: metrics::Format,
+
+    /// Histogram bucket bounds in seconds for the time between advertisements of a tag
+    #[structopt(long, default_value = "0.5,1,2,5,10,30,60,120,300")]
+    interarrival_buckets: metrics::Buckets,
+
+    /// Emit exponential moving averages of temperature and humidity per tag, giving the newest
+    /// sample this weight (0..1]
+    #[structopt(long, parse(try_from_str = parse_ema_alpha))]
+    ema_alpha: Option<f64>,
+
+    /// Emit temperature through a first-order low-pass filter with this time constant
+    #[structopt(long, parse(try_from_str = parse_positive))]
+    lowpass_tau_secs: Option<f64>,
+
+    /// Add temperature_rate_mc_per_min and pressure_rate_pa_per_min, the change since the tag's
+    /// previous reading per minute, smoothed with --ema-alpha if given
+    #[structopt(long)]
+    change_rates: bool,
+
+    /// Add heat_index_millicelsius, the apparent temperature by the heat index of the US National
+    /// Weather Service
+    #[structopt(long)]
+    heat_index: bool,
+
+    /// Hold readings back for this long and pass them on sorted by when they were received
+    #[structopt(long)]
+    reorder_buffer_ms: Option<u64>,
+
+    /// Drop implausible readings: values outside the --outlier-*-range bounds or changing more
+    /// than the --outlier-max-*-jump limits from the previous accepted reading of the tag
+    #[structopt(long)]
+    reject_outliers: bool,
+
+    /// Add pipeline_latency_ms, the time from parsing an advertisement to writing it to a client
+    #[structopt(long)]
+    pipeline_latency: bool,
+
+    /// Check readings against the RuuviTag's physical ranges and drop or flag (with
+    /// "suspect": true) those outside
+    #[structopt(long)]
+    on_invalid: Option<validate::OnInvalid>,
+
+    /// Add model_guess, the likely sensor model inferred from the data format and which values
+    /// are present; a heuristic, not authoritative
+    #[structopt(long)]
+    model_guess: bool,
+
+    /// Add firmware_guess, the likely firmware generation inferred like model_guess; a
+    /// heuristic for inventories, not authoritative
+    #[structopt(long)]
+    firmware_guess: bool,
+
+    /// Battery potential in millivolts below which a tag is warned about as low on battery
+    #[structopt(long, default_value = "2500")]
+    low_battery_mv: u16,
+
+    /// Add battery_status, "low" below --low-battery-mv and "ok" otherwise
+    #[structopt(long)]
+    battery_status: bool,
+
+    /// Add comfort, a heuristic label from temperature and humidity: cold, hot, dry, humid or
+    /// comfortable, by the --comfort-* bands
+    #[structopt(long)]
+    comfort: bool,
+
+    /// Temperature in Celsius below which comfort is cold
+    #[structopt(long, default_value = "18")]
+    comfort_min_c: f64,
+
+    /// Temperature in Celsius above which comfort is hot
+    #[structopt(long, default_value = "24")]
+    comfort_max_c: f64,
+
+    /// Relative humidity in percent below which comfort is dry
+    #[structopt(long, default_value = "30")]
+    comfort_min_humidity_pct: f64,
+
+    /// Relative humidity in percent above which comfort is humid
+    #[structopt(long, default_value = "60")]
+    comfort_max_humidity_pct: f64,
+
+    /// Add first_seen_unix_ms, when the tag was first seen since the bridge started
+    #[structopt(long)]
+    first_seen: bool,
+
+    /// Add seen_count, the number of readings of the tag received since the bridge started,
+    /// including readings dropped by --dedup-identical/--sample-every
+    #[structopt(long)]
+    seen_count: bool,
+
+    /// Add since_previous_ms, the time since the tag's previous reading; null for its first
+    #[structopt(long)]
+    since_previous: bool,
+
+    /// Add bridge_seq, a number the bridge gives each reading it broadcasts, one up from the
+    /// previous; gaps in it downstream are readings lost on the way
+    #[structopt(long)]
+    emit_bridge_seq: bool,
+
+    /// Add estimated_interval_ms, the tag's average advertising interval; null until a few of
+    /// its readings have arrived
+    #[structopt(long)]
+    estimated_interval: bool,
+
+    /// Add received_at, when the bridge received the advertisement
+    #[structopt(long)]
+    received_at: bool,
+
+    /// How received_at is written: iso, epoch-ms or epoch-s
+    #[structopt(long, default_value = "epoch-ms", possible_values = &["iso", "epoch-ms", "epoch-s"])]
+    timestamp_format: timestamp::Format,
+
+    /// Time zone of --timestamp-format iso: utc, local or, with the tz feature, a tz database
+    /// zone like Europe/Helsinki
+    #[structopt(long, default_value = "utc")]
+    timestamp_tz: timestamp::Zone,
+
+    /// Combine a tag's advertisements within this window into one reading, filling in values
+    /// missing from some of them; delays readings by up to the window
+    #[structopt(long)]
+    merge_window_ms: Option<u64>,
+
+    /// Readings that waited longer than this for a slow client are dropped instead of sent
+    #[structopt(long)]
+    max_message_age_ms: Option<u64>,
+
+    /// What to drop when the slowest consumer of the readings has fallen a full buffer behind:
+    /// drop-oldest, which that consumer then skips; drop-newest; or block-brief, holding up the
+    /// scan for up to 100 ms before dropping the newest
+    #[structopt(long, default_value = "drop-oldest", possible_values = &["drop-newest", "drop-oldest", "block-brief"])]
+    overflow_policy: overflow::OverflowPolicy,
+
+    /// Readings the internal stream of readings holds for its slowest consumer
+    #[structopt(
+        long,
+        default_value = "32",
+        parse(try_from_str = parse_positive_count),
+        env = "RUUVI_BRIDGE_CHANNEL_CAPACITY"
+    )]
+    channel_capacity: usize,
+
+    /// Send data socket clients one object per this many milliseconds instead of a line per
+    /// reading, keyed by MAC address with the newest reading of each tag in the window
+    #[structopt(long)]
+    batch_by_mac_ms: Option<u64>,
+
+    /// What a client gets before it SUBSCRIBEs to any tag: all or none
+    #[structopt(long, default_value = "all")]
+    default_subscription: control::DefaultSubscription,
+
+    /// Plausible temperature in Celsius; RuuviTag is rated for -40..85
+    #[structopt(long, default_value = "-60..85", allow_hyphen_values = true)]
+    outlier_temperature_range: outlier::Range,
+
+    /// Plausible relative humidity in percent
+    #[structopt(long, default_value = "0..100")]
+    outlier_humidity_range: outlier::Range,
+
+    /// Plausible pressure in hPa
+    #[structopt(long, default_value = "500..1155")]
+    outlier_pressure_range: outlier::Range,
+
+    /// Largest plausible temperature change between readings in Celsius
+    #[structopt(long, default_value = "10")]
+    outlier_max_temperature_jump: f64,
+
+    /// Largest plausible humidity change between readings in percentage points
+    #[structopt(long, default_value = "30")]
+    outlier_max_humidity_jump: f64,
+
+    /// Largest plausible pressure change between readings in hPa
+    #[structopt(long, default_value = "10")]
+    outlier_max_pressure_jump: f64,
+
+    /// Encoding of the data socket stream: json (one object per line), cbor (length-prefixed),
+    /// csv or influx (line protocol); clients can pick their own with FORMAT
+    #[structopt(
+        long,
+        default_value = "json",
+        possible_values = &["json", "cbor", "csv", "influx"],
+        env = "RUUVI_BRIDGE_FORMAT"
+    )]
+    format: output::Format,
+
+    /// How frames on the data socket are delimited: newline, or length-prefixed for a 4-byte
+    /// big-endian length before every frame
+    #[structopt(long, default_value = "newline", possible_values = &["newline", "length-prefixed"])]
+    framing: output::Framing,
+
+    /// Bytes ending every frame with --framing newline: lf, crlf, nul, a byte like 0x1e or a
+    /// string containing a control character
+    #[structopt(long, default_value = "crlf")]
+    delimiter: output::Delimiter,
+
+    /// Only accept connections from this IP address or CIDR range, like 192.168.1.0/24 or
+    /// fd00::/8; repeatable, everyone may connect when not given
+    #[structopt(long, number_of_values = 1)]
+    allow_ip: Vec<allow::IpNet>,
+
+    /// Also serve the data stream on a Unix socket at this path; Unix only
+    #[structopt(long, parse(from_os_str))]
+    unix_socket: Option<PathBuf>,
+
+    /// Only accept --unix-socket connections from processes of this user id; repeatable,
+    /// everyone who can open the socket file may connect when not given
+    #[structopt(long, number_of_values = 1)]
+    allow_uid: Vec<u32>,
+
+    /// Readings queued for each data socket client; a client whose queue is full misses
+    /// readings until it catches up
+    #[structopt(
+        long,
+        default_value = "32",
+        parse(try_from_str = parse_positive_count),
+        env = "RUUVI_BRIDGE_CLIENT_QUEUE_SIZE"
+    )]
+    client_queue_size: usize,
+
+    /// Secret that SCAN PAUSE and SCAN RESUME commands have to carry; without it they are
+    /// refused
+    #[structopt(long)]
+    admin_token: Option<control::Token>,
+
+    /// Secret that data socket clients have to send as their first line, AUTH <token>, within
+    /// a few seconds; others are disconnected
+    #[structopt(long)]
+    auth_token: Option<control::Token>,
+
+    /// Keep the readings of this many seconds for data socket clients to replay with CATCHUP
+    #[structopt(long, parse(try_from_str = parse_positive))]
+    catchup_secs: Option<f64>,
+
+    /// What to do when a data socket client falls behind and readings are dropped for it: skip
+    /// them, disconnect the client or panic
+    #[structopt(long, default_value = "skip", possible_values = &["skip", "disconnect", "panic"])]
+    on_lag: server::OnLag,
+
+    /// Disconnect a data socket client that fell this many times too far behind to get every
+    /// reading within --slow-client-window-secs
+    #[structopt(long, parse(try_from_str = parse_positive_count))]
+    slow_client_threshold: Option<usize>,
+
+    /// Window in which --slow-client-threshold lag events disconnect a client
+    #[structopt(long, default_value = "60", parse(try_from_str = parse_positive))]
+    slow_client_window_secs: f64,
+
+    /// Refuse data socket connections from a peer address that already has this many open
+    #[structopt(long, parse(try_from_str = parse_positive_count))]
+    max_connections_per_ip: Option<usize>,
+
+    /// PEM certificate chain to serve the data socket over TLS with; needs the tls feature
+    #[structopt(long, parse(from_os_str))]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[structopt(long, parse(from_os_str))]
+    tls_key: Option<PathBuf>,
+
+    /// PEM CA certificates; data socket clients then need a certificate signed by one of them
+    #[structopt(long, parse(from_os_str))]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Send every data socket client a header line listing the keys of the readings first
+    #[structopt(long)]
+    send_header: bool,
+
+    /// Compress the data socket stream: gzip, flushed after every line; clients have to know
+    #[structopt(long, possible_values = &["gzip"])]
+    compress: Option<output::Compression>,
+
+    /// Send readings on the data socket as lines in this format instead of JSON, e.g.
+    /// "{mac} {temperature_c} {humidity_pct}"; placeholders are mac, ts, the FILTER metric names
+    /// and the JSON keys, and {{ and }} are literal braces
+    #[structopt(long)]
+    template: Option<template::Template>,
+
+    /// Rendered for template placeholders that are unknown or have no value
+    #[structopt(long, default_value = "")]
+    template_missing: String,
+
+    /// Rename output fields, e.g. temperature_as_millicelsius=t,humidity_as_ppm=rh; other fields
+    /// keep their names, and no two fields may end up with the same key
+    #[structopt(long, default_value = "")]
+    field_map: output::FieldMap,
+
+    /// Case of the keys of readings: snake (temperature_as_millicelsius) or camel
+    /// (temperatureAsMillicelsius); --field-map renames take precedence
+    #[structopt(long, default_value = "snake", possible_values = &["snake", "camel"])]
+    key_case: output::KeyCase,
+
+    /// Comma separated Kafka bootstrap servers to produce readings to; needs the kafka feature
+    #[structopt(long)]
+    kafka_brokers: Option<String>,
+
+    /// Kafka topic for readings, keyed by MAC address
+    #[structopt(long, default_value = "ruuvi")]
+    #[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+    kafka_topic: String,
+
+    /// NATS server URL like nats://localhost:4222 to publish readings to; needs the nats feature
+    #[structopt(long)]
+    nats_url: Option<String>,
+
+    /// Readings are published on <prefix>.<mac> NATS subjects
+    #[structopt(long, default_value = "ruuvi")]
+    #[cfg_attr(not(feature = "nats"), allow(dead_code))]
+    nats_subject_prefix: String,
+
+    /// Redis URL like redis://localhost/ to publish readings to; needs the redis feature
+    #[structopt(long)]
+    redis_url: Option<String>,
+
+    /// Readings are published on <prefix>:<mac> Redis channels
+    #[structopt(long, default_value = "ruuvi")]
+    #[cfg_attr(not(feature = "redis"), allow(dead_code))]
+    redis_channel_prefix: String,
+
+    /// Append readings as JSON lines to this file
+    #[structopt(long, parse(from_os_str))]
+    file: Option<PathBuf>,
+
+    /// Interval in milliseconds at which buffered lines are written to --file; a full buffer is
+    /// written out right away
+    #[structopt(long, default_value = "1000")]
+    file_flush_ms: u64,
+
+    /// Size of the --file write buffer in bytes
+    #[structopt(long, default_value = "65536")]
+    file_buffer_bytes: usize,
+
+    /// Wait for every write of --file to reach the disk, so a power loss costs at most the
+    /// readings of one --file-flush-ms
+    #[structopt(long)]
+    file_fsync: bool,
+
+    /// Rename --file to <file>.<unix time in milliseconds> and start a new one before it would
+    /// grow past this many bytes
+    #[structopt(long)]
+    file_max_bytes: Option<u64>,
+
+    /// Rotated --file files to keep; older ones are deleted
+    #[structopt(long)]
+    file_keep: Option<usize>,
+
+    /// Write --file to a new file every day instead, named <stem>-YYYY-MM-DD.<extension> after
+    /// it; cannot be combined with --file-max-bytes
+    #[structopt(long, possible_values = &["daily"])]
+    file_rotate: Option<sink::file::Schedule>,
+
+    /// Whose midnight --file-rotate daily starts a new file at: utc or local
+    #[structopt(long, default_value = "utc", possible_values = &["utc", "local"])]
+    file_rotate_timezone: sink::file::DateZone,
+
+    /// Gzip rotated --file files in the background, adding a .gz suffix
+    #[structopt(long)]
+    compress_rotated: bool,
+
+    /// Log a one-line summary of tags, readings, clients and the scan at this interval
+    #[structopt(long, parse(try_from_str = parse_positive))]
+    summary_interval_secs: Option<f64>,
+
+    /// Periodically replace this file with a JSON array of the newest reading of every tag, the
+    /// same as GET /snapshot. Written atomically, so readers never see a partial file.
+    #[structopt(long, parse(from_os_str))]
+    snapshot_file: Option<PathBuf>,
+
+    /// Interval between writes of --snapshot-file
+    #[structopt(long, default_value = "10", parse(try_from_str = parse_positive))]
+    snapshot_interval_secs: f64,
+
+    /// OTLP gRPC endpoint like http://localhost:4317 to export traces of the readings to; needs
+    /// the otel feature
+    #[structopt(long)]
+    otlp_endpoint: Option<String>,
+
+    /// How mac_address is written: colon-upper, colon-lower, no-sep or bytes-array
+    #[structopt(long, default_value = "bytes-array", possible_values = &["colon-upper", "colon-lower", "no-sep", "bytes-array"])]
+    mac_format: mac::MacFormat,
+
+    /// Decimals kept in fractional output fields
+    #[structopt(long, default_value = "2")]
+    float_precision: u8,
+
+    /// Add dew_point_as_millicelsius and dew_point_as_millifahrenheit
+    #[structopt(long)]
+    dew_point: bool,
+
+    /// Add pressure_as_inhg, the pressure in inches of mercury
+    #[structopt(long)]
+    pressure_inhg: bool,
+
+    /// Add humidity_percent, the relative humidity in percent
+    #[structopt(long)]
+    humidity_percent: bool,
+
+    /// Add vapor_pressure_deficit_kpa
+    #[structopt(long)]
+    vapor_pressure_deficit: bool,
+
+    /// Add altitude_m, a rough altitude estimate from the pressure against --sea-level-pa
+    #[structopt(long)]
+    altitude: bool,
+
+    /// Sea-level pressure in pascals that altitude_m is estimated against
+    #[structopt(long, default_value = "101325")]
+    sea_level_pa: f64,
+
+    /// Altitude of the station in meters; adds sea_level_pressure_pa, and replaces altitude_m
+    #[structopt(long)]
+    station_altitude_m: Option<f64>,
+
+    /// Where logs go: stderr or syslog (Unix only)
+    #[structopt(long, default_value = "stderr")]
+    log_target: logging::Target,
+
+    /// Only log errors, whatever RUST_LOG says
+    #[structopt(short, long)]
+    quiet: bool,
+
+    /// Syslog facility used with --log-target syslog, e.g. daemon or local0
+    #[structopt(long, default_value = "daemon", parse(try_from_str = logging::parse_facility))]
+    syslog_facility: syslog::Facility,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::builder().format_timestamp(None).init();
+/// The host the listeners bind: --hostname, or with --all-interfaces the wildcard address. That
+/// is the IPv6 one where the host has IPv6, bound dual-stack so it takes IPv4 connections too
+/// (see `server::bind_listener`), and the IPv4 one otherwise.
+fn listen_host(opt: &Opt) -> String {
+    if !opt.all_interfaces {
+        return opt.hostname.clone();
+    }
+    if cfg!(unix) && std::net::TcpListener::bind("[::]:0").is_ok() {
+        "[::]".to_string()
+    } else {
+        "0.0.0.0".to_string()
+    }
+}
 
+fn outlier_limits(opt: &Opt) -> Vec<outlier::Limit> {
+    if !opt.reject_outliers {
+        return Vec::new();
+    }
+    vec![
+        outlier::Limit {
+            metric: "temperature_c",
+            range: opt.outlier_temperature_range,
+            max_jump: opt.outlier_max_temperature_jump,
+        },
+        outlier::Limit {
+            metric: "humidity_pct",
+            range: opt.outlier_humidity_range,
+            max_jump: opt.outlier_max_humidity_jump,
+        },
+        outlier::Limit {
+            metric: "pressure_hpa",
+            range: opt.outlier_pressure_range,
+            max_jump: opt.outlier_max_pressure_jump,
+        },
+    ]
+}
+
+fn parse_positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if value > 0.0 => Ok(value),
+        _ => Err(format!("expected a positive number, got {:?}", s)),
+    }
+}
+
+fn parse_positive_count(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(value) if value > 0 => Ok(value),
+        _ => Err(format!("expected a positive integer, got {:?}", s)),
+    }
+}
+
+fn parse_ema_alpha(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(alpha) if alpha > 0.0 && alpha <= 1.0 => Ok(alpha),
+        _ => Err(format!("expected a number in (0, 1], got {:?}", s)),
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // Before the runtime starts any threads, see ListenEnv::take
+    let listen_env = activation::ListenEnv::take();
+    tokio::runtime::Runtime::new()?.block_on(run(listen_env))
+}
+
+async fn run(listen_env: activation::ListenEnv) -> Result<(), Box<dyn Error>> {
     let opt = Opt::from_args();
+    if let Err(e) = logging::init(opt.log_target, opt.syslog_facility, opt.quiet) {
+        eprintln!("Failed to set up logging to {:?}: {}", opt.log_target, e);
+        process::exit(1);
+    }
     info!("CLI opts: {:?}", opt);
+    if opt.dry_run {
+        let problems = preflight::run(&opt).await;
+        for problem in &problems {
+            error!("Configuration problem: {}", problem);
+        }
+        if problems.is_empty() {
+            info!("Dry run passed");
+            process::exit(0);
+        }
+        process::exit(1);
+    }
+    if let Some(endpoint) = &opt.otlp_endpoint {
+        #[cfg(feature = "otel")]
+        {
+            if let Err(e) = otel::init(endpoint) {
+                error!("Failed to set up OTLP export to {:?}: {}", endpoint, e);
+                return Err(e);
+            }
+            info!("Exporting traces to {}", endpoint);
+        }
+        #[cfg(not(feature = "otel"))]
+        {
+            error!(
+                "OTLP endpoint {:?} given, but built without the otel feature",
+                endpoint
+            );
+            return Err("built without the otel feature".into());
+        }
+    }
     info!("Starting up...");
+    let mut inherited = activation::Inherited::from_env(listen_env);
 
-    let (tx, mut _rx) = broadcast::channel::<SensorValues>(32);
+    let (tx, _) = broadcast::channel::<Reading>(opt.channel_capacity);
+    let error_tx = opt
+        .error_port
+        .map(|_| broadcast::channel::<ParseFailure>(32).0);
 
     // Listener task for debugging:
     // tokio::spawn(async move {
@@ -170,41 +1549,737 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // 	}
     // });
 
-    if opt.initial_event_timeout != 0 {
+    if opt.initial_event_timeout != 0 && !opt.no_initial_timeout {
         let monitor_tx = tx.clone();
+        let timeout = Duration::from_secs(u64::from(opt.initial_event_timeout));
+        let action = opt.initial_timeout_action;
         let _monitor_task = tokio::spawn(async move {
             let mut receiver = monitor_tx.clone().subscribe();
-            let event_task = receiver.recv();
-            let sleep_task = sleep(Duration::from_secs(u64::from(opt.initial_event_timeout)));
+            let mut waited = Duration::ZERO;
+            loop {
+                let event_task = receiver.recv();
+                let sleep_task = sleep(timeout);
 
-            tokio::select! {
-                _ = event_task => {
-                    info!("Received a Ruuvi event before initial timeout, Bluetooth stack seems to be fine!")
-                }
-                _ = sleep_task => {
-                    error!("No Ruuvi events within the initial timeout. Is the Bluetooth stack properly initialized? Exiting!");
-                    process::exit(1);
-                }
-            };
+                tokio::select! {
+                    _ = event_task => {
+                        info!("Received a Ruuvi event before initial timeout, Bluetooth stack seems to be fine!");
+                        break;
+                    }
+                    _ = sleep_task => match action {
+                        InitialTimeoutAction::Exit => {
+                            error!("No Ruuvi events within the initial timeout. Is the Bluetooth stack properly initialized? Exiting!");
+                            process::exit(1);
+                        }
+                        InitialTimeoutAction::Warn => {
+                            waited += timeout;
+                            warn!(
+                                "Still no Ruuvi events after {:?}, is any tag powered?",
+                                waited
+                            );
+                        }
+                    },
+                };
+            }
         });
     }
 
     let socket_tx = tx.clone();
-    let _bt_task = tokio::spawn(async move {
-        let _ = bt_event_scan(tx).await;
+    let readings_tx = tx.clone();
+    let scan_error_tx = error_tx.clone();
+    let include_unknown_manufacturers = opt.error_include_unknown_manufacturers;
+    let all_adapters = opt.all_adapters;
+    let no_event_restart = opt.no_event_restart_secs.map(Duration::from_secs_f64);
+    let ruuvi_service = opt.ruuvi_service_uuid;
+    let verify_checksum = opt.verify_checksum;
+    let max_tracked_macs = opt.max_tracked_macs;
+    let keys = match &opt.decrypt_keys_file {
+        Some(path) => match decrypt::Keys::load(path) {
+            Ok(keys) => {
+                info!("Decrypting data format 8 of {:?}", keys);
+                Some(keys)
+            }
+            Err(e) => {
+                error!("Could not read decryption keys: {}", e);
+                return Err(e);
+            }
+        },
+        None => None,
+    };
+    let pipeline = Pipeline {
+        tx: overflow::Front::new(opt.overflow_policy, tx, opt.channel_capacity),
+        tracker: tracker::Tracker::new(tracker::Config {
+            interarrival_buckets: opt.interarrival_buckets.clone(),
+            ema_alpha: opt.ema_alpha,
+            lowpass_tau_secs: opt.lowpass_tau_secs,
+            change_rates: opt.change_rates,
+            outlier_limits: outlier_limits(&opt),
+            max_tracked_macs: opt.max_tracked_macs,
+        }),
+        on_invalid: opt.on_invalid,
+        model_detector: opt
+            .model_guess
+            .then(|| model::Detector::new(opt.max_tracked_macs)),
+        reorder: opt
+            .reorder_buffer_ms
+            .map(|ms| reorder::Reorder::new(Duration::from_millis(ms))),
+        firmware_guess: opt.firmware_guess,
+        battery_monitor: battery::Monitor::new(opt.low_battery_mv, opt.max_tracked_macs),
+        identical: opt
+            .dedup_identical
+            .then(|| identical::Identical::new(opt.max_tracked_macs)),
+        sampler: opt
+            .sample_every
+            .map(|every| sample::Sampler::new(every, opt.max_tracked_macs)),
+    };
+    let seen_tags = pipeline.tracker.seen();
+    let merger = opt
+        .merge_window_ms
+        .map(|ms| merge::Merger::new(Duration::from_millis(ms), opt.max_tracked_macs));
+    let field_map = match opt.field_map.clone().with_case(opt.key_case) {
+        Ok(field_map) => field_map,
+        Err(e) => {
+            error!("--field-map doesn't work with --key-case camel: {}", e);
+            return Err(e.into());
+        }
+    };
+    let output_options = Arc::new(output::Options {
+        format: opt.format,
+        compress: opt.compress,
+        send_header: opt.send_header,
+        on_lag: opt.on_lag,
+        slow_client_threshold: opt.slow_client_threshold,
+        slow_client_window: Duration::from_secs_f64(opt.slow_client_window_secs),
+        auth_token: opt.auth_token.clone(),
+        admin_token: opt.admin_token.clone(),
+        framing: match opt.framing {
+            output::Framing::Delimited(_) => output::Framing::Delimited(opt.delimiter.clone()),
+            output::Framing::LengthPrefixed => output::Framing::LengthPrefixed,
+        },
+        ema: opt.ema_alpha.is_some(),
+        lowpass: opt.lowpass_tau_secs.is_some(),
+        change_rates: opt.change_rates,
+        heat_index: opt.heat_index,
+        model_guess: opt.model_guess,
+        firmware_guess: opt.firmware_guess,
+        battery_status: opt.battery_status,
+        low_battery_mv: opt.low_battery_mv,
+        comfort: opt.comfort.then(|| {
+            comfort::Thresholds::new(
+                opt.comfort_min_c,
+                opt.comfort_max_c,
+                opt.comfort_min_humidity_pct,
+                opt.comfort_max_humidity_pct,
+            )
+        }),
+        first_seen: opt.first_seen,
+        seen_count: opt.seen_count,
+        since_previous: opt.since_previous,
+        bridge_seq: opt.emit_bridge_seq,
+        estimated_interval: opt.estimated_interval,
+        received_at: opt.received_at,
+        timestamp_format: opt.timestamp_format,
+        timestamp_zone: opt.timestamp_tz,
+        pipeline_latency: opt.pipeline_latency,
+        float_precision: opt.float_precision,
+        dew_point: opt.dew_point,
+        pressure_inhg: opt.pressure_inhg,
+        humidity_percent: opt.humidity_percent,
+        vapor_pressure_deficit: opt.vapor_pressure_deficit,
+        altitude: opt.altitude,
+        sea_level_pa: opt.sea_level_pa,
+        station_altitude_m: opt.station_altitude_m,
+        max_message_age: opt.max_message_age_ms.map(Duration::from_millis),
+        batch_by_mac: opt.batch_by_mac_ms.map(Duration::from_millis),
+        template: opt.template.clone(),
+        template_missing: opt.template_missing.clone(),
+        field_map,
+        mac_format: opt.mac_format,
+        max_tracked_macs: opt.max_tracked_macs,
     });
+    if opt.self_test {
+        match selftest::run(&output_options).await {
+            Ok(()) => {
+                info!("Self-test passed");
+                process::exit(0);
+            }
+            Err(e) => {
+                error!("Self-test failed: {}", e);
+                process::exit(SELF_TEST_EXIT_CODE);
+            }
+        }
+    }
+    let scan_retry = if opt.no_scan_retry {
+        None
+    } else {
+        Some(Duration::from_secs_f64(opt.scan_retry_secs))
+    };
+    let bt_task = tokio::spawn(async move {
+        let (mut pipeline, mut merger) = (pipeline, merger);
+        loop {
+            match bt_event_scan(
+                &mut pipeline,
+                &mut merger,
+                scan_error_tx.clone(),
+                include_unknown_manufacturers,
+                all_adapters,
+                no_event_restart,
+                ruuvi_service,
+                keys.as_ref(),
+                verify_checksum,
+                max_tracked_macs,
+            )
+            .await
+            {
+                Ok(()) => warn!("Bluetooth scan ended"),
+                Err(e) => error!("Bluetooth scan failed: {}", e),
+            }
+            metrics::inc(&metrics::METRICS.scan_failures);
+            // Degraded mode: clients stay connected, without readings until the scan is back
+            match scan_retry {
+                Some(delay) => {
+                    warn!("Retrying the Bluetooth scan in {:?}", delay);
+                    sleep(delay).await;
+                }
+                None => break,
+            }
+        }
+    });
+
+    let (shutdown_tx, _) = broadcast::channel::<()>(1);
+    // Subscribed before the scan can stop, so an early stop isn't missed by the accept loop
+    let accept_shutdown = shutdown_tx.subscribe();
+
+    // The scan task only stops by panicking or with --no-scan-retry. Without the scan there is
+    // nothing to serve, so shut down and let the service manager restart the bridge instead of
+    // leaving clients connected to a silent socket
+    let scan_stopped = Arc::new(AtomicBool::new(false));
+    {
+        let scan_stopped = scan_stopped.clone();
+        let shutdown_tx = shutdown_tx.clone();
+        tokio::spawn(async move {
+            match bt_task.await {
+                Ok(()) => error!("Bluetooth scan task ended, shutting down"),
+                Err(e) if e.is_panic() => {
+                    let payload = e.into_panic();
+                    let message = payload
+                        .downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| payload.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "non-string panic payload".to_string());
+                    error!("Bluetooth scan task panicked, shutting down: {}", message);
+                }
+                Err(e) => error!("Bluetooth scan task was cancelled, shutting down: {}", e),
+            }
+            scan_stopped.store(true, Ordering::SeqCst);
+            let _ = shutdown_tx.send(());
+        });
+    }
+    let allowed = allow::AllowList(opt.allow_ip.clone());
+    let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel::<()>(1);
+    let bind_retry_delay = Duration::from_millis(opt.bind_retry_delay_ms);
+    let host = listen_host(&opt);
+    if opt.all_interfaces {
+        let families = match host.as_str() {
+            "[::]" => "IPv4 and IPv6",
+            _ => "IPv4 only",
+        };
+        warn!(
+            "Listening on all interfaces ({}, {}): the readings are exposed to anyone who can \
+             reach this machine, restrict them with --allow-ip or a firewall",
+            host, families
+        );
+        if opt.hostname != "localhost" {
+            warn!(
+                "--hostname {:?} is ignored with --all-interfaces",
+                opt.hostname
+            );
+        }
+    }
 
-    let mut bind_addr = opt.hostname.to_owned();
-    bind_addr.push_str(&":");
+    if let (Some(error_port), Some(error_tx)) = (opt.error_port, error_tx) {
+        let error_bind_addr = format!("{}:{}", host, error_port);
+        debug!("Starting error socket listener at {:?}", error_bind_addr);
+        let error_listener = match activation::listen(
+            &mut inherited,
+            "error",
+            &error_bind_addr,
+            opt.bind_retries,
+            bind_retry_delay,
+        )
+        .await
+        {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Could not bind to {:?}: {}", error_bind_addr, e);
+                return Err(e.into());
+            }
+        };
+        let error_shutdown_tx = shutdown_tx.clone();
+        let error_shutdown_complete_tx = shutdown_complete_tx.clone();
+        let error_allowed = allowed.clone();
+        tokio::spawn(async move {
+            let error_shutdown = error_shutdown_tx.subscribe();
+            let _ = accept_loop(error_listener, error_shutdown, error_allowed, |socket| {
+                let receiver = error_tx.subscribe();
+                let shutdown_rx = error_shutdown_tx.subscribe();
+                let shutdown_complete = error_shutdown_complete_tx.clone();
+                tokio::spawn(async move {
+                    handle_error_socket(socket, receiver, shutdown_rx, shutdown_complete).await;
+                });
+            })
+            .await;
+        });
+    }
+
+    if let Some(path) = &opt.file {
+        if opt.file_rotate.is_some() && opt.file_max_bytes.is_some() {
+            error!("--file-rotate and --file-max-bytes cannot be combined");
+            return Err("conflicting file rotation options".into());
+        }
+        let file = match sink::file::FileSink::open(
+            path,
+            opt.file_buffer_bytes,
+            Duration::from_millis(opt.file_flush_ms),
+            opt.file_fsync,
+            sink::file::Rotation {
+                max_bytes: opt.file_max_bytes,
+                schedule: opt.file_rotate,
+                timezone: opt.file_rotate_timezone,
+                keep: opt.file_keep,
+                compress: opt.compress_rotated,
+            },
+        )
+        .await
+        {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Could not open {:?}: {}", path, e);
+                return Err(e.into());
+            }
+        };
+        info!("Appending readings to {:?}", path);
+        tokio::spawn(file.run(
+            readings_tx.subscribe(),
+            output_options.clone(),
+            shutdown_tx.subscribe(),
+            shutdown_complete_tx.clone(),
+        ));
+    }
+
+    if let Some(brokers) = &opt.kafka_brokers {
+        #[cfg(feature = "kafka")]
+        {
+            let kafka = sink::kafka::KafkaSink::new(brokers, &opt.kafka_topic)?;
+            info!(
+                "Producing readings to Kafka topic {:?} at {}",
+                opt.kafka_topic, brokers
+            );
+            tokio::spawn(sink::run(
+                kafka,
+                readings_tx.subscribe(),
+                output_options.clone(),
+            ));
+        }
+        #[cfg(not(feature = "kafka"))]
+        {
+            error!(
+                "Kafka brokers {:?} given, but built without the kafka feature",
+                brokers
+            );
+            return Err("built without the kafka feature".into());
+        }
+    }
+
+    let nats_connected = opt
+        .nats_url
+        .as_ref()
+        .map(|_| Arc::new(AtomicBool::new(false)));
+    if let Some(url) = &opt.nats_url {
+        #[cfg(feature = "nats")]
+        {
+            let connected = nats_connected.clone().expect("set along with the URL");
+            let nats = sink::nats::NatsSink::new(url, &opt.nats_subject_prefix, connected).await?;
+            info!(
+                "Publishing readings to NATS subjects {:?}.<mac>",
+                opt.nats_subject_prefix
+            );
+            tokio::spawn(sink::run(
+                nats,
+                readings_tx.subscribe(),
+                output_options.clone(),
+            ));
+        }
+        #[cfg(not(feature = "nats"))]
+        {
+            error!(
+                "NATS URL {:?} given, but built without the nats feature",
+                url
+            );
+            return Err("built without the nats feature".into());
+        }
+    }
+
+    if let Some(url) = &opt.redis_url {
+        #[cfg(feature = "redis")]
+        {
+            let redis = sink::redis::RedisSink::new(url, &opt.redis_channel_prefix)?;
+            info!(
+                "Publishing readings to Redis channels {:?}:<mac>",
+                opt.redis_channel_prefix
+            );
+            tokio::spawn(sink::run(
+                redis,
+                readings_tx.subscribe(),
+                output_options.clone(),
+            ));
+        }
+        #[cfg(not(feature = "redis"))]
+        {
+            error!(
+                "Redis URL {:?} given, but built without the redis feature",
+                url
+            );
+            return Err("built without the redis feature".into());
+        }
+    }
+
+    let last_values = cache::new(opt.max_tracked_macs);
+    if opt.http_port.is_some() || opt.snapshot_file.is_some() || opt.pushgateway_url.is_some() {
+        tokio::spawn(cache::run(readings_tx.subscribe(), last_values.clone()));
+        if let Some(ttl) = opt.cache_ttl_secs {
+            tokio::spawn(cache::evict(
+                last_values.clone(),
+                Duration::from_secs_f64(ttl),
+            ));
+        }
+    }
+
+    if let Some(period) = opt.summary_interval_secs {
+        tokio::spawn(metrics::log_summary(Duration::from_secs_f64(period)));
+    }
+
+    if let Some(path) = &opt.snapshot_file {
+        info!(
+            "Writing snapshots to {:?} every {}s",
+            path, opt.snapshot_interval_secs
+        );
+        tokio::spawn(snapshot::run(
+            last_values.clone(),
+            output_options.clone(),
+            path.clone(),
+            Duration::from_secs_f64(opt.snapshot_interval_secs),
+        ));
+    }
+
+    if let Some(url) = &opt.pushgateway_url {
+        let target = match pushgateway::Target::new(
+            url,
+            &opt.pushgateway_job,
+            opt.pushgateway_instance.as_deref(),
+        ) {
+            Ok(target) => target,
+            Err(e) => {
+                error!("Invalid Pushgateway URL: {}", e);
+                return Err(e.into());
+            }
+        };
+        info!("Pushing metrics to {:?}", target);
+        tokio::spawn(pushgateway::run(
+            target,
+            last_values.clone(),
+            Duration::from_secs_f64(opt.pushgateway_interval_secs),
+        ));
+    }
+
+    if let Some(http_port) = opt.http_port {
+        let state = http::State {
+            cache: last_values,
+            seen: seen_tags,
+            output: output_options.clone(),
+            metrics_format: opt.metrics_format,
+            nats_connected,
+        };
+
+        let http_bind_addr = format!("{}:{}", host, http_port);
+        debug!("Starting HTTP listener at {:?}", http_bind_addr);
+        let http_listener = match activation::listen(
+            &mut inherited,
+            "http",
+            &http_bind_addr,
+            opt.bind_retries,
+            bind_retry_delay,
+        )
+        .await
+        {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Could not bind to {:?}: {}", http_bind_addr, e);
+                return Err(e.into());
+            }
+        };
+        let http_shutdown = shutdown_tx.subscribe();
+        let http_allowed = allowed.clone();
+        tokio::spawn(async move {
+            let _ = accept_loop(http_listener, http_shutdown, http_allowed, |socket| {
+                tokio::spawn(http::handle_http(socket, state.clone()));
+            })
+            .await;
+        });
+    }
+
+    if let Some(websocket_port) = opt.websocket_port {
+        #[cfg(feature = "websocket")]
+        {
+            let websocket_bind_addr = format!("{}:{}", host, websocket_port);
+            debug!("Starting WebSocket listener at {:?}", websocket_bind_addr);
+            let websocket_listener = match activation::listen(
+                &mut inherited,
+                "websocket",
+                &websocket_bind_addr,
+                opt.bind_retries,
+                bind_retry_delay,
+            )
+            .await
+            {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Could not bind to {:?}: {}", websocket_bind_addr, e);
+                    return Err(e.into());
+                }
+            };
+            let websocket_tx = socket_tx.clone();
+            let options = output_options.clone();
+            let websocket_shutdown_tx = shutdown_tx.clone();
+            let shutdown_complete = shutdown_complete_tx.clone();
+            let accept_shutdown = shutdown_tx.subscribe();
+            let websocket_allowed = allowed.clone();
+            tokio::spawn(async move {
+                let _ = accept_loop(
+                    websocket_listener,
+                    accept_shutdown,
+                    websocket_allowed,
+                    |socket| {
+                        tokio::spawn(websocket::handle_websocket(
+                            socket,
+                            websocket_tx.subscribe(),
+                            options.clone(),
+                            websocket_shutdown_tx.subscribe(),
+                            shutdown_complete.clone(),
+                        ));
+                    },
+                )
+                .await;
+            });
+        }
+        #[cfg(not(feature = "websocket"))]
+        {
+            error!(
+                "WebSocket port {} given, but built without the websocket feature",
+                websocket_port
+            );
+            return Err("built without the websocket feature".into());
+        }
+    }
+
+    let mut bind_addr = host.clone();
+    bind_addr.push(':');
     bind_addr.push_str(&opt.port.to_string());
 
     debug!("Starting socket listener at {:?}", bind_addr);
-    let listener = TcpListener::bind(bind_addr).await.unwrap();
-    loop {
-        let (socket, _) = listener.accept().await.unwrap();
-        let receiver = socket_tx.subscribe();
+    let listener = match activation::listen(
+        &mut inherited,
+        "data",
+        &bind_addr,
+        opt.bind_retries,
+        bind_retry_delay,
+    )
+    .await
+    {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not bind to {:?}: {}", bind_addr, e);
+            return Err(e.into());
+        }
+    };
+
+    #[cfg(all(unix, feature = "systemd"))]
+    tokio::spawn(notify::run());
+
+    #[cfg(feature = "tls")]
+    let tls_acceptor = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => match tls::acceptor(cert, key, opt.tls_client_ca.as_deref()) {
+            Ok(acceptor) => {
+                info!(
+                    "Serving the data socket over TLS{}",
+                    if opt.tls_client_ca.is_some() {
+                        ", client certificates required"
+                    } else {
+                        ""
+                    }
+                );
+                Some(acceptor)
+            }
+            Err(e) => {
+                error!("Failed to set up TLS: {}", e);
+                return Err(e);
+            }
+        },
+        (None, None) if opt.tls_client_ca.is_none() => None,
+        _ => {
+            error!("--tls-cert and --tls-key go together, and --tls-client-ca needs both");
+            return Err("incomplete TLS options".into());
+        }
+    };
+    #[cfg(not(feature = "tls"))]
+    if opt.tls_cert.is_some() || opt.tls_key.is_some() || opt.tls_client_ca.is_some() {
+        error!("TLS options given, but built without the tls feature");
+        return Err("built without the tls feature".into());
+    }
+
+    let signal_shutdown_tx = shutdown_tx.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutdown requested, notifying clients...");
+        let _ = signal_shutdown_tx.send(());
+    });
+
+    let fanout = fanout::Fanout::new(
+        opt.client_queue_size,
+        opt.catchup_secs.map(Duration::from_secs_f64),
+    );
+    tokio::spawn(fanout::run(fanout.clone(), socket_tx.subscribe()));
+
+    if let Some(path) = &opt.unix_socket {
+        #[cfg(unix)]
+        {
+            let unix_listener = match activation::listen_unix(&mut inherited, path) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Could not bind Unix socket {:?}: {}", path, e);
+                    return Err(e.into());
+                }
+            };
+            info!("Listening on Unix socket {:?}", path);
+            let fanout = fanout.clone();
+            let output_options = output_options.clone();
+            let default_subscription = opt.default_subscription;
+            let unix_shutdown_tx = shutdown_tx.clone();
+            let unix_shutdown_complete_tx = shutdown_complete_tx.clone();
+            let accept_shutdown = shutdown_tx.subscribe();
+            let allowed_uids = opt.allow_uid.clone();
+            tokio::spawn(async move {
+                let _ = server::accept_unix_loop(
+                    unix_listener,
+                    accept_shutdown,
+                    allowed_uids,
+                    |socket, uid| {
+                        let peer = format!("unix uid {}", uid);
+                        let client = fanout.subscribe(&peer);
+                        let options = output_options.clone();
+                        let shutdown_rx = unix_shutdown_tx.subscribe();
+                        let shutdown_complete = unix_shutdown_complete_tx.clone();
+                        tokio::spawn(async move {
+                            handle_socket(
+                                socket,
+                                peer,
+                                client,
+                                options,
+                                default_subscription,
+                                shutdown_rx,
+                                shutdown_complete,
+                            )
+                            .await;
+                        });
+                    },
+                )
+                .await;
+            });
+        }
+        #[cfg(not(unix))]
+        {
+            error!(
+                "--unix-socket {:?} given, but Unix sockets need a Unix system",
+                path
+            );
+            return Err("Unix sockets are not supported on this platform".into());
+        }
+    }
+
+    let peer_limit = opt.max_connections_per_ip.map(server::PeerLimit::new);
+    let result = accept_loop(listener, accept_shutdown, allowed, |socket| {
+        let options = output_options.clone();
+        let default_subscription = opt.default_subscription;
+        let shutdown_rx = shutdown_tx.subscribe();
+        let shutdown_complete = shutdown_complete_tx.clone();
+        let peer = socket
+            .peer_addr()
+            .map_or_else(|_| "unknown peer".to_string(), |peer| peer.to_string());
+        let peer_guard = match (&peer_limit, socket.peer_addr()) {
+            (Some(limit), Ok(peer)) => match limit.acquire(peer.ip()) {
+                Some(guard) => Some(guard),
+                None => {
+                    warn!(
+                        "Refused connection from {}, it already has {} open",
+                        peer,
+                        opt.max_connections_per_ip.unwrap_or_default()
+                    );
+                    return;
+                }
+            },
+            _ => None,
+        };
+        let client = fanout.subscribe(&peer);
+        #[cfg(feature = "tls")]
+        if let Some(acceptor) = tls_acceptor.clone() {
+            tokio::spawn(async move {
+                let _peer_guard = peer_guard;
+                if let Some(socket) = tls::accept(&acceptor, socket).await {
+                    handle_socket(
+                        socket,
+                        peer,
+                        client,
+                        options,
+                        default_subscription,
+                        shutdown_rx,
+                        shutdown_complete,
+                    )
+                    .await;
+                }
+            });
+            return;
+        }
         tokio::spawn(async move {
-            handle_socket(socket, receiver).await;
+            let _peer_guard = peer_guard;
+            handle_socket(
+                socket,
+                peer,
+                client,
+                options,
+                default_subscription,
+                shutdown_rx,
+                shutdown_complete,
+            )
+            .await;
         });
+    })
+    .await;
+
+    // Every socket task holds a clone of the completion sender; once they have all sent their
+    // shutdown notice and dropped it, recv() returns None.
+    let _ = shutdown_tx.send(());
+    drop(shutdown_complete_tx);
+    if timeout(SHUTDOWN_GRACE, shutdown_complete_rx.recv())
+        .await
+        .is_err()
+    {
+        warn!("Not all clients were notified within {:?}", SHUTDOWN_GRACE);
+    }
+    #[cfg(feature = "otel")]
+    otel::shutdown();
+    info!("Shut down.");
+
+    if scan_stopped.load(Ordering::SeqCst) {
+        process::exit(SCAN_TASK_EXIT_CODE);
     }
+    result.map_err(|e| e.into())
 }
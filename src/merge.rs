@@ -0,0 +1,237 @@
+//! Combining the values of a tag's advertisements within a short window into one reading, for
+//! tags that leave some values out of individual advertisements.
+
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use ruuvi_sensor_protocol::{
+    Acceleration, AccelerationVector, BatteryPotential, Humidity, MacAddress,
+    MeasurementSequenceNumber, MovementCounter, Pressure, SensorValues, Temperature,
+    TransmitterPower,
+};
+
+use crate::lru::MacLru;
+use crate::mac;
+
+const RUUVI_MANUFACTURER_ID: u16 = 0x0499;
+
+/// Data format 5 carries every value the bridge knows, so merged readings are re-encoded in it,
+/// https://docs.ruuvi.com/ruuvi-sensor-protocols/dataformat-05
+const DATA_FORMAT_5: u8 = 5;
+
+#[derive(Debug, Default)]
+struct Values {
+    temperature_mk: Option<u32>,
+    humidity_ppm: Option<u32>,
+    pressure_pa: Option<u32>,
+    acceleration: Option<AccelerationVector>,
+    battery_mv: Option<u16>,
+    tx_power_dbm: Option<i8>,
+    movement_counter: Option<u32>,
+    sequence: Option<u32>,
+    mac: Option<[u8; 6]>,
+}
+
+impl Values {
+    fn of(sv: &SensorValues) -> Values {
+        Values {
+            temperature_mk: sv.temperature_as_millikelvins(),
+            humidity_ppm: sv.humidity_as_ppm(),
+            pressure_pa: sv.pressure_as_pascals(),
+            acceleration: sv.acceleration_vector_as_milli_g(),
+            battery_mv: sv.battery_potential_as_millivolts(),
+            tx_power_dbm: sv.tx_power_as_dbm(),
+            movement_counter: sv.movement_counter(),
+            sequence: sv.measurement_sequence_number(),
+            mac: sv.mac_address(),
+        }
+    }
+
+    /// Takes every value `newer` has, keeping ours where it has none
+    fn update(&mut self, newer: Values) {
+        fn take<T>(ours: &mut Option<T>, theirs: Option<T>) {
+            if theirs.is_some() {
+                *ours = theirs;
+            }
+        }
+        take(&mut self.temperature_mk, newer.temperature_mk);
+        take(&mut self.humidity_ppm, newer.humidity_ppm);
+        take(&mut self.pressure_pa, newer.pressure_pa);
+        take(&mut self.acceleration, newer.acceleration);
+        take(&mut self.battery_mv, newer.battery_mv);
+        take(&mut self.tx_power_dbm, newer.tx_power_dbm);
+        take(&mut self.movement_counter, newer.movement_counter);
+        take(&mut self.sequence, newer.sequence);
+        take(&mut self.mac, newer.mac);
+    }
+
+    fn is_complete(&self) -> bool {
+        self.temperature_mk.is_some()
+            && self.humidity_ppm.is_some()
+            && self.pressure_pa.is_some()
+            && self.acceleration.is_some()
+            && self.battery_mv.is_some()
+            && self.tx_power_dbm.is_some()
+            && self.movement_counter.is_some()
+            && self.sequence.is_some()
+            && self.mac.is_some()
+    }
+
+    /// Builds sensor values by encoding a data format 5 payload and parsing it back, since
+    /// `SensorValues` can't be constructed directly. Values outside what the format can carry
+    /// are clamped to its limits.
+    fn to_sensor_values(&self) -> SensorValues {
+        let i16_or_invalid = |v: Option<i64>| {
+            v.map_or(i16::MIN, |v| {
+                v.clamp(i64::from(i16::MIN) + 1, i64::from(i16::MAX)) as i16
+            })
+        };
+        let u16_or_invalid = |v: Option<i64>| v.map_or(u16::MAX, |v| v.clamp(0, 0xFFFE) as u16);
+
+        let temperature = i16_or_invalid(self.temperature_mk.map(|t| (i64::from(t) - 273_150) / 5));
+        let humidity = u16_or_invalid(self.humidity_ppm.map(|h| i64::from(h) / 25));
+        let pressure = u16_or_invalid(self.pressure_pa.map(|p| i64::from(p) - 50_000));
+        let acceleration = match self.acceleration {
+            Some(AccelerationVector(x, y, z)) => [x, y, z].map(|a| i16_or_invalid(Some(a.into()))),
+            None => [i16::MIN; 3],
+        };
+        let battery = self
+            .battery_mv
+            .map_or(2047, |mv| (i64::from(mv) - 1600).clamp(0, 2046) as u16);
+        let tx_power = self
+            .tx_power_dbm
+            .map_or(31, |dbm| ((i64::from(dbm) + 40) / 2).clamp(0, 30) as u16);
+        let movement_counter = self.movement_counter.map_or(0xFF, |m| m.min(0xFE) as u8);
+        let sequence = u16_or_invalid(self.sequence.map(i64::from));
+
+        let mut payload = vec![DATA_FORMAT_5];
+        payload.extend_from_slice(&temperature.to_be_bytes());
+        payload.extend_from_slice(&humidity.to_be_bytes());
+        payload.extend_from_slice(&pressure.to_be_bytes());
+        for a in acceleration {
+            payload.extend_from_slice(&a.to_be_bytes());
+        }
+        payload.extend_from_slice(&((battery << 5) | tx_power).to_be_bytes());
+        payload.push(movement_counter);
+        payload.extend_from_slice(&sequence.to_be_bytes());
+        payload.extend_from_slice(&self.mac.unwrap_or([0xFF; 6]));
+        SensorValues::from_manufacturer_specific_data(RUUVI_MANUFACTURER_ID, payload)
+            .expect("a complete data format 5 payload")
+    }
+}
+
+/// Sensor values with just temperature, humidity and pressure, for the URL data formats 2 and
+/// 4 that carry nothing else
+pub fn environmental(temperature_mk: u32, humidity_ppm: u32, pressure_pa: u32) -> SensorValues {
+    Values {
+        temperature_mk: Some(temperature_mk),
+        humidity_ppm: Some(humidity_ppm),
+        pressure_pa: Some(pressure_pa),
+        ..Values::default()
+    }
+    .to_sensor_values()
+}
+
+#[derive(Debug)]
+struct Pending {
+    values: Values,
+    /// Of the newest advertisement merged in
+    data_format: Option<u8>,
+    /// Of the newest advertisement merged in, see `Reading::received_at_unix_ms`
+    received_at_unix_ms: u64,
+    deadline: Instant,
+}
+
+impl Pending {
+    /// The merged values along with the data format and receive time to pass on with them
+    fn merged(self) -> (SensorValues, Option<u8>, u64) {
+        (
+            self.values.to_sensor_values(),
+            self.data_format,
+            self.received_at_unix_ms,
+        )
+    }
+}
+
+/// Holds back each tag's values until they are complete or the window since the tag's first
+/// advertisement closes
+#[derive(Debug)]
+pub struct Merger {
+    window: Duration,
+    pending: MacLru<Pending>,
+}
+
+impl Merger {
+    pub fn new(window: Duration, max_tracked_macs: usize) -> Merger {
+        Merger {
+            window,
+            pending: MacLru::new(max_tracked_macs),
+        }
+    }
+
+    /// Merges the advertisement into its tag's pending values and returns them once complete.
+    /// Advertisements without a MAC address can't be attributed and are returned as is, as are
+    /// complete ones with nothing pending.
+    pub fn add(
+        &mut self,
+        sv: SensorValues,
+        data_format: Option<u8>,
+        received_at_unix_ms: u64,
+        now: Instant,
+    ) -> Option<(SensorValues, Option<u8>, u64)> {
+        let values = Values::of(&sv);
+        let mac = match values.mac {
+            Some(mac) => mac,
+            None => return Some((sv, data_format, received_at_unix_ms)),
+        };
+        match self.pending.get_mut(&mac) {
+            Some(pending) => {
+                pending.values.update(values);
+                pending.data_format = data_format;
+                pending.received_at_unix_ms = received_at_unix_ms;
+                if !pending.values.is_complete() {
+                    return None;
+                }
+                let pending = self.pending.remove(&mac).expect("just looked up");
+                Some(pending.merged())
+            }
+            None if values.is_complete() => Some((sv, data_format, received_at_unix_ms)),
+            None => {
+                let pending = Pending {
+                    values,
+                    data_format,
+                    received_at_unix_ms,
+                    deadline: now + self.window,
+                };
+                if let Some((evicted, _)) = self.pending.insert(mac, pending) {
+                    debug!(
+                        "Dropping the values pending for {}, too many tags",
+                        mac::format(&evicted)
+                    );
+                }
+                None
+            }
+        }
+    }
+
+    /// When the earliest pending window closes
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.iter().map(|(_, p)| p.deadline).min()
+    }
+
+    /// Returns whatever was gathered for the tags whose window has closed by `now`
+    pub fn expire(&mut self, now: Instant) -> Vec<(SensorValues, Option<u8>, u64)> {
+        let closed: Vec<[u8; 6]> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(mac, _)| *mac)
+            .collect();
+        closed
+            .into_iter()
+            .filter_map(|mac| self.pending.remove(&mac))
+            .map(Pending::merged)
+            .collect()
+    }
+}
@@ -0,0 +1,633 @@
+//! Process wide counters and their Prometheus / OpenMetrics text exposition.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use log::info;
+use ruuvi_sensor_protocol::{
+    BatteryPotential, Humidity, ParseError, Pressure, SensorValues, Temperature,
+};
+use tokio::time::{interval, Duration};
+
+use crate::lru::MacLru;
+use crate::reading::{unix_millis, Reading};
+use crate::{mac, pause};
+
+pub struct Metrics {
+    pub advertisements: AtomicU64,
+    pub readings: AtomicU64,
+    /// By error kind, indexed like `PARSE_ERROR_KINDS`
+    pub parse_errors: [AtomicU64; 4],
+    pub invalid_readings: AtomicU64,
+    /// Advertisements dropped by --verify-checksum
+    pub checksum_failures: AtomicU64,
+    /// Readings dropped by --dedup-identical
+    pub identical_dropped: AtomicU64,
+    /// Distinct MAC addresses seen since startup
+    pub tags_seen: AtomicU64,
+    pub connected_clients: AtomicI64,
+    /// 1 while the Bluetooth scan is running
+    pub scan_active: AtomicI64,
+    pub scan_restarts: AtomicU64,
+    /// Times the Bluetooth scan failed or ended
+    pub scan_failures: AtomicU64,
+    /// Unix time in milliseconds of the latest Bluetooth event, 0 before the first
+    pub last_event_unix_ms: AtomicU64,
+    /// Seconds between consecutive readings of a tag, by MAC
+    pub interarrival: Mutex<BTreeMap<[u8; 6], Histogram>>,
+    /// By adapter label
+    pub adapters: Mutex<BTreeMap<String, AdapterStats>>,
+    /// Readings clients missed, by the address of the connected client
+    pub client_dropped: Mutex<BTreeMap<String, u64>>,
+    /// Readings all clients missed, including those that have disconnected since
+    pub client_dropped_total: AtomicU64,
+    /// Readings a consumer of the reading broadcast fell too far behind to receive
+    pub broadcast_skipped: AtomicU64,
+    /// Readings dropped by --overflow-policy drop-newest or block-brief
+    pub overflow_dropped: AtomicU64,
+}
+
+#[derive(Clone, Debug)]
+pub struct AdapterStats {
+    pub advertisements: u64,
+    /// The latest advertisement, or when scanning started if there was none yet
+    pub last_event: Instant,
+}
+
+pub static METRICS: Metrics = Metrics {
+    advertisements: AtomicU64::new(0),
+    readings: AtomicU64::new(0),
+    parse_errors: [
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+    ],
+    invalid_readings: AtomicU64::new(0),
+    checksum_failures: AtomicU64::new(0),
+    identical_dropped: AtomicU64::new(0),
+    tags_seen: AtomicU64::new(0),
+    connected_clients: AtomicI64::new(0),
+    scan_active: AtomicI64::new(0),
+    scan_restarts: AtomicU64::new(0),
+    scan_failures: AtomicU64::new(0),
+    last_event_unix_ms: AtomicU64::new(0),
+    interarrival: Mutex::new(BTreeMap::new()),
+    adapters: Mutex::new(BTreeMap::new()),
+    client_dropped: Mutex::new(BTreeMap::new()),
+    client_dropped_total: AtomicU64::new(0),
+    broadcast_skipped: AtomicU64::new(0),
+    overflow_dropped: AtomicU64::new(0),
+};
+
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Stable label values of the `ParseError` variants
+pub const PARSE_ERROR_KINDS: [&str; 4] = [
+    "unknown_manufacturer_id",
+    "unsupported_format_version",
+    "invalid_value_length",
+    "empty_value",
+];
+
+fn parse_error_index(e: &ParseError) -> usize {
+    match e {
+        ParseError::UnknownManufacturerId(_) => 0,
+        ParseError::UnsupportedFormatVersion(_) => 1,
+        ParseError::InvalidValueLength(_, _, _) => 2,
+        ParseError::EmptyValue => 3,
+    }
+}
+
+pub fn parse_error_kind(e: &ParseError) -> &'static str {
+    PARSE_ERROR_KINDS[parse_error_index(e)]
+}
+
+pub fn inc_parse_error(e: &ParseError) {
+    inc(&METRICS.parse_errors[parse_error_index(e)]);
+}
+
+impl AdapterStats {
+    fn new() -> AdapterStats {
+        AdapterStats {
+            advertisements: 0,
+            last_event: Instant::now(),
+        }
+    }
+}
+
+/// Starts tracking an adapter, so one that never receives anything still shows up
+pub fn register_adapter(label: &str) {
+    METRICS
+        .adapters
+        .lock()
+        .unwrap()
+        .entry(label.to_string())
+        .or_insert_with(AdapterStats::new);
+}
+
+pub fn observe_adapter(label: &str) {
+    let mut adapters = METRICS.adapters.lock().unwrap();
+    let stats = adapters
+        .entry(label.to_string())
+        .or_insert_with(AdapterStats::new);
+    stats.advertisements += 1;
+    stats.last_event = Instant::now();
+}
+
+pub fn observe_event() {
+    METRICS
+        .last_event_unix_ms
+        .store(unix_millis(), Ordering::Relaxed);
+}
+
+/// One line of the main counters, for eyeballing the bridge's health in the log
+fn summary() -> String {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let last_event = match load(&METRICS.last_event_unix_ms) {
+        0 => "no events yet".to_string(),
+        at => format!(
+            "last event {:.1}s ago",
+            unix_millis().saturating_sub(at) as f64 / 1000.0
+        ),
+    };
+    let scan = if METRICS.scan_active.load(Ordering::Relaxed) == 1 {
+        "active"
+    } else if pause::paused() {
+        "paused"
+    } else {
+        "stopped"
+    };
+    format!(
+        "{} tags, {} readings, {} clients, {}, scan {} ({} restarts, {} failures)",
+        load(&METRICS.tags_seen),
+        load(&METRICS.readings),
+        METRICS.connected_clients.load(Ordering::Relaxed),
+        last_event,
+        scan,
+        load(&METRICS.scan_restarts),
+        load(&METRICS.scan_failures),
+    )
+}
+
+/// Logs the summary line every `period`
+pub async fn log_summary(period: Duration) {
+    let mut ticks = interval(period);
+    loop {
+        ticks.tick().await;
+        info!("Summary: {}", summary());
+    }
+}
+
+/// Marks the scan as active for as long as the guard lives, however the scan loop ends
+pub struct ScanGuard;
+
+impl ScanGuard {
+    pub fn new() -> ScanGuard {
+        METRICS.scan_active.store(1, Ordering::Relaxed);
+        ScanGuard
+    }
+}
+
+impl Drop for ScanGuard {
+    fn drop(&mut self) {
+        METRICS.scan_active.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Keeps a client's dropped readings in the metrics for as long as the guard lives
+pub struct DroppedGuard {
+    peer: String,
+}
+
+impl DroppedGuard {
+    pub fn new(peer: &str) -> DroppedGuard {
+        METRICS
+            .client_dropped
+            .lock()
+            .unwrap()
+            .insert(peer.to_string(), 0);
+        DroppedGuard {
+            peer: peer.to_string(),
+        }
+    }
+}
+
+impl Drop for DroppedGuard {
+    fn drop(&mut self) {
+        METRICS.client_dropped.lock().unwrap().remove(&self.peer);
+    }
+}
+
+/// Counts a data socket client as connected for as long as the guard lives
+pub struct ClientGuard {
+    _dropped: DroppedGuard,
+}
+
+impl ClientGuard {
+    pub fn new(peer: &str) -> ClientGuard {
+        METRICS.connected_clients.fetch_add(1, Ordering::Relaxed);
+        ClientGuard {
+            _dropped: DroppedGuard::new(peer),
+        }
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        METRICS.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn inc_broadcast_skipped(n: u64) {
+    METRICS.broadcast_skipped.fetch_add(n, Ordering::Relaxed);
+}
+
+pub fn inc_client_dropped(peer: &str, n: u64) {
+    METRICS.client_dropped_total.fetch_add(n, Ordering::Relaxed);
+    if let Some(dropped) = METRICS.client_dropped.lock().unwrap().get_mut(peer) {
+        *dropped += n;
+    }
+}
+
+/// Upper bounds of histogram buckets, e.g. `0.5,1,2,5`; `+Inf` is implied
+#[derive(Clone, Debug, PartialEq)]
+pub struct Buckets(pub Vec<f64>);
+
+impl FromStr for Buckets {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bounds = s
+            .split(',')
+            .map(|b| {
+                b.trim()
+                    .parse::<f64>()
+                    .map_err(|_| format!("invalid bucket bound {:?}", b))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if bounds.windows(2).any(|w| w[0] >= w[1]) {
+            return Err("bucket bounds must be strictly increasing".to_string());
+        }
+        Ok(Buckets(bounds))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    /// Observations per bucket, not cumulative; the last one is the +Inf bucket
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(buckets: &Buckets) -> Histogram {
+        Histogram {
+            bounds: buckets.0.clone(),
+            counts: vec![0; buckets.0.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Prometheus text format 0.0.4
+    Prometheus,
+    /// OpenMetrics 1.0 text format
+    OpenMetrics,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prometheus" => Ok(Format::Prometheus),
+            "openmetrics" => Ok(Format::OpenMetrics),
+            _ => Err(format!("unknown metrics format {:?}", s)),
+        }
+    }
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+/// Writes metric families in either format. The two differ mainly in counter naming: OpenMetrics
+/// names the family without the `_total` suffix that its samples carry, and has UNIT and EOF.
+struct Exposition {
+    format: Format,
+    out: String,
+    family: &'static str,
+    kind: Kind,
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Exposition {
+    fn new(format: Format) -> Exposition {
+        Exposition {
+            format,
+            out: String::new(),
+            family: "",
+            kind: Kind::Gauge,
+        }
+    }
+
+    /// Starts a family; with a unit, `name` must end in `_<unit>` as OpenMetrics requires
+    fn family(&mut self, name: &'static str, kind: Kind, unit: Option<&str>, help: &str) {
+        self.family = name;
+        self.kind = kind;
+        let (type_name, described) = match (self.format, kind) {
+            (Format::Prometheus, Kind::Counter) => ("counter", format!("{}_total", name)),
+            (_, Kind::Counter) => ("counter", name.to_string()),
+            (_, Kind::Gauge) => ("gauge", name.to_string()),
+            (_, Kind::Histogram) => ("histogram", name.to_string()),
+        };
+        let _ = writeln!(self.out, "# HELP {} {}", described, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", described, type_name);
+        if let (Format::OpenMetrics, Some(unit)) = (self.format, unit) {
+            let _ = writeln!(self.out, "# UNIT {} {}", described, unit);
+        }
+    }
+
+    fn write_sample(&mut self, suffix: &str, labels: &[(&str, String)], value: impl Display) {
+        let _ = write!(self.out, "{}{}", self.family, suffix);
+        if !labels.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+                .collect::<Vec<_>>()
+                .join(",");
+            let _ = write!(self.out, "{{{}}}", labels);
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    fn sample(&mut self, labels: &[(&str, String)], value: impl Display) {
+        let suffix = match self.kind {
+            Kind::Counter => "_total",
+            Kind::Gauge | Kind::Histogram => "",
+        };
+        self.write_sample(suffix, labels, value);
+    }
+
+    fn histogram(&mut self, labels: &[(&str, String)], histogram: &Histogram) {
+        let mut cumulative = 0;
+        let bounds = histogram.bounds.iter().map(|b| b.to_string());
+        for (bound, count) in bounds
+            .chain(std::iter::once("+Inf".to_string()))
+            .zip(&histogram.counts)
+        {
+            cumulative += count;
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", bound));
+            self.write_sample("_bucket", &bucket_labels, cumulative);
+        }
+        self.write_sample("_sum", labels, histogram.sum);
+        self.write_sample("_count", labels, histogram.count);
+    }
+
+    fn finish(mut self) -> String {
+        if self.format == Format::OpenMetrics {
+            self.out.push_str("# EOF\n");
+        }
+        self.out
+    }
+}
+
+/// Latest value of each tag, labeled by MAC
+struct TagGauge {
+    name: &'static str,
+    unit: &'static str,
+    help: &'static str,
+    value: fn(&SensorValues) -> Option<f64>,
+}
+
+const TAG_GAUGES: &[TagGauge] = &[
+    TagGauge {
+        name: "ruuvi_temperature_celsius",
+        unit: "celsius",
+        help: "Latest temperature",
+        value: |sv| {
+            sv.temperature_as_millicelsius()
+                .map(|t| f64::from(t) / 1000.0)
+        },
+    },
+    TagGauge {
+        name: "ruuvi_humidity_percent",
+        unit: "percent",
+        help: "Latest relative humidity",
+        value: |sv| sv.humidity_as_ppm().map(|h| f64::from(h) / 10_000.0),
+    },
+    TagGauge {
+        name: "ruuvi_pressure_pascals",
+        unit: "pascals",
+        help: "Latest air pressure",
+        value: |sv| sv.pressure_as_pascals().map(f64::from),
+    },
+    TagGauge {
+        name: "ruuvi_battery_volts",
+        unit: "volts",
+        help: "Latest battery potential",
+        value: |sv| {
+            sv.battery_potential_as_millivolts()
+                .map(|v| f64::from(v) / 1000.0)
+        },
+    },
+];
+
+pub fn render(format: Format, last_values: &MacLru<Reading>) -> String {
+    let mut e = Exposition::new(format);
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+    e.family(
+        "ruuvi_advertisements",
+        Kind::Counter,
+        None,
+        "Ruuvi manufacturer and service data advertisements received, by adapter",
+    );
+    let adapters = METRICS.adapters.lock().unwrap().clone();
+    for (adapter, stats) in &adapters {
+        e.sample(&[("adapter", adapter.clone())], stats.advertisements);
+    }
+    e.family(
+        "ruuvi_adapter_last_event_age_seconds",
+        Kind::Gauge,
+        Some("seconds"),
+        "Time since the adapter last received an advertisement",
+    );
+    for (adapter, stats) in &adapters {
+        e.sample(
+            &[("adapter", adapter.clone())],
+            stats.last_event.elapsed().as_secs_f64(),
+        );
+    }
+    e.family(
+        "ruuvi_readings",
+        Kind::Counter,
+        None,
+        "Advertisements successfully parsed into readings",
+    );
+    e.sample(&[], load(&METRICS.readings));
+    e.family(
+        "ruuvi_parse_errors",
+        Kind::Counter,
+        None,
+        "Manufacturer data that failed to parse, by error kind",
+    );
+    for (kind, counter) in PARSE_ERROR_KINDS.iter().zip(&METRICS.parse_errors) {
+        e.sample(&[("kind", kind.to_string())], load(counter));
+    }
+    e.family(
+        "ruuvi_invalid_readings",
+        Kind::Counter,
+        None,
+        "Readings outside the RuuviTag's physical ranges",
+    );
+    e.sample(&[], load(&METRICS.invalid_readings));
+    e.family(
+        "ruuvi_checksum_failures",
+        Kind::Counter,
+        None,
+        "Advertisements dropped because their checksum didn't match",
+    );
+    e.sample(&[], load(&METRICS.checksum_failures));
+    e.family(
+        "ruuvi_identical_dropped",
+        Kind::Counter,
+        None,
+        "Readings dropped for repeating their tag's previous reading exactly",
+    );
+    e.sample(&[], load(&METRICS.identical_dropped));
+    e.family(
+        "ruuvi_tags_seen",
+        Kind::Counter,
+        None,
+        "Distinct tags seen since startup",
+    );
+    e.sample(&[], load(&METRICS.tags_seen));
+    e.family(
+        "ruuvi_connected_clients",
+        Kind::Gauge,
+        None,
+        "Clients connected to the data socket",
+    );
+    e.sample(&[], METRICS.connected_clients.load(Ordering::Relaxed));
+    e.family(
+        "ruuvi_broadcast_skipped",
+        Kind::Counter,
+        None,
+        "Readings skipped by a client, sink or cache that fell behind the reading broadcast",
+    );
+    e.sample(&[], load(&METRICS.broadcast_skipped));
+    e.family(
+        "ruuvi_overflow_dropped",
+        Kind::Counter,
+        None,
+        "Newest readings dropped because the reading broadcast was full",
+    );
+    e.sample(&[], load(&METRICS.overflow_dropped));
+    e.family(
+        "ruuvi_client_dropped",
+        Kind::Counter,
+        None,
+        "Readings a client missed because it fell behind, by connected client",
+    );
+    let client_dropped = METRICS.client_dropped.lock().unwrap().clone();
+    for (peer, dropped) in client_dropped {
+        e.sample(&[("peer", peer)], dropped);
+    }
+    e.family(
+        "ruuvi_scan_active",
+        Kind::Gauge,
+        None,
+        "Whether the Bluetooth scan is running",
+    );
+    e.sample(&[], METRICS.scan_active.load(Ordering::Relaxed));
+    e.family(
+        "ruuvi_scan_restarts",
+        Kind::Counter,
+        None,
+        "Scans restarted after no Bluetooth events arrived for --no-event-restart-secs",
+    );
+    e.sample(&[], load(&METRICS.scan_restarts));
+    e.family(
+        "ruuvi_scan_failures",
+        Kind::Counter,
+        None,
+        "Times the Bluetooth scan failed or ended",
+    );
+    e.sample(&[], load(&METRICS.scan_failures));
+    let last_event_unix_ms = load(&METRICS.last_event_unix_ms);
+    e.family(
+        "ruuvi_last_event_timestamp_seconds",
+        Kind::Gauge,
+        Some("seconds"),
+        "Unix time of the latest Bluetooth event",
+    );
+    if last_event_unix_ms > 0 {
+        e.sample(&[], last_event_unix_ms as f64 / 1000.0);
+    }
+
+    let mut tags: Vec<_> = last_values.iter().collect();
+    tags.sort_by_key(|(mac, _)| **mac);
+    for gauge in TAG_GAUGES {
+        e.family(gauge.name, Kind::Gauge, Some(gauge.unit), gauge.help);
+        for (mac, reading) in &tags {
+            if let Some(v) = (gauge.value)(&reading.values) {
+                e.sample(&[("mac", mac::format(mac))], v);
+            }
+        }
+    }
+
+    e.family(
+        "ruuvi_advertisement_interarrival_seconds",
+        Kind::Histogram,
+        Some("seconds"),
+        "Time between consecutive readings of a tag",
+    );
+    for (mac, histogram) in METRICS.interarrival.lock().unwrap().iter() {
+        e.histogram(&[("mac", mac::format(mac))], histogram);
+    }
+
+    e.finish()
+}
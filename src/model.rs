@@ -0,0 +1,163 @@
+//! Heuristic guesses of the sensor model and firmware behind an advertisement. Tags announce
+//! neither, so these only go by the data format and which values the tag leaves out.
+
+use log::info;
+
+use ruuvi_sensor_protocol::{Humidity, MacAddress, Pressure, SensorValues, Temperature};
+
+use crate::lru::MacLru;
+use crate::mac;
+
+const UNKNOWN: &str = "unknown";
+
+/// Guesses the model from the data format byte, the first byte of the manufacturer data, and
+/// the values present. RuuviTag Pro variants lacking a sensor send data format 5 with that value
+/// marked invalid; a full set of values could be a plain RuuviTag or a Pro 4in1.
+pub fn guess(data_format: Option<u8>, sv: &SensorValues) -> &'static str {
+    match data_format {
+        Some(2) | Some(4) => "RuuviTag URL",
+        Some(3) => "RuuviTag 3",
+        Some(5) => match (
+            sv.temperature_as_millicelsius(),
+            sv.humidity_as_ppm(),
+            sv.pressure_as_pascals(),
+        ) {
+            (Some(_), Some(_), Some(_)) => "RuuviTag 5",
+            (Some(_), Some(_), None) => "RuuviTag Pro 3in1",
+            (Some(_), None, None) => "RuuviTag Pro 2in1",
+            _ => UNKNOWN,
+        },
+        _ => UNKNOWN,
+    }
+}
+
+/// Guesses the firmware generation. Firmware 1.x and 2.x broadcast data format 3 by default and
+/// 3.x data format 5; the Pro variants only ever shipped with 3.x. The URL formats 2 and 4 went
+/// away with 3.x. Anything else, or a tag
+/// configured away from its default format, is beyond telling.
+pub fn firmware_guess(data_format: Option<u8>, sv: &SensorValues) -> &'static str {
+    match (data_format, guess(data_format, sv)) {
+        (Some(2), _) | (Some(3), _) | (Some(4), _) => "1.x or 2.x",
+        (Some(5), "RuuviTag Pro 3in1") | (Some(5), "RuuviTag Pro 2in1") => "3.x",
+        (Some(5), "RuuviTag 5") => "2.x or 3.x",
+        _ => UNKNOWN,
+    }
+}
+
+/// Remembers the guess per tag so it is only logged when it is new or changes
+#[derive(Debug)]
+pub struct Detector {
+    guesses: MacLru<&'static str>,
+}
+
+impl Detector {
+    pub fn new(max_tracked_macs: usize) -> Detector {
+        Detector {
+            guesses: MacLru::new(max_tracked_macs),
+        }
+    }
+
+    pub fn guess(&mut self, data_format: Option<u8>, sv: &SensorValues) -> &'static str {
+        let model = guess(data_format, sv);
+        if let Some(mac) = sv.mac_address() {
+            if self.guesses.get(&mac) != Some(&model) {
+                info!("Tag {} looks like a {}", mac::format(&mac), model);
+            }
+            self.guesses.insert(mac, model);
+        }
+        model
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::merge;
+
+    /// The valid data format 5 example of the protocol documentation
+    const DATA_FORMAT_5: [u8; 24] = [
+        0x05, 0x12, 0xFC, 0x53, 0x94, 0xC3, 0x7C, 0x00, 0x04, 0xFF, 0xFC, 0x04, 0x0C, 0xAC, 0x36,
+        0x42, 0x00, 0xCD, 0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F,
+    ];
+    /// The valid data format 3 example of the protocol documentation
+    const DATA_FORMAT_3: [u8; 14] = [
+        0x03, 0x29, 0x1A, 0x1E, 0xCE, 0x1E, 0xFC, 0x18, 0xF9, 0x42, 0x02, 0xCA, 0x0B, 0x53,
+    ];
+
+    fn parse(payload: &[u8]) -> SensorValues {
+        SensorValues::from_manufacturer_specific_data(0x0499, payload).unwrap()
+    }
+
+    /// Data format 5 with humidity and, unless `with_pressure`, pressure marked invalid
+    fn data_format_5_without_humidity(with_pressure: bool) -> SensorValues {
+        let mut payload = DATA_FORMAT_5;
+        payload[3..5].copy_from_slice(&[0xFF, 0xFF]);
+        if !with_pressure {
+            payload[5..7].copy_from_slice(&[0xFF, 0xFF]);
+        }
+        parse(&payload)
+    }
+
+    /// Data format 5 with only humidity marked invalid
+    fn data_format_5_without_pressure() -> SensorValues {
+        let mut payload = DATA_FORMAT_5;
+        payload[5..7].copy_from_slice(&[0xFF, 0xFF]);
+        parse(&payload)
+    }
+
+    #[test]
+    fn guesses_from_data_format_5_values() {
+        assert_eq!(guess(Some(5), &parse(&DATA_FORMAT_5)), "RuuviTag 5");
+        assert_eq!(
+            guess(Some(5), &data_format_5_without_pressure()),
+            "RuuviTag Pro 3in1"
+        );
+        assert_eq!(
+            guess(Some(5), &data_format_5_without_humidity(false)),
+            "RuuviTag Pro 2in1"
+        );
+        assert_eq!(
+            guess(Some(5), &data_format_5_without_humidity(true)),
+            UNKNOWN
+        );
+    }
+
+    #[test]
+    fn guesses_from_older_data_formats() {
+        assert_eq!(guess(Some(3), &parse(&DATA_FORMAT_3)), "RuuviTag 3");
+        let url = merge::environmental(297_150, 300_000, 99_500);
+        assert_eq!(guess(Some(2), &url), "RuuviTag URL");
+        assert_eq!(guess(Some(4), &url), "RuuviTag URL");
+        assert_eq!(guess(None, &parse(&DATA_FORMAT_5)), UNKNOWN);
+        assert_eq!(guess(Some(6), &parse(&DATA_FORMAT_5)), UNKNOWN);
+    }
+
+    #[test]
+    fn guesses_firmware_from_model() {
+        let url = merge::environmental(297_150, 300_000, 99_500);
+        assert_eq!(firmware_guess(Some(2), &url), "1.x or 2.x");
+        assert_eq!(firmware_guess(Some(4), &url), "1.x or 2.x");
+        assert_eq!(
+            firmware_guess(Some(3), &parse(&DATA_FORMAT_3)),
+            "1.x or 2.x"
+        );
+        assert_eq!(
+            firmware_guess(Some(5), &parse(&DATA_FORMAT_5)),
+            "2.x or 3.x"
+        );
+        assert_eq!(
+            firmware_guess(Some(5), &data_format_5_without_pressure()),
+            "3.x"
+        );
+        assert_eq!(
+            firmware_guess(Some(5), &data_format_5_without_humidity(false)),
+            "3.x"
+        );
+        assert_eq!(
+            firmware_guess(Some(5), &data_format_5_without_humidity(true)),
+            UNKNOWN
+        );
+        assert_eq!(firmware_guess(None, &parse(&DATA_FORMAT_5)), UNKNOWN);
+    }
+}
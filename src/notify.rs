@@ -0,0 +1,55 @@
+//! systemd `Type=notify` integration, see sd_notify(3).
+
+use std::sync::atomic::Ordering;
+
+use log::{debug, info, warn};
+use sd_notify::NotifyState;
+use tokio::time::{sleep, Duration};
+
+use crate::{metrics, pause};
+
+const READY_POLL: Duration = Duration::from_millis(100);
+
+fn advertisements() -> u64 {
+    metrics::METRICS.advertisements.load(Ordering::Relaxed)
+}
+
+fn notify(state: NotifyState) {
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Tells systemd the bridge is up once the scan has received its first advertisement; call it
+/// after the data socket is listening. If the watchdog is enabled, it is then pinged only while
+/// advertisements keep coming in, or the scan is paused with SCAN PAUSE, so a wedged scan gets
+/// the bridge restarted.
+pub async fn run() {
+    while advertisements() == 0 {
+        sleep(READY_POLL).await;
+    }
+    notify(NotifyState::Ready);
+    info!("Notified systemd of readiness");
+
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    // Twice per timeout, as sd_watchdog_enabled(3) recommends
+    let period = Duration::from_micros(usec) / 2;
+    info!("Pinging the systemd watchdog every {:?}", period);
+    let mut seen = advertisements();
+    loop {
+        sleep(period).await;
+        let now = advertisements();
+        if now != seen || pause::paused() {
+            notify(NotifyState::Watchdog);
+        } else {
+            debug!(
+                "No advertisements in {:?}, not pinging the watchdog",
+                period
+            );
+        }
+        seen = now;
+    }
+}
@@ -0,0 +1,61 @@
+//! OpenTelemetry traces of each reading's way through the bridge, exported over OTLP. A
+//! `reading` span covers the processing after an advertisement is parsed, and a `socket_write`
+//! child span each write of the reading to a data socket client.
+
+use std::error::Error;
+
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+use ruuvi_sensor_protocol::{MacAddress, SensorValues};
+
+use crate::mac;
+use crate::reading::Reading;
+
+/// Installs a batching OTLP exporter to `endpoint` as the receiver of all spans
+pub fn init(endpoint: &str) -> Result<(), Box<dyn Error>> {
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                env!("CARGO_PKG_NAME"),
+            )])),
+        )
+        .install_batch(runtime::Tokio)?;
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber)?;
+    Ok(())
+}
+
+/// Exports the spans still buffered
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+pub fn reading_span(sv: &SensorValues) -> Span {
+    let mac = sv.mac_address().map(|m| mac::format(&m));
+    tracing::info_span!("reading", mac = mac.as_deref().unwrap_or("unknown"))
+}
+
+/// The trace context readings carry so later spans can join the reading's trace
+pub fn context(span: &Span) -> Context {
+    span.context()
+}
+
+/// Covers a write of the reading to a client until dropped, recording the pipeline latency
+pub fn write_span(reading: &Reading) -> Span {
+    let latency_ms = reading.parsed_at.elapsed().as_secs_f64() * 1000.0;
+    let span = tracing::info_span!("socket_write", pipeline_latency_ms = latency_ms);
+    span.set_parent(reading.trace_context.clone());
+    span
+}
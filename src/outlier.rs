@@ -0,0 +1,103 @@
+//! Rejection of readings that are physically implausible or jump implausibly far.
+
+use std::str::FromStr;
+
+use ruuvi_sensor_protocol::SensorValues;
+
+use crate::fields;
+
+/// After this many consecutive jump rejections the new level is taken as real, e.g. the tag was
+/// carried outside, and becomes the baseline
+const MAX_CONSECUTIVE_JUMPS: u32 = 3;
+
+/// An inclusive range written as `min..max`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl FromStr for Range {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected a range like -60..85, got {:?}", s);
+        let (min, max) = s.split_once("..").ok_or_else(invalid)?;
+        let range = Range {
+            min: min.trim().parse().map_err(|_| invalid())?,
+            max: max.trim().parse().map_err(|_| invalid())?,
+        };
+        if range.min > range.max {
+            return Err(invalid());
+        }
+        Ok(range)
+    }
+}
+
+impl Range {
+    pub fn contains(&self, value: f64) -> bool {
+        self.min <= value && value <= self.max
+    }
+}
+
+/// Bounds and the largest plausible change between consecutive readings of one metric
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    /// Metric name from [`crate::fields`]
+    pub metric: &'static str,
+    pub range: Range,
+    pub max_jump: f64,
+}
+
+/// Last accepted value of each limited metric of a tag
+#[derive(Debug, Default)]
+pub struct Baseline {
+    values: Vec<Option<f64>>,
+    consecutive_jumps: u32,
+}
+
+/// Checks the absolute bounds, returning a description of the offending value
+pub fn check_bounds(limits: &[Limit], sv: &SensorValues) -> Result<(), String> {
+    for limit in limits {
+        if let Some(value) = fields::value(sv, limit.metric) {
+            if !limit.range.contains(value) {
+                return Err(format!(
+                    "{} {} outside {}..{}",
+                    limit.metric, value, limit.range.min, limit.range.max
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Baseline {
+    /// Checks for jumps from the last accepted reading and, if there are none, makes this the
+    /// new baseline
+    pub fn check_jumps(&mut self, limits: &[Limit], sv: &SensorValues) -> Result<(), String> {
+        self.values.resize(limits.len(), None);
+        let current: Vec<_> = limits.iter().map(|l| fields::value(sv, l.metric)).collect();
+
+        if self.consecutive_jumps < MAX_CONSECUTIVE_JUMPS {
+            for ((limit, previous), value) in limits.iter().zip(&self.values).zip(&current) {
+                if let (Some(previous), Some(value)) = (previous, value) {
+                    if (value - previous).abs() > limit.max_jump {
+                        self.consecutive_jumps += 1;
+                        return Err(format!(
+                            "{} jumped from {} to {}",
+                            limit.metric, previous, value
+                        ));
+                    }
+                }
+            }
+        }
+
+        self.consecutive_jumps = 0;
+        for (stored, value) in self.values.iter_mut().zip(current) {
+            if value.is_some() {
+                *stored = value;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1024 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde_json::{json, Map, Value};
+
+use ruuvi_sensor_protocol::{
+    Acceleration, AccelerationVector, BatteryPotential, Humidity, MacAddress,
+    MeasurementSequenceNumber, MovementCounter, Pressure, SensorValues, Temperature,
+    TransmitterPower,
+};
+
+use crate::reading::Reading;
+use crate::server::OnLag;
+use crate::template::Template;
+use crate::{barometry, battery, comfort, control, mac, thermo, timestamp};
+
+/// Keys of the values decoded from the advertisement, present in every reading
+const BASE_FIELDS: &[&str] = &[
+    "acceleration_vector_as_milli_g",
+    "battery_potential_as_millivolts",
+    "humidity_as_ppm",
+    "mac_address",
+    "measurement_sequence_number",
+    "movement_counter",
+    "pressure_as_pascals",
+    "temperature_as_millikelvins",
+    "temperature_as_millicelsius",
+    "tx_power_as_dbm",
+];
+
+/// Keys of the fields the bridge derives, present as their options say
+const DERIVED_FIELDS: &[&str] = &[
+    "dew_point_as_millicelsius",
+    "dew_point_as_millifahrenheit",
+    "heat_index_millicelsius",
+    "pressure_as_inhg",
+    "humidity_percent",
+    "vapor_pressure_deficit_kpa",
+    "altitude_m",
+    "sea_level_pressure_pa",
+    "temperature_ema_millicelsius",
+    "humidity_ema_ppm",
+    "temperature_filtered_millicelsius",
+    "temperature_rate_mc_per_min",
+    "pressure_rate_pa_per_min",
+    "pipeline_latency_ms",
+    "suspect",
+    "model_guess",
+    "firmware_guess",
+    "battery_status",
+    "comfort",
+    "first_seen_unix_ms",
+    "seen_count",
+    "since_previous_ms",
+    "bridge_seq",
+    "estimated_interval_ms",
+    "received_at",
+];
+
+/// Keys of the JSON object emitted per reading
+pub const FIELDS: [&str; BASE_FIELDS.len() + DERIVED_FIELDS.len()] = all_fields();
+
+const fn all_fields<const N: usize>() -> [&'static str; N] {
+    let mut fields = [""; N];
+    let mut i = 0;
+    while i < BASE_FIELDS.len() {
+        fields[i] = BASE_FIELDS[i];
+        i += 1;
+    }
+    while i < N {
+        fields[i] = DERIVED_FIELDS[i - BASE_FIELDS.len()];
+        i += 1;
+    }
+    fields
+}
+
+/// Version of the layout of the reading objects, announced with --send-header; bumped when a
+/// key changes meaning or goes away
+pub const SCHEMA_VERSION: u32 = 1;
+
+const PASCALS_PER_INHG: f64 = 3386.389;
+
+/// Encoding of what is written to a data socket client. The text formats other than JSON only
+/// apply to readings; control lines stay JSON lines in them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// One JSON object per line
+    Json,
+    /// Self-describing CBOR items, each preceded by its length as a 4-byte big-endian integer
+    Cbor,
+    /// Comma-separated values, after a header line naming the columns
+    Csv,
+    /// InfluxDB line protocol, with the MAC address as a tag
+    Influx,
+}
+
+impl Format {
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Cbor => "cbor",
+            Format::Csv => "csv",
+            Format::Influx => "influx",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "cbor" => Ok(Format::Cbor),
+            "csv" => Ok(Format::Csv),
+            "influx" => Ok(Format::Influx),
+            _ => Err(format!(
+                "unknown format {:?}, expected json, cbor, csv or influx",
+                s
+            )),
+        }
+    }
+}
+
+/// Compression of the whole data socket stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    /// A gzip stream, flushed after every frame so clients can decompress as it goes
+    Gzip,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Compression::Gzip),
+            _ => Err(format!("unknown compression {:?}, expected gzip", s)),
+        }
+    }
+}
+
+/// Bytes ending a frame, like `\r\n`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delimiter(pub Vec<u8>);
+
+impl Default for Delimiter {
+    fn default() -> Self {
+        Delimiter(b"\r\n".to_vec())
+    }
+}
+
+impl FromStr for Delimiter {
+    type Err = String;
+
+    /// `lf`, `crlf`, `nul`, a byte in hex like `0x1e`, or the string itself
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = match s {
+            "lf" => b"\n".to_vec(),
+            "crlf" => b"\r\n".to_vec(),
+            "nul" => vec![0],
+            _ => match s.strip_prefix("0x") {
+                Some(hex) => vec![u8::from_str_radix(hex, 16)
+                    .map_err(|_| format!("expected a byte like 0x1e, got {:?}", s))?],
+                None => s.as_bytes().to_vec(),
+            },
+        };
+        // Compact JSON escapes every control character, so one of them can't occur in a frame
+        if !bytes.iter().any(|b| *b < 0x20) {
+            return Err(format!(
+                "delimiter {:?} could occur in JSON, it needs a control character like lf or nul",
+                s
+            ));
+        }
+        Ok(Delimiter(bytes))
+    }
+}
+
+/// How frames on the data socket are told apart
+#[derive(Clone, Debug, PartialEq)]
+pub enum Framing {
+    /// Every frame ends with the delimiter, except CBOR items which are always length-prefixed
+    Delimited(Delimiter),
+    /// Every frame is preceded by its length in bytes as a 4-byte big-endian integer
+    LengthPrefixed,
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing::Delimited(Delimiter::default())
+    }
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newline" => Ok(Framing::default()),
+            "length-prefixed" => Ok(Framing::LengthPrefixed),
+            _ => Err(format!(
+                "unknown framing {:?}, expected newline or length-prefixed",
+                s
+            )),
+        }
+    }
+}
+
+/// Wraps a serialized payload into one complete frame, ready to be written to the socket
+pub fn frame(framing: &Framing, mut payload: Vec<u8>) -> Vec<u8> {
+    match framing {
+        Framing::Delimited(delimiter) => {
+            payload.extend_from_slice(&delimiter.0);
+            payload
+        }
+        Framing::LengthPrefixed => {
+            let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+            frame.extend_from_slice(&payload);
+            frame
+        }
+    }
+}
+
+/// Encodes `value` as one complete frame, ready to be written to the socket
+pub fn encode(format: Format, framing: &Framing, value: &Value) -> Vec<u8> {
+    match format {
+        Format::Json | Format::Csv | Format::Influx => {
+            frame(framing, value.to_string().into_bytes())
+        }
+        Format::Cbor => {
+            let mut item = Vec::new();
+            ciborium::into_writer(value, &mut item).expect("JSON values are valid CBOR");
+            // CBOR items can't be split on newlines
+            frame(&Framing::LengthPrefixed, item)
+        }
+    }
+}
+
+/// A line preceded by a channel token and a space, if there is one
+pub fn with_token(token: Option<&str>, line: String) -> String {
+    match token {
+        Some(token) => format!("{} {}", token, line),
+        None => line,
+    }
+}
+
+/// Encodes the JSON object of a reading as one complete frame, preceded by `token` in the
+/// text formats. Empty for a line protocol reading without a single value. Line protocol is
+/// timestamped with `received_at_unix_ms`, when the bridge received the advertisement.
+pub fn encode_reading(
+    format: Format,
+    object: Map<String, Value>,
+    received_at_unix_ms: u64,
+    options: &Options,
+    token: Option<&str>,
+) -> Vec<u8> {
+    let line = match format {
+        Format::Cbor => return encode(format, &options.framing, &Value::Object(object)),
+        Format::Json => Value::Object(object).to_string(),
+        Format::Csv => csv_row(&columns(object, options)),
+        Format::Influx => {
+            match influx_line(&columns(object, options), received_at_unix_ms, options) {
+                Some(line) => line,
+                None => return Vec::new(),
+            }
+        }
+    };
+    frame(&options.framing, with_token(token, line).into_bytes())
+}
+
+/// The CSV header line for the columns of a reading's JSON object
+pub fn csv_header(object: &Map<String, Value>, options: &Options) -> String {
+    columns(object.clone(), options)
+        .into_iter()
+        .map(|(name, _)| csv_escape(&name))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Flattens the JSON object into scalar columns: the MAC address as `AA:BB:CC:DD:EE:FF` unless
+/// --mac-format already made it a string, and vectors as one column per axis
+fn columns(object: Map<String, Value>, options: &Options) -> Vec<(String, Value)> {
+    let mac_key = options.key("mac_address");
+    let mut columns = Vec::new();
+    for (name, value) in object {
+        match value {
+            Value::Array(bytes) if name == mac_key => {
+                let mac = bytes
+                    .iter()
+                    .map(|b| b.as_u64().unwrap_or(0) as u8)
+                    .collect::<Vec<_>>();
+                let mac = <[u8; 6]>::try_from(mac.as_slice())
+                    .map_or(Value::Null, |mac| Value::String(mac::format(&mac)));
+                columns.push((name, mac));
+            }
+            Value::Array(items) => {
+                for (axis, item) in ["x", "y", "z"].iter().zip(items) {
+                    columns.push((format!("{}_{}", name, axis), item));
+                }
+            }
+            value => columns.push((name, value)),
+        }
+    }
+    columns
+}
+
+fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn csv_row(columns: &[(String, Value)]) -> String {
+    columns
+        .iter()
+        .map(|(_, value)| match value {
+            Value::Null => String::new(),
+            Value::String(s) => csv_escape(s),
+            value => csv_escape(&value.to_string()),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn influx_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// A line like `ruuvi,mac_address=AA:BB:CC:DD:EE:FF humidity_as_ppm=450000i,... <ns>`, or
+/// `None` without any field values
+fn influx_line(
+    columns: &[(String, Value)],
+    received_at_unix_ms: u64,
+    options: &Options,
+) -> Option<String> {
+    let mac_key = options.key("mac_address");
+    let mut line = "ruuvi".to_string();
+    let mut fields = Vec::new();
+    for (name, value) in columns {
+        match value {
+            Value::Null => {}
+            Value::String(mac) if name == mac_key => {
+                line.push_str(&format!(",{}={}", influx_escape(name), influx_escape(mac)));
+            }
+            Value::String(s) => fields.push(format!(
+                "{}=\"{}\"",
+                influx_escape(name),
+                s.replace('\\', "\\\\").replace('"', "\\\"")
+            )),
+            Value::Number(n) if n.is_f64() => fields.push(format!("{}={}", influx_escape(name), n)),
+            Value::Number(n) => fields.push(format!("{}={}i", influx_escape(name), n)),
+            value => fields.push(format!("{}={}", influx_escape(name), value)),
+        }
+    }
+    if fields.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{} {} {}",
+        line,
+        fields.join(","),
+        u128::from(received_at_unix_ms) * 1_000_000
+    ))
+}
+
+/// Output keys replacing the default field names, written as `name=key,name=key`
+#[derive(Clone, Debug, Default)]
+pub struct FieldMap(HashMap<String, String>);
+
+impl FromStr for FieldMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = HashMap::new();
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (name, key) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=key, got {:?}", pair))?;
+            let (name, key) = (name.trim(), key.trim());
+            if !FIELDS.contains(&name) {
+                return Err(format!(
+                    "unknown field {:?}, expected one of {}",
+                    name,
+                    FIELDS.join(", ")
+                ));
+            }
+            if key.is_empty() {
+                return Err(format!("empty key for {:?}", name));
+            }
+            if map.insert(name.to_string(), key.to_string()).is_some() {
+                return Err(format!("{:?} is renamed more than once", name));
+            }
+        }
+        let field_map = FieldMap(map);
+        field_map.check_keys()?;
+        Ok(field_map)
+    }
+}
+
+impl FieldMap {
+    /// Maps the fields not renamed explicitly to their names in `case`. Fails if that writes two
+    /// fields under the same key.
+    pub fn with_case(mut self, case: KeyCase) -> Result<FieldMap, String> {
+        if case == KeyCase::Snake {
+            return Ok(self);
+        }
+        for name in FIELDS {
+            self.0
+                .entry(name.to_string())
+                .or_insert_with(|| camel_case(name));
+        }
+        self.check_keys()?;
+        Ok(self)
+    }
+
+    /// Fails if two fields would be written under the same key: both renamed to it, or one
+    /// renamed to the key another keeps
+    fn check_keys(&self) -> Result<(), String> {
+        let mut names_by_key = HashMap::new();
+        for name in FIELDS {
+            let key = self.0.get(name).map_or(name, String::as_str);
+            if let Some(other) = names_by_key.insert(key, name) {
+                return Err(format!(
+                    "{:?} and {:?} would both be written as {:?}",
+                    other, name, key
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How the field names, which are snake_case, are written as keys
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyCase {
+    Snake,
+    Camel,
+}
+
+impl FromStr for KeyCase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snake" => Ok(KeyCase::Snake),
+            "camel" => Ok(KeyCase::Camel),
+            _ => Err(format!("expected snake or camel, got {:?}", s)),
+        }
+    }
+}
+
+/// `temperature_as_millicelsius` as `temperatureAsMillicelsius`
+fn camel_case(name: &str) -> String {
+    let mut words = name.split('_');
+    let mut camel = words.next().unwrap_or_default().to_string();
+    for word in words {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            camel.extend(first.to_uppercase());
+            camel.push_str(chars.as_str());
+        }
+    }
+    camel
+}
+
+/// Which optional fields are included in the output, and how
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub format: Format,
+    pub compress: Option<Compression>,
+    pub framing: Framing,
+    /// Announce the keys of the readings to every data socket client when it connects
+    pub send_header: bool,
+    pub on_lag: OnLag,
+    /// Lag events within `slow_client_window` after which a data socket client is disconnected
+    pub slow_client_threshold: Option<usize>,
+    pub slow_client_window: Duration,
+    /// Token data socket clients have to send with AUTH first; `None` lets everyone in
+    pub auth_token: Option<control::Token>,
+    /// Token SCAN commands have to carry; `None` refuses them all
+    pub admin_token: Option<control::Token>,
+    pub dew_point: bool,
+    pub pressure_inhg: bool,
+    pub humidity_percent: bool,
+    pub vapor_pressure_deficit: bool,
+    /// Add `altitude_m`, unless `station_altitude_m` is known
+    pub altitude: bool,
+    pub ema: bool,
+    pub lowpass: bool,
+    pub change_rates: bool,
+    pub heat_index: bool,
+    pub model_guess: bool,
+    pub firmware_guess: bool,
+    pub battery_status: bool,
+    pub low_battery_mv: u16,
+    /// Bands of the comfort field; `None` leaves it out
+    pub comfort: Option<comfort::Thresholds>,
+    pub first_seen: bool,
+    pub seen_count: bool,
+    pub since_previous: bool,
+    pub bridge_seq: bool,
+    pub estimated_interval: bool,
+    pub received_at: bool,
+    pub timestamp_format: timestamp::Format,
+    pub timestamp_zone: timestamp::Zone,
+    /// Add the time from parsing to sending on the data socket
+    pub pipeline_latency: bool,
+    /// Decimals kept in fractional fields like `pressure_as_inhg` and `humidity_percent`
+    pub float_precision: u8,
+    /// Reference pressure for `altitude_m`
+    pub sea_level_pa: f64,
+    /// Known altitude of the station; replaces `altitude_m` with `sea_level_pressure_pa`
+    pub station_altitude_m: Option<f64>,
+    /// Data socket clients are not sent readings parsed longer ago than this
+    pub max_message_age: Option<Duration>,
+    /// Data socket clients get one object per window with the newest reading of each tag
+    pub batch_by_mac: Option<Duration>,
+    /// Replaces the JSON object on the data socket with a line in this format
+    pub template: Option<Template>,
+    /// Stands in for template placeholders without a value
+    pub template_missing: String,
+    pub field_map: FieldMap,
+    pub mac_format: mac::MacFormat,
+    /// Bound of the per-tag state of each connection
+    pub max_tracked_macs: usize,
+}
+
+impl Options {
+    /// Rounds to the configured precision so the JSON doesn't carry float noise
+    pub fn float(&self, value: f64) -> Value {
+        let scale = 10f64.powi(i32::from(self.float_precision));
+        json!((value * scale).round() / scale)
+    }
+
+    /// The output key of a field, which is its name unless remapped
+    pub fn key<'a>(&'a self, name: &'a str) -> &'a str {
+        self.field_map.0.get(name).map_or(name, String::as_str)
+    }
+}
+
+/// Keys of the objects `to_json` emits with these options, sorted like them. `suspect` is left
+/// out as it only appears on flagged readings.
+pub fn keys(options: &Options) -> Vec<String> {
+    let mut names = BASE_FIELDS.to_vec();
+    let optional = [
+        (options.dew_point, "dew_point_as_millicelsius"),
+        (options.dew_point, "dew_point_as_millifahrenheit"),
+        (options.pressure_inhg, "pressure_as_inhg"),
+        (options.humidity_percent, "humidity_percent"),
+        (options.vapor_pressure_deficit, "vapor_pressure_deficit_kpa"),
+        (
+            options.altitude && options.station_altitude_m.is_none(),
+            "altitude_m",
+        ),
+        (
+            options.station_altitude_m.is_some(),
+            "sea_level_pressure_pa",
+        ),
+        (options.ema, "temperature_ema_millicelsius"),
+        (options.ema, "humidity_ema_ppm"),
+        (options.lowpass, "temperature_filtered_millicelsius"),
+        (options.change_rates, "temperature_rate_mc_per_min"),
+        (options.change_rates, "pressure_rate_pa_per_min"),
+        (options.heat_index, "heat_index_millicelsius"),
+        (options.first_seen, "first_seen_unix_ms"),
+        (options.seen_count, "seen_count"),
+        (options.since_previous, "since_previous_ms"),
+        (options.bridge_seq, "bridge_seq"),
+        (options.estimated_interval, "estimated_interval_ms"),
+        (options.model_guess, "model_guess"),
+        (options.firmware_guess, "firmware_guess"),
+        (options.battery_status, "battery_status"),
+        (options.comfort.is_some(), "comfort"),
+        (options.pipeline_latency, "pipeline_latency_ms"),
+        (options.received_at, "received_at"),
+    ];
+    names.extend(optional.iter().filter(|(on, _)| *on).map(|(_, name)| *name));
+    let mut keys = names
+        .into_iter()
+        .map(|name| options.key(name).to_string())
+        .collect::<Vec<_>>();
+    keys.sort();
+    keys
+}
+
+pub fn to_json(reading: &Reading, options: &Options) -> Map<String, Value> {
+    let mut object = sensor_values_json(&reading.values, options.mac_format);
+
+    if options.dew_point {
+        let dew_point = dew_point_celsius(&reading.values);
+        object.insert(
+            "dew_point_as_millicelsius".to_string(),
+            json!(dew_point.map(|t| (t * 1000.0).round() as i32)),
+        );
+        object.insert(
+            "dew_point_as_millifahrenheit".to_string(),
+            json!(dew_point.map(|t| (thermo::celsius_to_fahrenheit(t) * 1000.0).round() as i32)),
+        );
+    }
+    if options.pressure_inhg {
+        object.insert(
+            "pressure_as_inhg".to_string(),
+            reading
+                .values
+                .pressure_as_pascals()
+                .map_or(Value::Null, |p| {
+                    options.float(f64::from(p) / PASCALS_PER_INHG)
+                }),
+        );
+    }
+    if options.humidity_percent {
+        object.insert(
+            "humidity_percent".to_string(),
+            reading
+                .values
+                .humidity_as_ppm()
+                .map_or(Value::Null, |h| options.float(f64::from(h) / 10_000.0)),
+        );
+    }
+    if options.vapor_pressure_deficit {
+        object.insert(
+            "vapor_pressure_deficit_kpa".to_string(),
+            vapor_pressure_deficit_kpa(&reading.values).map_or(Value::Null, |v| options.float(v)),
+        );
+    }
+    let pressure = reading.values.pressure_as_pascals().map(f64::from);
+    match options.station_altitude_m {
+        Some(altitude) => {
+            object.insert(
+                "sea_level_pressure_pa".to_string(),
+                pressure.map_or(Value::Null, |p| {
+                    options.float(barometry::sea_level_pressure_pa(p, altitude))
+                }),
+            );
+        }
+        None if options.altitude => {
+            object.insert(
+                "altitude_m".to_string(),
+                pressure.map_or(Value::Null, |p| {
+                    options.float(barometry::altitude_m(p, options.sea_level_pa))
+                }),
+            );
+        }
+        None => {}
+    }
+    if options.ema {
+        object.insert(
+            "temperature_ema_millicelsius".to_string(),
+            json!(reading.temperature_ema_millicelsius),
+        );
+        object.insert(
+            "humidity_ema_ppm".to_string(),
+            json!(reading.humidity_ema_ppm),
+        );
+    }
+    if options.lowpass {
+        object.insert(
+            "temperature_filtered_millicelsius".to_string(),
+            json!(reading.temperature_filtered_millicelsius),
+        );
+    }
+    if options.change_rates {
+        object.insert(
+            "temperature_rate_mc_per_min".to_string(),
+            reading
+                .temperature_rate_mc_per_min
+                .map_or(Value::Null, |rate| options.float(rate)),
+        );
+        object.insert(
+            "pressure_rate_pa_per_min".to_string(),
+            reading
+                .pressure_rate_pa_per_min
+                .map_or(Value::Null, |rate| options.float(rate)),
+        );
+    }
+    if options.heat_index {
+        object.insert(
+            "heat_index_millicelsius".to_string(),
+            json!(heat_index_celsius(&reading.values).map(|t| (t * 1000.0).round() as i32)),
+        );
+    }
+    if options.first_seen {
+        object.insert(
+            "first_seen_unix_ms".to_string(),
+            json!(reading.first_seen_unix_ms),
+        );
+    }
+    if options.seen_count {
+        object.insert("seen_count".to_string(), json!(reading.seen_count));
+    }
+    if options.since_previous {
+        object.insert(
+            "since_previous_ms".to_string(),
+            json!(reading.since_previous_ms),
+        );
+    }
+    if options.bridge_seq {
+        object.insert("bridge_seq".to_string(), json!(reading.bridge_seq));
+    }
+    if options.estimated_interval {
+        object.insert(
+            "estimated_interval_ms".to_string(),
+            json!(reading.estimated_interval_ms),
+        );
+    }
+    if options.received_at {
+        object.insert(
+            "received_at".to_string(),
+            timestamp::to_json(
+                reading.received_at_unix_ms,
+                options.timestamp_format,
+                options.timestamp_zone,
+            ),
+        );
+    }
+    if options.model_guess {
+        object.insert("model_guess".to_string(), json!(reading.model_guess));
+    }
+    if options.firmware_guess {
+        object.insert("firmware_guess".to_string(), json!(reading.firmware_guess));
+    }
+    if options.battery_status {
+        let millivolts = reading.values.battery_potential_as_millivolts();
+        object.insert(
+            "battery_status".to_string(),
+            json!(battery::status(millivolts, options.low_battery_mv)),
+        );
+    }
+    if let Some(thresholds) = &options.comfort {
+        object.insert(
+            "comfort".to_string(),
+            json!(comfort::classify(
+                reading.values.temperature_as_millicelsius(),
+                reading.values.humidity_as_ppm(),
+                thresholds
+            )),
+        );
+    }
+    if reading.suspect {
+        object.insert("suspect".to_string(), json!(true));
+    }
+    if options.field_map.0.is_empty() {
+        return object;
+    }
+    object
+        .into_iter()
+        .map(|(name, value)| (options.key(&name).to_string(), value))
+        .collect()
+}
+
+fn dew_point_celsius(sv: &SensorValues) -> Option<f64> {
+    let celsius = f64::from(sv.temperature_as_millicelsius()?) / 1000.0;
+    let humidity_pct = f64::from(sv.humidity_as_ppm()?) / 10_000.0;
+    thermo::dew_point_celsius(celsius, humidity_pct)
+}
+
+fn heat_index_celsius(sv: &SensorValues) -> Option<f64> {
+    let celsius = f64::from(sv.temperature_as_millicelsius()?) / 1000.0;
+    let humidity_pct = f64::from(sv.humidity_as_ppm()?) / 10_000.0;
+    Some(thermo::heat_index_celsius(celsius, humidity_pct))
+}
+
+fn vapor_pressure_deficit_kpa(sv: &SensorValues) -> Option<f64> {
+    let celsius = f64::from(sv.temperature_as_millicelsius()?) / 1000.0;
+    let humidity_pct = f64::from(sv.humidity_as_ppm()?) / 10_000.0;
+    Some(thermo::vapor_pressure_deficit_kpa(celsius, humidity_pct))
+}
+
+fn sensor_values_json(sv: &SensorValues, mac_format: mac::MacFormat) -> Map<String, Value> {
+    let mut object = Map::new();
+    object.insert(
+        "acceleration_vector_as_milli_g".to_string(),
+        json!(sv.acceleration_vector_as_milli_g().map(|av| {
+            let AccelerationVector(a, b, c) = av;
+            vec![a, b, c]
+        })),
+    );
+    object.insert(
+        "battery_potential_as_millivolts".to_string(),
+        json!(sv.battery_potential_as_millivolts()),
+    );
+    object.insert("humidity_as_ppm".to_string(), json!(sv.humidity_as_ppm()));
+    object.insert(
+        "mac_address".to_string(),
+        sv.mac_address()
+            .map_or(Value::Null, |mac| mac_format.to_json(&mac)),
+    );
+    object.insert(
+        "measurement_sequence_number".to_string(),
+        json!(sv.measurement_sequence_number()),
+    );
+    object.insert("movement_counter".to_string(), json!(sv.movement_counter()));
+    object.insert(
+        "pressure_as_pascals".to_string(),
+        json!(sv.pressure_as_pascals()),
+    );
+    object.insert(
+        "temperature_as_millikelvins".to_string(),
+        json!(sv.temperature_as_millikelvins()),
+    );
+    object.insert(
+        "temperature_as_millicelsius".to_string(),
+        json!(sv.temperature_as_millicelsius()),
+    );
+    object.insert("tx_power_as_dbm".to_string(), json!(sv.tx_power_as_dbm()));
+    object
+}
+
+/// Keeps only the selected fields; the MAC address is always kept so readings stay attributable
+pub fn project(object: &mut Map<String, Value>, selected: &[String], options: &Options) {
+    let mac_key = options.key("mac_address");
+    object.retain(|key, _| key == mac_key || selected.iter().any(|s| options.key(s) == key));
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    /// The valid data format 5 example of the protocol documentation: 24.3 °C, 53.49 % and
+    /// 100044 Pa
+    const PAYLOAD: [u8; 24] = [
+        0x05, 0x12, 0xFC, 0x53, 0x94, 0xC3, 0x7C, 0x00, 0x04, 0xFF, 0xFC, 0x04, 0x0C, 0xAC, 0x36,
+        0x42, 0x00, 0xCD, 0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F,
+    ];
+
+    /// Every optional field off
+    pub(crate) fn options() -> Options {
+        Options {
+            format: Format::Json,
+            compress: None,
+            framing: Framing::default(),
+            send_header: false,
+            on_lag: OnLag::Skip,
+            slow_client_threshold: None,
+            slow_client_window: Duration::from_secs(60),
+            auth_token: None,
+            admin_token: None,
+            dew_point: false,
+            pressure_inhg: false,
+            humidity_percent: false,
+            vapor_pressure_deficit: false,
+            altitude: false,
+            ema: false,
+            lowpass: false,
+            change_rates: false,
+            heat_index: false,
+            model_guess: false,
+            firmware_guess: false,
+            battery_status: false,
+            low_battery_mv: 2500,
+            comfort: None,
+            first_seen: false,
+            seen_count: false,
+            since_previous: false,
+            bridge_seq: false,
+            estimated_interval: false,
+            received_at: false,
+            timestamp_format: timestamp::Format::EpochMs,
+            timestamp_zone: timestamp::Zone::Utc,
+            pipeline_latency: false,
+            float_precision: 2,
+            sea_level_pa: 101_325.0,
+            station_altitude_m: None,
+            max_message_age: None,
+            batch_by_mac: None,
+            template: None,
+            template_missing: String::new(),
+            field_map: FieldMap::default(),
+            mac_format: mac::MacFormat::ColonUpper,
+            max_tracked_macs: 4096,
+        }
+    }
+
+    /// The data format 5 example reading
+    pub(crate) fn reading() -> Reading {
+        reading_of(PAYLOAD)
+    }
+
+    fn reading_of(payload: [u8; 24]) -> Reading {
+        let sv = SensorValues::from_manufacturer_specific_data(0x0499, payload).unwrap();
+        Reading::new(sv, Instant::now())
+    }
+
+    #[test]
+    fn pressure_as_inhg() {
+        let options = Options {
+            pressure_inhg: true,
+            ..options()
+        };
+        let object = to_json(&reading(), &options);
+        assert_eq!(object["pressure_as_inhg"], json!(29.54));
+        assert!(!to_json(&reading(), &self::options()).contains_key("pressure_as_inhg"));
+    }
+
+    #[test]
+    fn standard_atmosphere_in_inhg() {
+        assert!((101_325.0 / PASCALS_PER_INHG - 29.92).abs() < 0.005);
+    }
+
+    #[test]
+    fn dew_point_in_celsius_and_fahrenheit() {
+        let options = Options {
+            dew_point: true,
+            ..options()
+        };
+        let object = to_json(&reading(), &options);
+        assert_eq!(object["dew_point_as_millicelsius"], json!(14248));
+        assert_eq!(object["dew_point_as_millifahrenheit"], json!(57646));
+    }
+
+    #[test]
+    fn dew_point_left_out_unless_asked_for() {
+        let object = to_json(&reading(), &options());
+        assert!(!object.contains_key("dew_point_as_millicelsius"));
+        assert!(!object.contains_key("dew_point_as_millifahrenheit"));
+    }
+
+    #[test]
+    fn humidity_percent() {
+        let options = Options {
+            humidity_percent: true,
+            ..options()
+        };
+        // 20000 steps of 0.0025 %, i.e. 500000 ppm
+        let mut payload = PAYLOAD;
+        payload[3..5].copy_from_slice(&20_000u16.to_be_bytes());
+        let reading = reading_of(payload);
+        assert_eq!(reading.values.humidity_as_ppm(), Some(500_000));
+        let object = to_json(&reading, &options);
+        assert_eq!(object["humidity_percent"], json!(50.0));
+        assert_eq!(
+            to_json(&self::reading(), &options)["humidity_percent"],
+            json!(53.49)
+        );
+    }
+
+    #[test]
+    fn camel_case_of_field_names() {
+        assert_eq!(
+            camel_case("temperature_as_millicelsius"),
+            "temperatureAsMillicelsius"
+        );
+        assert_eq!(
+            camel_case("acceleration_vector_as_milli_g"),
+            "accelerationVectorAsMilliG"
+        );
+        assert_eq!(camel_case("comfort"), "comfort");
+    }
+
+    #[test]
+    fn camel_case_keys_keep_explicit_renames() {
+        let field_map: FieldMap = "seen_count=count".parse().unwrap();
+        let options = Options {
+            field_map: field_map.with_case(KeyCase::Camel).unwrap(),
+            seen_count: true,
+            ..options()
+        };
+        let object = to_json(&reading(), &options);
+        assert!(object.contains_key("macAddress"));
+        assert!(object.contains_key("temperatureAsMillicelsius"));
+        assert!(object.contains_key("count"));
+        assert!(!object.contains_key("mac_address"));
+    }
+
+    #[test]
+    fn field_map_renames_keys_and_back() {
+        let field_map: FieldMap = "temperature_as_millicelsius=t, mac_address=id"
+            .parse()
+            .unwrap();
+        let options = Options {
+            field_map,
+            ..options()
+        };
+        let renamed = to_json(&reading(), &options);
+        assert_eq!(renamed["t"], json!(24300));
+        assert_eq!(renamed["id"], json!("CB:B8:33:4C:88:4F"));
+        assert!(!renamed.contains_key("temperature_as_millicelsius"));
+
+        let original = to_json(&reading(), &self::options());
+        let restored = renamed
+            .into_iter()
+            .map(|(key, value)| {
+                let name = FIELDS
+                    .iter()
+                    .find(|name| options.key(name) == key)
+                    .expect("every key comes from a field");
+                (name.to_string(), value)
+            })
+            .collect::<Map<_, _>>();
+        assert_eq!(restored, original);
+    }
+
+    #[test]
+    fn field_map_rejects_unknown_fields_and_empty_keys() {
+        assert!("temperature=t".parse::<FieldMap>().is_err());
+        assert!("seen_count=".parse::<FieldMap>().is_err());
+        assert!("seen_count".parse::<FieldMap>().is_err());
+        assert!("".parse::<FieldMap>().is_ok());
+    }
+
+    #[test]
+    fn field_map_rejects_keys_written_twice() {
+        assert!("seen_count=n, seen_count=m".parse::<FieldMap>().is_err());
+        assert!("seen_count=n, first_seen_unix_ms=n"
+            .parse::<FieldMap>()
+            .is_err());
+        assert!("seen_count=mac_address".parse::<FieldMap>().is_err());
+        assert!("seen_count=mac_address, mac_address=id"
+            .parse::<FieldMap>()
+            .is_ok());
+        assert!("seen_count=seen_count".parse::<FieldMap>().is_ok());
+        let field_map: FieldMap = "seen_count=macAddress".parse().unwrap();
+        assert!(field_map.clone().with_case(KeyCase::Snake).is_ok());
+        assert!(field_map.with_case(KeyCase::Camel).is_err());
+    }
+}
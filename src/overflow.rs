@@ -0,0 +1,126 @@
+//! What happens to new readings when the reading broadcast is full, i.e. its slowest consumer
+//! hasn't taken any of the readings it holds. Left alone the broadcast makes room by dropping
+//! the oldest reading, which the slow consumers then skip. The other policies put a bounded
+//! buffer in front of it that is only emptied into the broadcast while it has room.
+
+use std::str::FromStr;
+
+use log::{debug, trace};
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::time::{timeout, Duration};
+
+use crate::metrics;
+use crate::reading::Reading;
+
+/// How long block-brief holds up the scan waiting for room in the buffer
+pub const BLOCK_BRIEF: Duration = Duration::from_millis(100);
+/// How often a full broadcast is checked for room again without a consumer taking a reading,
+/// in case the slowest consumer went away instead
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Signalled whenever a consumer takes a reading off the broadcast, which may make room in it
+static TAKEN: Notify = Notify::const_new();
+
+/// Takes the next reading off a subscription to the reading broadcast. Consumers use it instead
+/// of `recv`, so that readings held back in the buffer are forwarded as soon as there is room.
+pub async fn recv(
+    receiver: &mut broadcast::Receiver<Reading>,
+) -> Result<Reading, broadcast::error::RecvError> {
+    let received = receiver.recv().await;
+    TAKEN.notify_one();
+    received
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drop the reading that doesn't fit
+    DropNewest,
+    /// The broadcast's own behavior
+    DropOldest,
+    /// Hold up the scan for up to `BLOCK_BRIEF`, then drop the reading that doesn't fit
+    BlockBrief,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "block-brief" => Ok(OverflowPolicy::BlockBrief),
+            _ => Err(format!(
+                "expected drop-newest, drop-oldest or block-brief, got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// Where the pipeline sends its readings
+pub enum Front {
+    Direct(broadcast::Sender<Reading>),
+    Buffered {
+        sender: mpsc::Sender<Reading>,
+        policy: OverflowPolicy,
+    },
+}
+
+fn overflowed() {
+    metrics::inc(&metrics::METRICS.overflow_dropped);
+    debug!("Reading broadcast full, dropped the newest reading");
+}
+
+impl Front {
+    /// In front of `tx`, which was created with `capacity`; the buffer holds as many readings
+    pub fn new(policy: OverflowPolicy, tx: broadcast::Sender<Reading>, capacity: usize) -> Front {
+        match policy {
+            OverflowPolicy::DropOldest => Front::Direct(tx),
+            policy => {
+                let (sender, receiver) = mpsc::channel(capacity);
+                tokio::spawn(forward(receiver, tx, capacity));
+                Front::Buffered { sender, policy }
+            }
+        }
+    }
+
+    pub async fn send(&self, reading: Reading) {
+        match self {
+            Front::Direct(tx) => {
+                let recipients = tx.send(reading);
+                trace!("Message was sent to {:?}", recipients)
+            }
+            Front::Buffered {
+                sender,
+                policy: OverflowPolicy::BlockBrief,
+            } => {
+                if let Err(mpsc::error::SendTimeoutError::Timeout(_)) =
+                    sender.send_timeout(reading, BLOCK_BRIEF).await
+                {
+                    overflowed();
+                }
+            }
+            Front::Buffered { sender, .. } => {
+                if let Err(mpsc::error::TrySendError::Full(_)) = sender.try_send(reading) {
+                    overflowed();
+                }
+            }
+        }
+    }
+}
+
+/// Moves readings from the buffer to the broadcast whenever it has room
+async fn forward(
+    mut receiver: mpsc::Receiver<Reading>,
+    tx: broadcast::Sender<Reading>,
+    capacity: usize,
+) {
+    while let Some(reading) = receiver.recv().await {
+        // Sending into a full broadcast would push out the slowest consumer's oldest reading
+        while tx.len() >= capacity {
+            let _ = timeout(RECHECK_INTERVAL, TAKEN.notified()).await;
+        }
+        let recipients = tx.send(reading);
+        trace!("Message was sent to {:?}", recipients)
+    }
+}
@@ -0,0 +1,30 @@
+//! `SCAN PAUSE` and `SCAN RESUME`: stopping the Bluetooth scan for a while without stopping the
+//! bridge, e.g. to leave a shared radio to something else. Clients stay connected and the last
+//! value cache is kept, but no new readings arrive until the scan resumes.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::sync::Notify;
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+/// Wakes the scan loop, which is its only waiter, so a change made while it is busy elsewhere
+/// is still picked up
+static CHANGED: Notify = Notify::const_new();
+
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+/// Pauses or resumes the scan; false if it already was
+pub fn set(paused: bool) -> bool {
+    let changed = PAUSED.swap(paused, Ordering::SeqCst) != paused;
+    if changed {
+        CHANGED.notify_one();
+    }
+    changed
+}
+
+/// Resolves once the scan was paused or resumed
+pub async fn changed() {
+    CHANGED.notified().await
+}
@@ -0,0 +1,203 @@
+//! `--dry-run`: checks that the configuration can work, without binding any socket or starting
+//! the scan. Every problem found is collected, so one run reports them all.
+
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use btleplug::api::{Central, Manager as _};
+use btleplug::platform::Manager;
+use log::info;
+use tokio::net::lookup_host;
+
+use crate::{decrypt, pushgateway, Opt};
+
+/// An existing file has to open for appending, a missing one needs a directory to be created in
+fn check_writable(option: &str, path: &Path, problems: &mut Vec<String>) {
+    if path.is_dir() {
+        problems.push(format!("{} {:?} is a directory", option, path));
+    } else if path.exists() {
+        if let Err(e) = OpenOptions::new().append(true).open(path) {
+            problems.push(format!("{} {:?} is not writable: {}", option, path, e));
+        }
+    } else {
+        check_directory(option, path, problems);
+    }
+}
+
+/// The directory `path` would be created in has to exist and not be read-only
+fn check_directory(option: &str, path: &Path, problems: &mut Vec<String>) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match dir.metadata() {
+        Ok(metadata) if !metadata.is_dir() => problems.push(format!(
+            "{} {:?}: {:?} is not a directory",
+            option, path, dir
+        )),
+        Ok(metadata) if metadata.permissions().readonly() => {
+            problems.push(format!("{} {:?}: {:?} is read-only", option, path, dir))
+        }
+        Ok(_) => {}
+        Err(e) => problems.push(format!("{} {:?}: {:?}: {}", option, path, dir, e)),
+    }
+}
+
+async fn check_resolves(option: &str, address: &str, problems: &mut Vec<String>) {
+    match lookup_host(address).await {
+        Ok(mut addrs) => {
+            if addrs.next().is_none() {
+                problems.push(format!("{} {:?} resolves to no address", option, address))
+            }
+        }
+        Err(e) => problems.push(format!("{} {:?} doesn't resolve: {}", option, address, e)),
+    }
+}
+
+/// The adapters the scan would use
+async fn adapters(all_adapters: bool) -> Result<Vec<String>, Box<dyn Error>> {
+    let manager = Manager::new().await?;
+    let mut adapters = manager.adapters().await?;
+    if !all_adapters {
+        adapters.truncate(1);
+    }
+    let mut infos = Vec::new();
+    for adapter in &adapters {
+        infos.push(adapter.adapter_info().await?);
+    }
+    Ok(infos)
+}
+
+/// The problems that would keep the bridge from starting as configured; empty if there are none
+pub async fn run(opt: &Opt) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let tls = opt.tls_cert.is_some() || opt.tls_key.is_some() || opt.tls_client_ca.is_some();
+    let features = [
+        (
+            "--otlp-endpoint",
+            opt.otlp_endpoint.is_some(),
+            cfg!(feature = "otel"),
+            "otel",
+        ),
+        (
+            "--kafka-brokers",
+            opt.kafka_brokers.is_some(),
+            cfg!(feature = "kafka"),
+            "kafka",
+        ),
+        (
+            "--nats-url",
+            opt.nats_url.is_some(),
+            cfg!(feature = "nats"),
+            "nats",
+        ),
+        (
+            "--redis-url",
+            opt.redis_url.is_some(),
+            cfg!(feature = "redis"),
+            "redis",
+        ),
+        (
+            "--websocket-port",
+            opt.websocket_port.is_some(),
+            cfg!(feature = "websocket"),
+            "websocket",
+        ),
+        ("TLS options", tls, cfg!(feature = "tls"), "tls"),
+    ];
+    for (option, given, built_with, feature) in features {
+        if given && !built_with {
+            problems.push(format!(
+                "{} given, but built without the {} feature",
+                option, feature
+            ));
+        }
+    }
+    if opt.unix_socket.is_some() && !cfg!(unix) {
+        problems.push("--unix-socket given, but Unix sockets need a Unix system".to_string());
+    }
+
+    let ports = [
+        ("--port", Some(i32::from(opt.port))),
+        ("--error-port", opt.error_port.map(i32::from)),
+        ("--http-port", opt.http_port.map(i32::from)),
+        ("--websocket-port", opt.websocket_port.map(i32::from)),
+    ];
+    let host = crate::listen_host(opt);
+    for (option, port) in ports {
+        if let Some(port) = port {
+            let address = format!("{}:{}", host, port);
+            check_resolves(option, &address, &mut problems).await;
+        }
+    }
+    if let Some(brokers) = &opt.kafka_brokers {
+        for broker in brokers.split(',').map(str::trim) {
+            check_resolves("--kafka-brokers", broker, &mut problems).await;
+        }
+    }
+    if let Some(url) = &opt.pushgateway_url {
+        let instance = opt.pushgateway_instance.as_deref();
+        match pushgateway::Target::new(url, &opt.pushgateway_job, instance) {
+            Ok(target) => {
+                check_resolves("--pushgateway-url", target.authority(), &mut problems).await
+            }
+            Err(e) => problems.push(format!("--pushgateway-url: {}", e)),
+        }
+    }
+
+    if let Err(e) = opt.field_map.clone().with_case(opt.key_case) {
+        problems.push(format!("--field-map with --key-case camel: {}", e));
+    }
+
+    if let Some(path) = &opt.decrypt_keys_file {
+        if let Err(e) = decrypt::Keys::load(path) {
+            problems.push(format!("--decrypt-keys-file: {}", e));
+        }
+    }
+    #[cfg(feature = "tls")]
+    match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => {
+            if let Err(e) = crate::tls::acceptor(cert, key, opt.tls_client_ca.as_deref()) {
+                problems.push(format!("TLS: {}", e));
+            }
+        }
+        (None, None) if opt.tls_client_ca.is_none() => {}
+        _ => problems.push(
+            "--tls-cert and --tls-key go together, and --tls-client-ca needs both".to_string(),
+        ),
+    }
+
+    if let Some(path) = &opt.file {
+        if opt.file_rotate.is_some() && opt.file_max_bytes.is_some() {
+            problems.push("--file-rotate and --file-max-bytes cannot be combined".to_string());
+        }
+        // Daily files are named after --file, which itself is never written
+        if opt.file_rotate.is_some() {
+            check_directory("--file", path, &mut problems);
+        } else {
+            check_writable("--file", path, &mut problems);
+        }
+    }
+    if let Some(path) = &opt.snapshot_file {
+        check_writable("--snapshot-file", path, &mut problems);
+    }
+    if let Some(path) = &opt.unix_socket {
+        check_directory("--unix-socket", path, &mut problems);
+    }
+
+    match adapters(opt.all_adapters).await {
+        Ok(adapters) if adapters.is_empty() => {
+            problems.push("no Bluetooth adapters found".to_string())
+        }
+        Ok(adapters) => {
+            for adapter in adapters {
+                info!("Would scan with adapter: {}", adapter);
+            }
+        }
+        Err(e) => problems.push(format!("Bluetooth: {}", e)),
+    }
+
+    problems
+}
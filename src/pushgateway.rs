@@ -0,0 +1,106 @@
+//! Pushing the `/metrics` metric set to a Prometheus Pushgateway, for bridges a scraper can't
+//! reach. Speaks just enough HTTP/1.1 for a POST to a plain `http://` URL.
+
+use log::{trace, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{interval, timeout, Duration};
+
+use crate::cache::LastValues;
+use crate::metrics;
+
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where to push, parsed from a URL like `http://pushgateway:9091`
+#[derive(Clone, Debug)]
+pub struct Target {
+    /// `host:port`, connected to and sent as the Host header
+    authority: String,
+    /// The grouping key path, e.g. `/metrics/job/ruuvi/instance/pi`
+    path: String,
+}
+
+fn encode_path_segment(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl Target {
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    pub fn new(url: &str, job: &str, instance: Option<&str>) -> Result<Target, String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("expected an http:// URL, got {:?}", url))?;
+        let (authority, prefix) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        if authority.is_empty() {
+            return Err(format!("no host in {:?}", url));
+        }
+        // 9091 is the Pushgateway's default port
+        let authority = if authority.contains(':') {
+            authority.to_string()
+        } else {
+            format!("{}:9091", authority)
+        };
+        let mut path = format!("{}/metrics/job/{}", prefix, encode_path_segment(job));
+        if let Some(instance) = instance {
+            path.push_str("/instance/");
+            path.push_str(&encode_path_segment(instance));
+        }
+        Ok(Target { authority, path })
+    }
+}
+
+async fn push(target: &Target, body: &str) -> Result<(), String> {
+    let mut socket = TcpStream::connect(&target.authority)
+        .await
+        .map_err(|e| e.to_string())?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        target.path,
+        target.authority,
+        body.len(),
+        body
+    );
+    socket
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut response = Vec::new();
+    socket
+        .read_to_end(&mut response)
+        .await
+        .map_err(|e| e.to_string())?;
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or("");
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(format!("Pushgateway answered {:?}", status_line)),
+    }
+}
+
+/// Pushes the metrics every `period`, forever. A failed push is only logged; the next one
+/// comes around on schedule.
+pub async fn run(target: Target, cache: LastValues, period: Duration) {
+    let mut ticks = interval(period);
+    loop {
+        ticks.tick().await;
+        let body = metrics::render(metrics::Format::Prometheus, &cache.lock().unwrap());
+        match timeout(PUSH_TIMEOUT, push(&target, &body)).await {
+            Ok(Ok(())) => trace!("Pushed metrics to {}{}", target.authority, target.path),
+            Ok(Err(e)) => warn!("Failed to push metrics: {}", e),
+            Err(_) => warn!("Pushing metrics timed out after {:?}", PUSH_TIMEOUT),
+        }
+    }
+}
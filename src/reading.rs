@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use ruuvi_sensor_protocol::SensorValues;
+
+/// A parsed advertisement together with what the bridge derived from it
+#[derive(Clone, Debug)]
+pub struct Reading {
+    pub values: SensorValues,
+    /// When the advertisement was parsed, for measuring fan-out latency; never serialized
+    pub parsed_at: Instant,
+    pub temperature_ema_millicelsius: Option<i32>,
+    pub humidity_ema_ppm: Option<u32>,
+    pub temperature_filtered_millicelsius: Option<i32>,
+    /// When the advertisement came off the adapter, in milliseconds since the Unix epoch
+    pub received_at_unix_ms: u64,
+    /// Change since the tag's previous reading; `None` for its first and after a reboot
+    pub temperature_rate_mc_per_min: Option<f64>,
+    pub pressure_rate_pa_per_min: Option<f64>,
+    /// Outside the RuuviTag's physical ranges but passed on anyway
+    pub suspect: bool,
+    /// Heuristic guess of the sensor model, see `model::guess`
+    pub model_guess: Option<&'static str>,
+    /// Heuristic guess of the firmware generation, see `model::firmware_guess`
+    pub firmware_guess: Option<&'static str>,
+    /// When the tag was first seen during this run; `None` without a MAC address
+    pub first_seen_unix_ms: Option<u64>,
+    /// Readings of the tag received during this run, this one and dropped ones included
+    pub seen_count: Option<u64>,
+    /// Time since the tag's previous reading; `None` for its first
+    pub since_previous_ms: Option<u64>,
+    /// Position of the reading among all readings the bridge broadcast during this run
+    pub bridge_seq: Option<u64>,
+    /// Average time between the tag's readings; `None` until there are enough of them
+    pub estimated_interval_ms: Option<u64>,
+    /// Trace of the reading's way through the bridge
+    #[cfg(feature = "otel")]
+    pub trace_context: opentelemetry::Context,
+}
+
+impl Reading {
+    pub fn new(values: SensorValues, parsed_at: Instant) -> Reading {
+        Reading {
+            values,
+            parsed_at,
+            temperature_ema_millicelsius: None,
+            humidity_ema_ppm: None,
+            temperature_filtered_millicelsius: None,
+            received_at_unix_ms: unix_millis(),
+            temperature_rate_mc_per_min: None,
+            pressure_rate_pa_per_min: None,
+            suspect: false,
+            model_guess: None,
+            firmware_guess: None,
+            first_seen_unix_ms: None,
+            seen_count: None,
+            since_previous_ms: None,
+            bridge_seq: None,
+            estimated_interval_ms: None,
+            #[cfg(feature = "otel")]
+            trace_context: opentelemetry::Context::new(),
+        }
+    }
+}
+
+static BRIDGE_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// The next bridge_seq, counting from 0 for the first reading broadcast
+pub fn next_bridge_seq() -> u64 {
+    BRIDGE_SEQ.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Wall clock time as milliseconds since the Unix epoch
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
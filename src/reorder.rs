@@ -0,0 +1,160 @@
+//! `--reorder-buffer-ms`: readings held back for a short window and passed on sorted by when
+//! they were received, for writers that expect timestamps in order.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::reading::Reading;
+
+/// Readings held at most, so a burst can't grow the buffer without limit; the earliest received
+/// is passed on early to make room
+pub const MAX_READINGS: usize = 1024;
+
+#[derive(Debug)]
+pub struct Reorder {
+    window: Duration,
+    /// Sorted by `received_at_unix_ms`, readings received at the same time in arrival order,
+    /// each with when its window closes
+    held: VecDeque<(Instant, Reading)>,
+}
+
+impl Reorder {
+    pub fn new(window: Duration) -> Reorder {
+        Reorder {
+            window,
+            held: VecDeque::new(),
+        }
+    }
+
+    /// Holds `reading` back until the window since it was received closes, `now` and
+    /// `now_unix_ms` being the same moment. Returns the readings to pass on right away: the
+    /// earliest held if the buffer is full, and any whose window has already closed.
+    pub fn push(&mut self, reading: Reading, now: Instant, now_unix_ms: u64) -> Vec<Reading> {
+        let age = Duration::from_millis(now_unix_ms.saturating_sub(reading.received_at_unix_ms));
+        let due = now + self.window.saturating_sub(age);
+        let at = self
+            .held
+            .partition_point(|(_, held)| held.received_at_unix_ms <= reading.received_at_unix_ms);
+        self.held.insert(at, (due, reading));
+        let mut passed_on = Vec::new();
+        if self.held.len() > MAX_READINGS {
+            passed_on.extend(self.held.pop_front().map(|(_, reading)| reading));
+        }
+        passed_on.extend(self.expire(now));
+        passed_on
+    }
+
+    /// When the window of the reading held the longest closes
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.held.iter().map(|(due, _)| *due).min()
+    }
+
+    /// Passes on the readings whose window has closed by `now`, along with any received before
+    /// them, in order
+    pub fn expire(&mut self, now: Instant) -> Vec<Reading> {
+        let cutoff = self
+            .held
+            .iter()
+            .filter(|(due, _)| *due <= now)
+            .map(|(_, reading)| reading.received_at_unix_ms)
+            .max();
+        let cutoff = match cutoff {
+            Some(cutoff) => cutoff,
+            None => return Vec::new(),
+        };
+        let due = self
+            .held
+            .partition_point(|(_, held)| held.received_at_unix_ms <= cutoff);
+        self.held.drain(..due).map(|(_, reading)| reading).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ruuvi_sensor_protocol::SensorValues;
+
+    /// The valid data format 5 example of the protocol documentation
+    const PAYLOAD: [u8; 24] = [
+        0x05, 0x12, 0xFC, 0x53, 0x94, 0xC3, 0x7C, 0x00, 0x04, 0xFF, 0xFC, 0x04, 0x0C, 0xAC, 0x36,
+        0x42, 0x00, 0xCD, 0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F,
+    ];
+
+    fn reading(received_at_unix_ms: u64) -> Reading {
+        let sv = SensorValues::from_manufacturer_specific_data(0x0499, PAYLOAD).unwrap();
+        let mut reading = Reading::new(sv, Instant::now());
+        reading.received_at_unix_ms = received_at_unix_ms;
+        reading
+    }
+
+    fn received(readings: Vec<Reading>) -> Vec<u64> {
+        readings.iter().map(|r| r.received_at_unix_ms).collect()
+    }
+
+    #[test]
+    fn passes_readings_on_sorted_once_window_closes() {
+        let start = Instant::now();
+        let window = Duration::from_millis(100);
+        let mut reorder = Reorder::new(window);
+        assert!(reorder.push(reading(1_020), start, 1_020).is_empty());
+        assert!(reorder.push(reading(1_000), start, 1_020).is_empty());
+        assert!(reorder.push(reading(1_010), start, 1_020).is_empty());
+        assert_eq!(
+            reorder.next_deadline(),
+            Some(start + Duration::from_millis(80))
+        );
+        assert!(reorder.expire(start + Duration::from_millis(79)).is_empty());
+        assert_eq!(
+            received(reorder.expire(start + Duration::from_millis(80))),
+            [1_000]
+        );
+        assert_eq!(received(reorder.expire(start + window)), [1_010, 1_020]);
+        assert_eq!(reorder.next_deadline(), None);
+    }
+
+    #[test]
+    fn passes_on_readings_older_than_window_right_away() {
+        let start = Instant::now();
+        let mut reorder = Reorder::new(Duration::from_millis(100));
+        reorder.push(reading(1_000), start, 1_000);
+        assert_eq!(received(reorder.push(reading(900), start, 1_000)), [900]);
+        assert_eq!(
+            received(reorder.push(reading(950), start + Duration::from_millis(100), 1_100)),
+            [950, 1_000]
+        );
+    }
+
+    #[test]
+    fn keeps_arrival_order_of_readings_received_at_same_time() {
+        let start = Instant::now();
+        let mut reorder = Reorder::new(Duration::from_millis(100));
+        let mut first = reading(1_000);
+        first.temperature_ema_millicelsius = Some(1);
+        let mut second = reading(1_000);
+        second.temperature_ema_millicelsius = Some(2);
+        reorder.push(first, start, 1_000);
+        reorder.push(second, start, 1_000);
+        let passed = reorder.expire(start + Duration::from_millis(100));
+        assert_eq!(
+            passed
+                .iter()
+                .map(|r| r.temperature_ema_millicelsius)
+                .collect::<Vec<_>>(),
+            [Some(1), Some(2)]
+        );
+    }
+
+    #[test]
+    fn passes_on_earliest_when_full() {
+        let start = Instant::now();
+        let mut reorder = Reorder::new(Duration::from_secs(1));
+        for received_at in (0..MAX_READINGS as u64).rev() {
+            assert!(reorder
+                .push(reading(received_at + 1), start, 1_000)
+                .is_empty());
+        }
+        assert_eq!(received(reorder.push(reading(0), start, 1_000)), [0]);
+        assert_eq!(received(reorder.push(reading(900), start, 1_000)), [1]);
+    }
+}
@@ -0,0 +1,35 @@
+//! --sample-every: passes on every Nth reading of each tag, for trend logging that doesn't need
+//! every advertisement. Counting per tag thins out tags with different advertising intervals by
+//! the same factor.
+
+use ruuvi_sensor_protocol::{MacAddress, SensorValues};
+
+use crate::lru::MacLru;
+
+#[derive(Debug)]
+pub struct Sampler {
+    every: usize,
+    /// Readings of each tag since the last one passed on
+    counts: MacLru<usize>,
+}
+
+impl Sampler {
+    pub fn new(every: usize, max_tracked_macs: usize) -> Sampler {
+        Sampler {
+            every,
+            counts: MacLru::new(max_tracked_macs),
+        }
+    }
+
+    /// Whether the reading is passed on: the first of a tag is, then every `every`th. Readings
+    /// without a MAC address can't be counted per tag and always are.
+    pub fn sample(&mut self, sv: &SensorValues) -> bool {
+        let mac = match sv.mac_address() {
+            Some(mac) => mac,
+            None => return true,
+        };
+        let count = self.counts.get(&mac).copied().unwrap_or(0);
+        self.counts.insert(mac, (count + 1) % self.every);
+        count == 0
+    }
+}
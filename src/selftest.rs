@@ -0,0 +1,139 @@
+//! `--self-test`: a synthetic reading sent through the fan-out and a real data socket to a
+//! client inside the bridge, which checks the JSON it gets. Needs no Bluetooth.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{interval, timeout, Duration};
+
+use ruuvi_sensor_protocol::SensorValues;
+
+use crate::reading::Reading;
+use crate::{control, fanout, output};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const RESEND_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The valid data format 5 example of the protocol documentation,
+/// https://docs.ruuvi.com/ruuvi-sensor-protocols/dataformat-05
+const PAYLOAD: [u8; 24] = [
+    0x05, 0x12, 0xFC, 0x53, 0x94, 0xC3, 0x7C, 0x00, 0x04, 0xFF, 0xFC, 0x04, 0x0C, 0xAC, 0x36, 0x42,
+    0x00, 0xCD, 0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F,
+];
+
+/// Values the example decodes to, by field name
+fn expected(options: &output::Options) -> [(&'static str, Value); 5] {
+    [
+        (
+            "mac_address",
+            options
+                .mac_format
+                .to_json(&[0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F]),
+        ),
+        ("temperature_as_millicelsius", json!(24300)),
+        ("humidity_as_ppm", json!(534900)),
+        ("pressure_as_pascals", json!(100044)),
+        ("measurement_sequence_number", json!(205)),
+    ]
+}
+
+/// Checks a reading object against the example, under the keys `--field-map` gives the fields
+fn check(object: &Value, options: &output::Options) -> Result<(), String> {
+    for (name, value) in expected(options) {
+        let key = options.key(name);
+        match object.get(key) {
+            Some(actual) if *actual == value => {}
+            Some(actual) => return Err(format!("{} is {}, expected {}", key, actual, value)),
+            None => return Err(format!("{} is missing from {}", key, object)),
+        }
+    }
+    Ok(())
+}
+
+async fn receive(client: TcpStream, options: &output::Options) -> Result<(), String> {
+    let mut lines = BufReader::new(client).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return Err("the bridge closed the connection".to_string()),
+            Err(e) => return Err(format!("reading from the data socket failed: {}", e)),
+        };
+        let object: Value =
+            serde_json::from_str(&line).map_err(|e| format!("invalid JSON {:?}: {}", line, e))?;
+        // The header of --send-header
+        if object.get("event").is_some() {
+            continue;
+        }
+        return check(&object, options);
+    }
+}
+
+/// Runs the test with the output options the bridge was started with, except that it always
+/// speaks newline delimited JSON without compression, templates, batching or authentication
+pub async fn run(options: &output::Options) -> Result<(), String> {
+    let mut options = options.clone();
+    options.format = output::Format::Json;
+    options.compress = None;
+    options.framing = output::Framing::default();
+    options.template = None;
+    options.batch_by_mac = None;
+    options.max_message_age = None;
+    options.auth_token = None;
+    let options = Arc::new(options);
+
+    let sv = SensorValues::from_manufacturer_specific_data(0x0499, PAYLOAD)
+        .map_err(|e| format!("the example payload didn't parse: {:?}", e))?;
+    let (tx, _) = broadcast::channel::<Reading>(4);
+    let fanout = fanout::Fanout::new(4, None);
+    tokio::spawn(fanout::run(fanout.clone(), tx.subscribe()));
+
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .map_err(|e| format!("binding a local data socket failed: {}", e))?;
+    let addr = listener
+        .local_addr()
+        .map_err(|e| format!("no local address: {}", e))?;
+    let (shutdown_tx, _) = broadcast::channel::<()>(1);
+    let (shutdown_complete_tx, _shutdown_complete_rx) = mpsc::channel::<()>(1);
+    let server_options = options.clone();
+    let server_shutdown = shutdown_tx.subscribe();
+    tokio::spawn(async move {
+        if let Ok((socket, peer)) = listener.accept().await {
+            let peer = peer.to_string();
+            let client = fanout.subscribe(&peer);
+            crate::handle_socket(
+                socket,
+                peer,
+                client,
+                server_options,
+                control::DefaultSubscription::All,
+                server_shutdown,
+                shutdown_complete_tx,
+            )
+            .await;
+        }
+    });
+
+    let client = TcpStream::connect(addr)
+        .await
+        .map_err(|e| format!("connecting to the local data socket failed: {}", e))?;
+    // The server side subscribes only once it has accepted, so keep sending until it gets one
+    let sender = tokio::spawn(async move {
+        let mut ticks = interval(RESEND_INTERVAL);
+        loop {
+            ticks.tick().await;
+            let _ = tx.send(Reading::new(sv.clone(), Instant::now()));
+        }
+    });
+    let result = match timeout(TIMEOUT, receive(client, &options)).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("no reading arrived within {:?}", TIMEOUT)),
+    };
+    sender.abort();
+    let _ = shutdown_tx.send(());
+    result
+}
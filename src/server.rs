@@ -0,0 +1,537 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use async_compression::tokio::write::GzipEncoder;
+use log::{debug, error, info, warn};
+use serde_json::json;
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{TcpSocket, UnixListener, UnixStream};
+use tokio::sync::broadcast;
+use tokio::time::{sleep, Duration};
+
+use crate::allow::AllowList;
+use crate::output::{self, Compression, Format, Framing};
+
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Whether binding may succeed later: the address is still held by a previous instance, or, at
+/// boot, the interface is not up yet
+fn bind_may_succeed_later(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::AddrInUse | std::io::ErrorKind::AddrNotAvailable
+    )
+}
+
+fn describe_failures(failures: &[(SocketAddr, std::io::Error)]) -> String {
+    failures
+        .iter()
+        .map(|(candidate, e)| format!("{}: {}", candidate, e))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Binds the first address `addr` resolves to that can be bound, so a hostname with both A and
+/// AAAA records works where only one of the families does. While none can be bound for reasons
+/// that may clear up the whole lot is retried with exponential backoff. The listener gets
+/// SO_REUSEADDR from tokio on Unix, so sockets lingering in TIME_WAIT don't count as busy.
+pub async fn bind_listener(
+    addr: &str,
+    retries: u32,
+    initial_delay: Duration,
+) -> std::io::Result<TcpListener> {
+    let candidates: Vec<SocketAddr> = lookup_host(addr).await?.collect();
+    if candidates.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("{:?} resolves to no address", addr),
+        ));
+    }
+    let mut delay = initial_delay;
+    let mut attempt = 0;
+    loop {
+        let mut failures = Vec::new();
+        for candidate in &candidates {
+            match bind_one(candidate).await {
+                Ok(listener) => {
+                    for (skipped, e) in &failures {
+                        warn!("Skipped {} of {:?}: {}", skipped, addr, e);
+                    }
+                    if candidates.len() > 1 {
+                        info!(
+                            "Bound {} of {:?}, which resolves to {:?}",
+                            candidate, addr, candidates
+                        );
+                    }
+                    return Ok(listener);
+                }
+                Err(e) => failures.push((*candidate, e)),
+            }
+        }
+        if attempt < retries && failures.iter().any(|(_, e)| bind_may_succeed_later(e)) {
+            attempt += 1;
+            warn!(
+                "Failed to bind {:?} ({}), retry {}/{} in {:?}",
+                addr,
+                describe_failures(&failures),
+                attempt,
+                retries,
+                delay
+            );
+            sleep(delay).await;
+            delay *= 2;
+            continue;
+        }
+        if failures.len() == 1 {
+            return Err(failures.remove(0).1);
+        }
+        return Err(std::io::Error::new(
+            failures[0].1.kind(),
+            format!(
+                "none of the addresses of {:?} could be bound: {}",
+                addr,
+                describe_failures(&failures)
+            ),
+        ));
+    }
+}
+
+/// Binds `addr`, the IPv6 wildcard address as a dual-stack socket
+async fn bind_one(addr: &SocketAddr) -> std::io::Result<TcpListener> {
+    #[cfg(unix)]
+    if let SocketAddr::V6(v6) = addr {
+        if v6.ip().is_unspecified() {
+            return bind_dual_stack(*addr);
+        }
+    }
+    TcpListener::bind(addr).await
+}
+
+/// Binds `addr` with IPV6_V6ONLY off, so IPv4 clients can connect too whatever the system
+/// default says (`net.ipv6.bindv6only` on Linux)
+#[cfg(unix)]
+fn bind_dual_stack(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    use std::os::unix::io::AsRawFd;
+
+    let socket = TcpSocket::new_v6()?;
+    socket.set_reuseaddr(true)?;
+    let off: libc::c_int = 0;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            &off as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&off) as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+#[derive(Debug, PartialEq)]
+enum AcceptErrorAction {
+    Continue,
+    Backoff,
+    Fatal,
+}
+
+fn accept_error_action(e: &std::io::Error) -> AcceptErrorAction {
+    use std::io::ErrorKind;
+    match e.kind() {
+        // The peer went away between SYN and accept; nothing wrong with the listener itself
+        ErrorKind::ConnectionAborted
+        | ErrorKind::ConnectionReset
+        | ErrorKind::Interrupted
+        | ErrorKind::WouldBlock => AcceptErrorAction::Continue,
+        ErrorKind::OutOfMemory => AcceptErrorAction::Backoff,
+        _ => match e.raw_os_error() {
+            // Resource exhaustion that may clear up
+            Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOMEM) => {
+                AcceptErrorAction::Backoff
+            }
+            _ => AcceptErrorAction::Fatal,
+        },
+    }
+}
+
+/// Caps the connections open at the same time from any one peer address
+#[derive(Clone, Debug)]
+pub struct PeerLimit {
+    max: usize,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl PeerLimit {
+    pub fn new(max: usize) -> PeerLimit {
+        PeerLimit {
+            max,
+            open: Arc::default(),
+        }
+    }
+
+    /// Counts a connection from `ip` for as long as the guard lives, or `None` if `ip` already
+    /// has the maximum open
+    pub fn acquire(&self, ip: IpAddr) -> Option<PeerGuard> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(PeerGuard {
+            ip,
+            open: self.open.clone(),
+        })
+    }
+}
+
+pub struct PeerGuard {
+    ip: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for PeerGuard {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// What to do when a data socket client falls behind and readings are dropped for it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnLag {
+    /// Log it and go on with the readings that still fit
+    Skip,
+    /// Close the connection
+    Disconnect,
+    /// Panic in the client's task, which kills that connection only; for debugging
+    Panic,
+}
+
+impl FromStr for OnLag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(OnLag::Skip),
+            "disconnect" => Ok(OnLag::Disconnect),
+            "panic" => Ok(OnLag::Panic),
+            _ => Err(format!("expected skip, disconnect or panic, got {:?}", s)),
+        }
+    }
+}
+
+/// Times a data socket client fell too far behind to get every reading, within a sliding window
+#[derive(Debug)]
+pub struct LagTracker {
+    threshold: usize,
+    window: Duration,
+    /// When each lag event in the window happened and how many readings it skipped
+    events: VecDeque<(Instant, u64)>,
+}
+
+impl LagTracker {
+    pub fn new(threshold: usize, window: Duration) -> LagTracker {
+        LagTracker {
+            threshold,
+            window,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Records a lag event that skipped `skipped` readings; true once the threshold is reached
+    pub fn lagged(&mut self, skipped: u64, now: Instant) -> bool {
+        while let Some((at, _)) = self.events.front() {
+            if now.duration_since(*at) < self.window {
+                break;
+            }
+            self.events.pop_front();
+        }
+        self.events.push_back((now, skipped));
+        self.events.len() >= self.threshold
+    }
+}
+
+impl fmt::Display for LagTracker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "lagged {} times within {:?}, skipping {} readings",
+            self.events.len(),
+            self.window,
+            self.events.iter().map(|(_, skipped)| skipped).sum::<u64>()
+        )
+    }
+}
+
+/// Accepts connections until shutdown is signalled or the listener fails fatally, handing each
+/// allowed one to `on_accept` and closing the others right away.
+pub async fn accept_loop<F>(
+    listener: TcpListener,
+    mut shutdown: broadcast::Receiver<()>,
+    allowed: AllowList,
+    mut on_accept: F,
+) -> std::io::Result<()>
+where
+    F: FnMut(TcpStream),
+{
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                match accepted {
+                    Ok((socket, peer)) if allowed.allows(peer.ip()) => on_accept(socket),
+                    Ok((_, peer)) => warn!("Refused connection from {}, not in --allow-ip", peer),
+                    Err(e) => match accept_error_action(&e) {
+                        AcceptErrorAction::Continue => {
+                            warn!("Failed to accept connection: {:?}", e);
+                        }
+                        AcceptErrorAction::Backoff => {
+                            warn!("Failed to accept connection, backing off for {:?}: {:?}", ACCEPT_BACKOFF, e);
+                            sleep(ACCEPT_BACKOFF).await;
+                        }
+                        AcceptErrorAction::Fatal => {
+                            error!("Listener failed, giving up: {:?}", e);
+                            return Err(e);
+                        }
+                    },
+                }
+            }
+            _ = shutdown.recv() => return Ok(()),
+        }
+    }
+}
+
+/// Binds a Unix socket at `path`, replacing a socket file left behind by a previous instance:
+/// one nothing accepts connections on anymore. A socket still in use, or anything else at the
+/// path, is left alone and fails the bind.
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::AddrInUse,
+                        format!("{} is in use by another process", path.display()),
+                    ))
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path)?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    UnixListener::bind(path)
+}
+
+/// Like `accept_loop` for a Unix socket, where peers are told apart by the user id of the
+/// process on the other end (`SO_PEERCRED`) instead of an address. Everyone who may open the
+/// socket file may connect when `allowed_uids` is empty.
+#[cfg(unix)]
+pub async fn accept_unix_loop<F>(
+    listener: UnixListener,
+    mut shutdown: broadcast::Receiver<()>,
+    allowed_uids: Vec<u32>,
+    mut on_accept: F,
+) -> std::io::Result<()>
+where
+    F: FnMut(UnixStream, u32),
+{
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let socket = match accepted {
+                    Ok((socket, _)) => socket,
+                    Err(e) => match accept_error_action(&e) {
+                        AcceptErrorAction::Continue => {
+                            warn!("Failed to accept Unix socket connection: {:?}", e);
+                            continue;
+                        }
+                        AcceptErrorAction::Backoff => {
+                            warn!(
+                                "Failed to accept Unix socket connection, backing off {:?}: {:?}",
+                                ACCEPT_BACKOFF, e
+                            );
+                            sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                        AcceptErrorAction::Fatal => {
+                            error!("Unix socket listener failed, giving up: {:?}", e);
+                            return Err(e);
+                        }
+                    },
+                };
+                let uid = match socket.peer_cred() {
+                    Ok(cred) => cred.uid(),
+                    Err(e) => {
+                        warn!("Refused Unix socket connection, no peer credentials: {:?}", e);
+                        continue;
+                    }
+                };
+                if allowed_uids.is_empty() || allowed_uids.contains(&uid) {
+                    on_accept(socket, uid);
+                } else {
+                    warn!("Refused Unix socket connection from uid {}, not in --allow-uid", uid);
+                }
+            }
+            _ = shutdown.recv() => return Ok(()),
+        }
+    }
+}
+
+/// Wraps the writer of a data socket client in the stream compression, if any. Flushing the
+/// result flushes the compressor too, and shutting it down finishes the compressed stream.
+pub fn compressed<W>(
+    writer: W,
+    compression: Option<Compression>,
+) -> Box<dyn AsyncWrite + Send + Unpin>
+where
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    match compression {
+        None => Box::new(writer),
+        Some(Compression::Gzip) => Box::new(GzipEncoder::new(writer)),
+    }
+}
+
+pub async fn write_frame<W>(socket: &mut W, frame: &[u8]) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    socket.write_all(frame).await?;
+    socket.flush().await
+}
+
+/// Tells the client that the bridge is going away on purpose and closes the socket.
+pub async fn send_shutdown_notice<W>(socket: &mut W, format: Format, framing: &Framing)
+where
+    W: AsyncWrite + Unpin,
+{
+    let notice = output::encode(format, framing, &json!({"event": "shutdown"}));
+    match write_frame(socket, &notice).await {
+        Ok(_) => debug!("Sent shutdown notice"),
+        Err(e) => debug!("Failed to send shutdown notice: {:?}", e),
+    }
+    let _ = socket.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Error, ErrorKind};
+
+    #[test]
+    fn accept_error_action_by_error() {
+        for kind in [
+            ErrorKind::ConnectionAborted,
+            ErrorKind::ConnectionReset,
+            ErrorKind::Interrupted,
+            ErrorKind::WouldBlock,
+        ] {
+            assert_eq!(
+                accept_error_action(&Error::from(kind)),
+                AcceptErrorAction::Continue
+            );
+        }
+        for errno in [libc::EMFILE, libc::ENFILE, libc::ENOMEM] {
+            assert_eq!(
+                accept_error_action(&Error::from_raw_os_error(errno)),
+                AcceptErrorAction::Backoff
+            );
+        }
+        assert_eq!(
+            accept_error_action(&Error::from_raw_os_error(libc::EBADF)),
+            AcceptErrorAction::Fatal
+        );
+        assert_eq!(
+            accept_error_action(&Error::from(ErrorKind::PermissionDenied)),
+            AcceptErrorAction::Fatal
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn binds_ipv6_wildcard_dual_stack() {
+        if std::net::TcpListener::bind("[::]:0").is_err() {
+            return;
+        }
+        let listener = bind_listener("[::]:0", 0, Duration::ZERO).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn bind_unix_replaces_only_stale_sockets() {
+        let path = std::env::temp_dir().join(format!("bind-unix-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = bind_unix(&path).unwrap();
+        assert_eq!(bind_unix(&path).unwrap_err().kind(), ErrorKind::AddrInUse);
+        drop(listener);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn peer_limit_counts_open_connections_per_address() {
+        let limit = PeerLimit::new(2);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let first = limit.acquire(a).unwrap();
+        let _second = limit.acquire(a).unwrap();
+        assert!(limit.acquire(a).is_none());
+        assert!(limit.acquire(b).is_some());
+        drop(first);
+        assert!(limit.acquire(a).is_some());
+    }
+
+    #[test]
+    fn peer_limit_forgets_addresses_without_connections() {
+        let limit = PeerLimit::new(1);
+        let a: IpAddr = "2001:db8::1".parse().unwrap();
+        drop(limit.acquire(a).unwrap());
+        assert!(limit.open.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn lag_tracker_reaches_threshold_within_window() {
+        let mut lag = LagTracker::new(3, Duration::from_secs(10));
+        let start = Instant::now();
+        assert!(!lag.lagged(1, start));
+        assert!(!lag.lagged(2, start + Duration::from_secs(1)));
+        assert!(lag.lagged(3, start + Duration::from_secs(2)));
+        assert_eq!(
+            lag.to_string(),
+            "lagged 3 times within 10s, skipping 6 readings"
+        );
+    }
+
+    #[test]
+    fn lag_tracker_forgets_events_outside_window() {
+        let mut lag = LagTracker::new(2, Duration::from_secs(10));
+        let start = Instant::now();
+        assert!(!lag.lagged(1, start));
+        assert!(!lag.lagged(1, start + Duration::from_secs(10)));
+        assert!(lag.lagged(1, start + Duration::from_secs(15)));
+    }
+}
@@ -0,0 +1,65 @@
+//! RuuviTag readings advertised as service data under a given UUID instead of, or besides,
+//! manufacturer data. The payload is the same as in the manufacturer data, data format first.
+
+use uuid::Uuid;
+
+use ruuvi_sensor_protocol::{MacAddress, MeasurementSequenceNumber, ParseError, SensorValues};
+
+use crate::lru::MacLru;
+
+const RUUVI_MANUFACTURER_ID: u16 = 0x0499;
+
+/// Sensor values of service data under `ruuvi_service`; `None` for other services
+pub fn parse(
+    ruuvi_service: &Uuid,
+    service: &Uuid,
+    data: &[u8],
+) -> Option<Result<SensorValues, ParseError>> {
+    (service == ruuvi_service)
+        .then(|| SensorValues::from_manufacturer_specific_data(RUUVI_MANUFACTURER_ID, data))
+}
+
+/// How an advertisement reached the bridge
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Source {
+    ManufacturerData = 0,
+    ServiceData = 1,
+}
+
+impl Source {
+    fn other(self) -> Source {
+        match self {
+            Source::ManufacturerData => Source::ServiceData,
+            Source::ServiceData => Source::ManufacturerData,
+        }
+    }
+}
+
+/// The newest measurement sequence number of each tag by source, so that a measurement a tag
+/// advertises both ways is only passed on once. Readings without a MAC address or a sequence
+/// number can't be matched up and are never duplicates.
+#[derive(Debug)]
+pub struct Dedup {
+    /// Indexed by `Source`
+    last: MacLru<[Option<u32>; 2]>,
+}
+
+impl Dedup {
+    pub fn new(max_tracked_macs: usize) -> Dedup {
+        Dedup {
+            last: MacLru::new(max_tracked_macs),
+        }
+    }
+
+    /// Whether the other source already brought this measurement
+    pub fn is_duplicate(&mut self, source: Source, sv: &SensorValues) -> bool {
+        let (mac, sequence) = match (sv.mac_address(), sv.measurement_sequence_number()) {
+            (Some(mac), Some(sequence)) => (mac, sequence),
+            _ => return false,
+        };
+        let mut last = self.last.get(&mac).copied().unwrap_or_default();
+        last[source as usize] = Some(sequence);
+        self.last.insert(mac, last);
+        last[source.other() as usize] == Some(sequence)
+    }
+}
@@ -0,0 +1,403 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{Datelike, Local, NaiveDate, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, info, trace, warn};
+use serde_json::Value;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::interval;
+
+use crate::reading::{unix_millis, Reading};
+use crate::{metrics, output, overflow};
+
+/// Time based rotation, writing straight to a file named after the period
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Schedule {
+    /// `<stem>-YYYY-MM-DD.<extension>` next to the given path
+    Daily,
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(Schedule::Daily),
+            _ => Err(format!("expected daily, got {:?}", s)),
+        }
+    }
+}
+
+/// Whose midnight a daily file ends at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DateZone {
+    Utc,
+    Local,
+}
+
+impl FromStr for DateZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utc" => Ok(DateZone::Utc),
+            "local" => Ok(DateZone::Local),
+            _ => Err(format!("expected utc or local, got {:?}", s)),
+        }
+    }
+}
+
+impl DateZone {
+    fn today(self) -> NaiveDate {
+        match self {
+            DateZone::Utc => Utc::now().date_naive(),
+            DateZone::Local => Local::now().date_naive(),
+        }
+    }
+}
+
+/// When to start a new file and what to do with the old ones. Without a schedule a rotated file
+/// is renamed to `<path>.<unix time in milliseconds>`.
+#[derive(Clone, Debug)]
+pub struct Rotation {
+    /// Start a new file before the current one would grow past this size
+    pub max_bytes: Option<u64>,
+    pub schedule: Option<Schedule>,
+    pub timezone: DateZone,
+    /// Rotated files to keep; older ones are deleted
+    pub keep: Option<usize>,
+    /// Gzip rotated files, adding a `.gz` suffix
+    pub compress: bool,
+}
+
+/// Appends readings to a file as JSON lines. Writes go through a buffer, which is written out
+/// whenever it fills up, every flush interval and on shutdown, so a slow disk doesn't see a
+/// write per reading.
+pub struct FileSink {
+    path: PathBuf,
+    /// The day the file being written belongs to, with a daily schedule
+    date: Option<NaiveDate>,
+    writer: BufWriter<File>,
+    buffer_bytes: usize,
+    flush_interval: Duration,
+    /// Make sure every flush has reached the disk before going on
+    fsync: bool,
+    rotation: Rotation,
+    /// Size of the current file, including what is still in the buffer
+    written: u64,
+    /// The file being written, which housekeeping leaves alone; set after every rotation
+    current: watch::Sender<PathBuf>,
+}
+
+async fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+impl FileSink {
+    pub async fn open(
+        path: &Path,
+        buffer_bytes: usize,
+        flush_interval: Duration,
+        fsync: bool,
+        rotation: Rotation,
+    ) -> io::Result<FileSink> {
+        let date = rotation.schedule.map(|_| rotation.timezone.today());
+        let current_path = match date {
+            Some(date) => dated_path(path, date),
+            None => path.to_path_buf(),
+        };
+        let file = open_append(&current_path).await?;
+        let written = file.metadata().await?.len();
+        if date.is_some() {
+            link_current(path, &current_path).await;
+        }
+        // The first pass compresses and prunes whatever an earlier run left behind
+        let (current, current_rx) = watch::channel(current_path);
+        tokio::spawn(housekeep(path.to_path_buf(), rotation.clone(), current_rx));
+        Ok(FileSink {
+            path: path.to_path_buf(),
+            date,
+            writer: BufWriter::with_capacity(buffer_bytes, file),
+            buffer_bytes,
+            flush_interval,
+            fsync,
+            rotation,
+            written,
+            current,
+        })
+    }
+
+    /// Writes readings from the broadcast until the channel closes or shutdown is requested
+    pub async fn run(
+        mut self,
+        mut receiver: broadcast::Receiver<Reading>,
+        options: Arc<output::Options>,
+        mut shutdown: broadcast::Receiver<()>,
+        _shutdown_complete: mpsc::Sender<()>,
+    ) {
+        let mut flush = interval(self.flush_interval);
+        loop {
+            tokio::select! {
+                reading = overflow::recv(&mut receiver) => match reading {
+                    Ok(reading) => {
+                        let object = output::to_json(&reading, &options);
+                        let mut line = Value::Object(object).to_string();
+                        line.push('\n');
+                        self.write(line.as_bytes()).await;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("File sink lagged, skipped {} readings", n);
+                        metrics::inc_broadcast_skipped(n);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = flush.tick() => self.flush().await,
+                _ = shutdown.recv() => break,
+            }
+        }
+        self.flush().await;
+        info!("File sink flushed and closed");
+    }
+
+    async fn write(&mut self, line: &[u8]) {
+        // The date is taken as the line is written, so a line goes to the file of the day it is
+        // written on even when it is the first one after midnight
+        if let Some(date) = self.date {
+            let today = self.rotation.timezone.today();
+            if today != date {
+                if let Err(e) = self.start_day(today).await {
+                    warn!("Failed to start the file for {}: {}", today, e);
+                }
+            }
+        }
+        let len = line.len() as u64;
+        let full = match self.rotation.max_bytes {
+            Some(max) => self.written > 0 && self.written + len > max,
+            None => false,
+        };
+        if full {
+            if let Err(e) = self.rotate().await {
+                warn!("Failed to rotate {:?}: {}", self.path, e);
+                // Try again once another file's worth has been written instead of on every line
+                self.written = 0;
+            }
+        }
+        match self.writer.write_all(line).await {
+            Ok(()) => self.written += len,
+            Err(e) => warn!("Failed to write reading to file: {}", e),
+        }
+    }
+
+    async fn rotate(&mut self) -> io::Result<()> {
+        self.write_out().await?;
+        let rotated = segment_path(&self.path, unix_millis());
+        tokio::fs::rename(&self.path, &rotated).await?;
+        let file = open_append(&self.path).await?;
+        self.writer = BufWriter::with_capacity(self.buffer_bytes, file);
+        self.written = 0;
+        info!("Rotated {:?} to {:?}", self.path, rotated);
+        self.current.send_replace(self.path.clone());
+        Ok(())
+    }
+
+    async fn start_day(&mut self, date: NaiveDate) -> io::Result<()> {
+        self.write_out().await?;
+        let path = dated_path(&self.path, date);
+        let file = open_append(&path).await?;
+        self.written = file.metadata().await?.len();
+        self.writer = BufWriter::with_capacity(self.buffer_bytes, file);
+        self.date = Some(date);
+        info!("Writing readings to {:?}", path);
+        link_current(&self.path, &path).await;
+        self.current.send_replace(path);
+        Ok(())
+    }
+
+    /// Writes the buffer to the file, and with fsync on waits until the file is on the disk
+    async fn write_out(&mut self) -> io::Result<()> {
+        self.writer.flush().await?;
+        if self.fsync {
+            self.writer.get_ref().sync_all().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) {
+        match self.write_out().await {
+            Ok(()) => trace!("Flushed file sink"),
+            Err(e) => warn!("Failed to flush file sink: {}", e),
+        }
+    }
+}
+
+/// `<stem>-YYYY-MM-DD.<extension>` in the directory of `path`
+fn dated_path(path: &Path, date: NaiveDate) -> PathBuf {
+    let (stem, extension) = split_name(path);
+    path.with_file_name(format!("{}-{}{}", stem, date.format("%Y-%m-%d"), extension))
+}
+
+/// Points `<stem>-current.<extension>` at `target`, for readers that want a path that stays the
+/// same across days. The link is made under a temporary name and renamed over the old one, so
+/// it never goes missing.
+#[cfg(unix)]
+async fn link_current(path: &Path, target: &Path) {
+    let (stem, extension) = split_name(path);
+    let link = path.with_file_name(format!("{}-current{}", stem, extension));
+    let mut temporary = link.as_os_str().to_os_string();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    // Relative, as both are in the same directory
+    let target = target.file_name().unwrap_or_default();
+    // A temporary link left behind by a crash would fail the symlink call
+    let _ = tokio::fs::remove_file(&temporary).await;
+    let result = match tokio::fs::symlink(target, &temporary).await {
+        Ok(()) => tokio::fs::rename(&temporary, &link).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => debug!("Pointed {:?} at {:?}", link, target),
+        Err(e) => warn!("Failed to point {:?} at {:?}: {}", link, target, e),
+    }
+}
+
+#[cfg(not(unix))]
+async fn link_current(_path: &Path, _target: &Path) {}
+
+/// The file name of `path` as the stem and the extension with its dot, e.g. `.jsonl`
+fn split_name(path: &Path) -> (String, String) {
+    let stem = path
+        .file_stem()
+        .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+    let extension = path.extension().map_or_else(String::new, |extension| {
+        format!(".{}", extension.to_string_lossy())
+    });
+    (stem, extension)
+}
+
+fn segment_path(path: &Path, unix_ms: u64) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", unix_ms));
+    PathBuf::from(name)
+}
+
+fn is_compressed(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "gz")
+}
+
+/// Sorts the files rotated from `path` oldest first: by the time in the name of size rotated
+/// files and by the date of daily ones. `None` for other files.
+fn rotated_at(name: &str, path: &Path, schedule: Option<Schedule>) -> Option<i64> {
+    let name = name.strip_suffix(".gz").unwrap_or(name);
+    match schedule {
+        None => {
+            let prefix = format!("{}.", path.file_name()?.to_str()?);
+            name.strip_prefix(&prefix)?.parse().ok()
+        }
+        Some(Schedule::Daily) => {
+            let (stem, extension) = split_name(path);
+            let date = name
+                .strip_prefix(&stem)?
+                .strip_prefix('-')?
+                .strip_suffix(&extension)?;
+            let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+            Some(i64::from(date.num_days_from_ce()))
+        }
+    }
+}
+
+/// Rotated files of `path`, oldest first, leaving out `current`
+fn segments(path: &Path, rotation: &Rotation, current: &Path) -> io::Result<Vec<PathBuf>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let rotated_at = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| rotated_at(name, path, rotation.schedule));
+        if let Some(rotated_at) = rotated_at {
+            if Some(entry.file_name().as_os_str()) != current.file_name() {
+                segments.push((rotated_at, entry.path()));
+            }
+        }
+    }
+    segments.sort();
+    Ok(segments.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Gzips `path` into `<path>.gz` and removes the original. The archive is written under a
+/// temporary name first, so a crash never leaves a truncated `.gz` behind.
+fn compress(path: &Path) -> io::Result<PathBuf> {
+    let mut compressed = path.as_os_str().to_os_string();
+    compressed.push(".gz");
+    let compressed = PathBuf::from(compressed);
+    let mut partial = compressed.as_os_str().to_os_string();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    let mut encoder = GzEncoder::new(fs::File::create(&partial)?, Compression::default());
+    io::copy(&mut fs::File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::rename(&partial, &compressed)?;
+    fs::remove_file(path)?;
+    Ok(compressed)
+}
+
+/// Compresses rotated files, then deletes all but the newest `keep` of them. Compressing first
+/// means a file only counts toward the limit in its final form.
+fn housekeep_once(path: &Path, rotation: &Rotation, current: &Path) -> io::Result<()> {
+    if rotation.compress {
+        for segment in segments(path, rotation, current)? {
+            if !is_compressed(&segment) {
+                let compressed = compress(&segment)?;
+                debug!("Compressed {:?} to {:?}", segment, compressed);
+            }
+        }
+    }
+    if let Some(keep) = rotation.keep {
+        let segments = segments(path, rotation, current)?;
+        for old in &segments[..segments.len().saturating_sub(keep)] {
+            fs::remove_file(old)?;
+            debug!("Removed {:?}", old);
+        }
+    }
+    Ok(())
+}
+
+/// Runs a housekeeping pass on start and after every rotation, on a blocking thread so
+/// compressing a large file doesn't hold up the writer. Rotations during a pass are covered by
+/// a single pass after it.
+async fn housekeep(path: PathBuf, rotation: Rotation, mut current: watch::Receiver<PathBuf>) {
+    if !rotation.compress && rotation.keep.is_none() {
+        return;
+    }
+    loop {
+        let path = path.clone();
+        let rotation = rotation.clone();
+        let current_path = current.borrow_and_update().clone();
+        let pass = move || housekeep_once(&path, &rotation, &current_path);
+        match tokio::task::spawn_blocking(pass).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Housekeeping of rotated files failed: {}", e),
+            Err(e) => warn!("Housekeeping of rotated files panicked: {}", e),
+        }
+        if current.changed().await.is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+use log::{trace, warn};
+use rdkafka::config::ClientConfig;
+use rdkafka::error::KafkaError;
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+
+use super::Sink;
+
+pub struct KafkaSink {
+    producer: FutureProducer,
+    topic: String,
+}
+
+impl KafkaSink {
+    pub fn new(brokers: &str, topic: &str) -> Result<KafkaSink, KafkaError> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", "30000")
+            .create()?;
+        Ok(KafkaSink {
+            producer,
+            topic: topic.to_string(),
+        })
+    }
+}
+
+impl Sink for KafkaSink {
+    fn name(&self) -> &'static str {
+        "Kafka"
+    }
+
+    async fn publish(&mut self, mac: Option<&str>, payload: String) {
+        // Keyed by MAC so all readings of a tag land on the same partition, in order
+        let mut record = FutureRecord::to(&self.topic).payload(&payload);
+        if let Some(mac) = mac {
+            record = record.key(mac);
+        }
+        // send_result only enqueues; the delivery report is awaited off the scan's path
+        match self.producer.send_result(record) {
+            Ok(delivery) => {
+                tokio::spawn(async move {
+                    match delivery.await {
+                        Ok(Ok((partition, offset))) => {
+                            trace!("Delivered to Kafka partition {} at {}", partition, offset)
+                        }
+                        Ok(Err((e, _))) => warn!("Kafka delivery failed: {}", e),
+                        Err(_) => warn!("Kafka delivery was cancelled"),
+                    }
+                });
+            }
+            Err((e, _)) => warn!("Failed to enqueue Kafka message: {}", e),
+        }
+    }
+}
+
+impl Drop for KafkaSink {
+    fn drop(&mut self) {
+        let _ = self.producer.flush(Duration::from_secs(5));
+    }
+}
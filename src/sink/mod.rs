@@ -0,0 +1,63 @@
+//! Outputs that publish every reading to an external system instead of serving clients.
+
+use std::sync::Arc;
+
+use log::warn;
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+use ruuvi_sensor_protocol::MacAddress;
+
+use crate::reading::Reading;
+use crate::{mac, metrics, output, overflow};
+
+pub mod file;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "nats")]
+pub mod nats;
+#[cfg(feature = "redis")]
+pub mod redis;
+
+/// Something readings can be published to. Implementations must not hold up the caller for
+/// long; slow deliveries belong in a background task.
+#[cfg_attr(
+    not(any(feature = "kafka", feature = "nats", feature = "redis")),
+    allow(dead_code)
+)]
+pub trait Sink {
+    fn name(&self) -> &'static str;
+
+    /// Publishes a reading serialized as JSON, keyed by its tag's MAC address when it has one
+    fn publish(
+        &mut self,
+        mac: Option<&str>,
+        payload: String,
+    ) -> impl std::future::Future<Output = ()> + Send;
+}
+
+/// Feeds readings from the broadcast to `sink` until the channel closes
+#[cfg_attr(
+    not(any(feature = "kafka", feature = "nats", feature = "redis")),
+    allow(dead_code)
+)]
+pub async fn run<S: Sink>(
+    mut sink: S,
+    mut receiver: broadcast::Receiver<Reading>,
+    options: Arc<output::Options>,
+) {
+    loop {
+        match overflow::recv(&mut receiver).await {
+            Ok(reading) => {
+                let mac = reading.values.mac_address().map(|m| mac::format(&m));
+                let payload = Value::Object(output::to_json(&reading, &options)).to_string();
+                sink.publish(mac.as_deref(), payload).await;
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("{} sink lagged, skipped {} readings", sink.name(), n);
+                metrics::inc_broadcast_skipped(n);
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
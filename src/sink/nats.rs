@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use async_nats::{Client, ConnectError, ConnectOptions, Event};
+use log::{info, warn};
+
+use super::Sink;
+
+/// Publishes readings on `<prefix>.<mac>` subjects, or on `<prefix>` for readings without a MAC
+/// address. The client reconnects by itself and buffers publishes while disconnected.
+pub struct NatsSink {
+    client: Client,
+    subject_prefix: String,
+}
+
+impl NatsSink {
+    /// Starts connecting in the background; `connected` follows the connection state
+    pub async fn new(
+        url: &str,
+        subject_prefix: &str,
+        connected: Arc<AtomicBool>,
+    ) -> Result<NatsSink, ConnectError> {
+        let client = ConnectOptions::new()
+            .retry_on_initial_connect()
+            .event_callback(move |event| {
+                let connected = connected.clone();
+                async move {
+                    match event {
+                        Event::Connected => {
+                            info!("Connected to NATS");
+                            connected.store(true, Ordering::Relaxed);
+                        }
+                        Event::Disconnected | Event::Closed => {
+                            warn!("NATS connection {}", event);
+                            connected.store(false, Ordering::Relaxed);
+                        }
+                        _ => warn!("NATS: {}", event),
+                    }
+                }
+            })
+            .connect(url)
+            .await?;
+        Ok(NatsSink {
+            client,
+            subject_prefix: subject_prefix.to_string(),
+        })
+    }
+}
+
+impl Sink for NatsSink {
+    fn name(&self) -> &'static str {
+        "NATS"
+    }
+
+    async fn publish(&mut self, mac: Option<&str>, payload: String) {
+        let subject = match mac {
+            Some(mac) => format!("{}.{}", self.subject_prefix, mac),
+            None => self.subject_prefix.clone(),
+        };
+        if let Err(e) = self.client.publish(subject, payload.into()).await {
+            warn!("Failed to publish reading to NATS: {}", e);
+        }
+    }
+}
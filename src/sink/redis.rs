@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use redis::aio::MultiplexedConnection;
+use redis::{Client, RedisError};
+use tokio::time::{timeout, Instant};
+
+use super::Sink;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Publishes readings on `<prefix>:<mac>` channels, or on `<prefix>` for readings without a MAC
+/// address. While Redis is unreachable readings are dropped, and reconnecting is attempted with
+/// exponential backoff.
+pub struct RedisSink {
+    client: Client,
+    channel_prefix: String,
+    connection: Option<MultiplexedConnection>,
+    backoff: Duration,
+    next_attempt: Instant,
+}
+
+impl RedisSink {
+    pub fn new(url: &str, channel_prefix: &str) -> Result<RedisSink, RedisError> {
+        Ok(RedisSink {
+            client: Client::open(url)?,
+            channel_prefix: channel_prefix.to_string(),
+            connection: None,
+            backoff: INITIAL_BACKOFF,
+            next_attempt: Instant::now(),
+        })
+    }
+
+    async fn connection(&mut self) -> Option<&mut MultiplexedConnection> {
+        if self.connection.is_none() && Instant::now() >= self.next_attempt {
+            let connect = self.client.get_multiplexed_tokio_connection();
+            match timeout(CONNECT_TIMEOUT, connect).await {
+                Ok(Ok(connection)) => {
+                    info!("Connected to Redis");
+                    self.connection = Some(connection);
+                    self.backoff = INITIAL_BACKOFF;
+                }
+                Ok(Err(e)) => self.back_off(&e.to_string()),
+                Err(_) => self.back_off("connection timed out"),
+            }
+        }
+        self.connection.as_mut()
+    }
+
+    fn back_off(&mut self, reason: &str) {
+        warn!(
+            "Could not connect to Redis ({}), retrying in {:?}",
+            reason, self.backoff
+        );
+        self.next_attempt = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+impl Sink for RedisSink {
+    fn name(&self) -> &'static str {
+        "Redis"
+    }
+
+    async fn publish(&mut self, mac: Option<&str>, payload: String) {
+        let channel = match mac {
+            Some(mac) => format!("{}:{}", self.channel_prefix, mac),
+            None => self.channel_prefix.clone(),
+        };
+        let connection = match self.connection().await {
+            Some(connection) => connection,
+            None => {
+                debug!("Not connected to Redis, dropping reading for {}", channel);
+                return;
+            }
+        };
+        let published: Result<i64, RedisError> = redis::cmd("PUBLISH")
+            .arg(&channel)
+            .arg(payload)
+            .query_async(connection)
+            .await;
+        if let Err(e) = published {
+            warn!("Lost Redis connection: {}", e);
+            self.connection = None;
+        }
+    }
+}
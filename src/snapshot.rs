@@ -0,0 +1,61 @@
+//! The newest reading of every tag as one JSON array, served at `GET /snapshot` and
+//! periodically written to a file.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::{trace, warn};
+use serde_json::Value;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::time::{interval, Duration};
+
+use crate::cache::LastValues;
+use crate::output;
+
+/// Readings ordered by MAC address so consecutive snapshots diff cleanly
+pub fn to_json(cache: &LastValues, options: &output::Options) -> Value {
+    let cache = cache.lock().unwrap();
+    let mut readings: Vec<_> = cache.iter().collect();
+    readings.sort_by_key(|(mac, _)| **mac);
+    Value::Array(
+        readings
+            .into_iter()
+            .map(|(_, reading)| Value::Object(output::to_json(reading, options)))
+            .collect(),
+    )
+}
+
+/// Writes next to `path` and renames over it, so readers see either the previous snapshot or
+/// the new one but never a partial file
+async fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temp_name = OsString::from(".");
+    temp_name.push(path.file_name().unwrap_or_default());
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let mut file = fs::File::create(&temp_path).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(&temp_path, path).await
+}
+
+/// Writes the snapshot to `path` every `period`, forever
+pub async fn run(
+    cache: LastValues,
+    options: Arc<output::Options>,
+    path: PathBuf,
+    period: Duration,
+) {
+    let mut ticks = interval(period);
+    loop {
+        ticks.tick().await;
+        let contents = to_json(&cache, &options).to_string();
+        match write_atomically(&path, contents.as_bytes()).await {
+            Ok(()) => trace!("Wrote snapshot to {:?}", path),
+            Err(e) => warn!("Failed to write snapshot to {:?}: {}", path, e),
+        }
+    }
+}
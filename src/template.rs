@@ -0,0 +1,155 @@
+//! User supplied output line format like `{mac} {temperature_c}C {humidity_pct}%`, used in place
+//! of the JSON object on the data socket.
+
+use std::str::FromStr;
+
+use serde_json::{Map, Value};
+
+use ruuvi_sensor_protocol::MacAddress;
+
+use crate::reading::{unix_millis, Reading};
+use crate::{fields, mac, output};
+
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    Placeholder(String),
+}
+
+/// A format string with `{name}` placeholders; `{{` and `}}` stand for literal braces
+#[derive(Clone, Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some('{') | None => {
+                                return Err(format!("unclosed placeholder in {:?}", s))
+                            }
+                            Some(c) => name.push(c),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder(name.trim().to_string()));
+                }
+                '}' => return Err(format!("unmatched }} in {:?}, write }}}} for a brace", s)),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+}
+
+impl Template {
+    /// Fills in the placeholders from the reading: `mac`, `ts` (Unix time in milliseconds), the
+    /// friendly metric names like `temperature_c` and any field of the JSON `object`. Unknown
+    /// placeholders and absent values render as `options.template_missing`.
+    pub fn render(
+        &self,
+        reading: &Reading,
+        object: &Map<String, Value>,
+        options: &output::Options,
+    ) -> String {
+        let mut line = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => line.push_str(literal),
+                Part::Placeholder(name) => {
+                    let value = match name.as_str() {
+                        "mac" => reading.values.mac_address().map(|m| mac::format(&m)),
+                        "ts" => Some(unix_millis().to_string()),
+                        name if fields::NAMES.contains(&name) => {
+                            fields::value(&reading.values, name)
+                                .map(|v| options.float(v).to_string())
+                        }
+                        name => match object.get(options.key(name)) {
+                            None | Some(Value::Null) => None,
+                            Some(Value::String(s)) => Some(s.clone()),
+                            Some(value) => Some(value.to_string()),
+                        },
+                    };
+                    line.push_str(value.as_deref().unwrap_or(&options.template_missing));
+                }
+            }
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::output::tests::{options, reading};
+
+    fn render(template: &str, options: &output::Options) -> String {
+        let template: Template = template.parse().unwrap();
+        let reading = reading();
+        let object = output::to_json(&reading, options);
+        template.render(&reading, &object, options)
+    }
+
+    #[test]
+    fn fills_in_placeholders() {
+        assert_eq!(
+            render("{mac} {temperature_c}C {humidity_pct}%", &options()),
+            "CB:B8:33:4C:88:4F 24.3C 53.49%"
+        );
+        assert_eq!(
+            render("seq={ measurement_sequence_number }", &options()),
+            "seq=205"
+        );
+    }
+
+    #[test]
+    fn missing_fields_render_as_template_missing() {
+        let options = output::Options {
+            template_missing: "-".to_string(),
+            ..options()
+        };
+        assert_eq!(render("{nonexistent} {seen_count}", &options), "- -");
+        assert_eq!(render("{nonexistent}", &output::tests::options()), "");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(
+            render("{{\"t\": {temperature_c}}}", &options()),
+            "{\"t\": 24.3}"
+        );
+        assert_eq!(render("{{}}", &options()), "{}");
+    }
+
+    #[test]
+    fn rejects_unbalanced_braces() {
+        assert!("{mac".parse::<Template>().is_err());
+        assert!("{mac{}".parse::<Template>().is_err());
+        assert!("mac}".parse::<Template>().is_err());
+    }
+}
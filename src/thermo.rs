@@ -0,0 +1,121 @@
+//! Thermodynamic quantities derived from temperature and relative humidity.
+
+/// Magnus formula coefficients over water (Sonntag 1990)
+const MAGNUS_A_HPA: f64 = 6.112;
+const MAGNUS_B: f64 = 17.62;
+const MAGNUS_C_CELSIUS: f64 = 243.12;
+/// Heat indices below this come from Steadman's simple formula, the Rothfusz regression doesn't
+/// fit there
+const HEAT_INDEX_MIN_FAHRENHEIT: f64 = 80.0;
+
+pub fn celsius_to_fahrenheit(celsius: f64) -> f64 {
+    celsius * 9.0 / 5.0 + 32.0
+}
+
+pub fn fahrenheit_to_celsius(fahrenheit: f64) -> f64 {
+    (fahrenheit - 32.0) * 5.0 / 9.0
+}
+
+/// Saturation vapor pressure in hPa at the given temperature
+pub fn saturation_vapor_pressure_hpa(celsius: f64) -> f64 {
+    MAGNUS_A_HPA * (MAGNUS_B * celsius / (MAGNUS_C_CELSIUS + celsius)).exp()
+}
+
+/// Dew point in Celsius; `None` for zero humidity, where there is none
+pub fn dew_point_celsius(celsius: f64, relative_humidity_pct: f64) -> Option<f64> {
+    if relative_humidity_pct <= 0.0 {
+        return None;
+    }
+    let vapor_pressure = relative_humidity_pct / 100.0 * saturation_vapor_pressure_hpa(celsius);
+    let gamma = (vapor_pressure / MAGNUS_A_HPA).ln();
+    Some(MAGNUS_C_CELSIUS * gamma / (MAGNUS_B - gamma))
+}
+
+/// Heat index, the apparent temperature, in Celsius by the procedure of the US National Weather
+/// Service, https://www.wpc.ncep.noaa.gov/html/heatindex_equation.shtml: Steadman's simple
+/// formula averaged with the temperature, and where that comes to 80 °F or more the Rothfusz
+/// regression, adjusted for very dry and for humid air
+pub fn heat_index_celsius(celsius: f64, relative_humidity_pct: f64) -> f64 {
+    let t = celsius_to_fahrenheit(celsius);
+    let rh = relative_humidity_pct;
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    if simple < HEAT_INDEX_MIN_FAHRENHEIT {
+        return fahrenheit_to_celsius(simple);
+    }
+    let mut heat_index = -42.379 + 2.049_015_23 * t + 10.143_331_27 * rh
+        - 0.224_755_41 * t * rh
+        - 0.006_837_83 * t * t
+        - 0.054_817_17 * rh * rh
+        + 0.001_228_74 * t * t * rh
+        + 0.000_852_82 * t * rh * rh
+        - 0.000_001_99 * t * t * rh * rh;
+    if rh < 13.0 && (80.0..=112.0).contains(&t) {
+        heat_index -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+    } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+        heat_index += (rh - 85.0) / 10.0 * ((87.0 - t) / 5.0);
+    }
+    fahrenheit_to_celsius(heat_index)
+}
+
+/// Vapor pressure deficit in kPa: how far the air is from saturation
+pub fn vapor_pressure_deficit_kpa(celsius: f64, relative_humidity_pct: f64) -> f64 {
+    let saturation_kpa = saturation_vapor_pressure_hpa(celsius) / 10.0;
+    saturation_kpa * (1.0 - relative_humidity_pct / 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vapor_pressure_deficit_reference_points() {
+        // Saturation vapor pressure is 3.17 kPa at 25 °C and 2.34 kPa at 20 °C
+        assert!((vapor_pressure_deficit_kpa(25.0, 50.0) - 1.58).abs() < 0.01);
+        assert!((vapor_pressure_deficit_kpa(20.0, 60.0) - 0.93).abs() < 0.01);
+        assert!((vapor_pressure_deficit_kpa(25.0, 0.0) - 3.16).abs() < 0.01);
+    }
+
+    #[test]
+    fn vapor_pressure_deficit_of_saturated_air_is_zero() {
+        assert_eq!(vapor_pressure_deficit_kpa(25.0, 100.0), 0.0);
+    }
+
+    #[test]
+    fn heat_index_reference_points() {
+        // The heat index table of the US National Weather Service: 90 °F at 50 % feels like
+        // 95 °F, 80 °F at 40 % like 80 °F
+        let feels_like = |fahrenheit: f64, rh: f64| {
+            celsius_to_fahrenheit(heat_index_celsius(fahrenheit_to_celsius(fahrenheit), rh))
+        };
+        assert!((feels_like(90.0, 50.0) - 95.0).abs() < 0.5);
+        assert!((feels_like(80.0, 40.0) - 80.0).abs() < 0.5);
+        assert!((feels_like(96.0, 60.0) - 116.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn heat_index_below_80_fahrenheit_by_simple_formula() {
+        let feels_like = |fahrenheit: f64, rh: f64| {
+            celsius_to_fahrenheit(heat_index_celsius(fahrenheit_to_celsius(fahrenheit), rh))
+        };
+        assert!((feels_like(68.0, 90.0) - 68.73).abs() < 0.01);
+        // Humid enough for the regression, though the air is below 80 °F
+        assert!((feels_like(79.0, 90.0) - 82.98).abs() < 0.01);
+    }
+
+    #[test]
+    fn heat_index_adjusted_for_dry_and_humid_air() {
+        let feels_like = |fahrenheit: f64, rh: f64| {
+            celsius_to_fahrenheit(heat_index_celsius(fahrenheit_to_celsius(fahrenheit), rh))
+        };
+        // The regression alone gives 94.75 °F and 87.19 °F
+        assert!((feels_like(100.0, 10.0) - 94.12).abs() < 0.01);
+        assert!((feels_like(80.0, 100.0) - 89.29).abs() < 0.01);
+    }
+
+    #[test]
+    fn fahrenheit_conversions_round_trip() {
+        assert_eq!(celsius_to_fahrenheit(100.0), 212.0);
+        assert_eq!(fahrenheit_to_celsius(32.0), 0.0);
+        assert!((fahrenheit_to_celsius(celsius_to_fahrenheit(24.3)) - 24.3).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,122 @@
+//! How `received_at` is written: as milliseconds or seconds since the Unix epoch, or as an
+//! RFC 3339 time like `2024-01-02T03:04:05.678Z`.
+
+use std::str::FromStr;
+
+use chrono::{Local, LocalResult, SecondsFormat, TimeZone, Utc};
+use serde_json::{json, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Iso,
+    EpochMs,
+    EpochS,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "iso" => Ok(Format::Iso),
+            "epoch-ms" => Ok(Format::EpochMs),
+            "epoch-s" => Ok(Format::EpochS),
+            _ => Err(format!("expected iso, epoch-ms or epoch-s, got {:?}", s)),
+        }
+    }
+}
+
+/// The time zone of the ISO form; the epoch forms are the same in any
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Zone {
+    Utc,
+    Local,
+    /// A zone of the tz database like `Europe/Helsinki`
+    #[cfg(feature = "tz")]
+    Named(chrono_tz::Tz),
+}
+
+impl FromStr for Zone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utc" => Ok(Zone::Utc),
+            "local" => Ok(Zone::Local),
+            #[cfg(feature = "tz")]
+            _ => s
+                .parse()
+                .map(Zone::Named)
+                .map_err(|_| format!("expected utc, local or a tz database zone, got {:?}", s)),
+            #[cfg(not(feature = "tz"))]
+            _ => Err(format!(
+                "expected utc or local, got {:?}; named zones need the tz feature",
+                s
+            )),
+        }
+    }
+}
+
+/// Milliseconds precision, with `Z` for UTC and the offset otherwise
+fn iso(unix_ms: u64, zone: Zone) -> Option<String> {
+    let time = match Utc.timestamp_millis_opt(i64::try_from(unix_ms).ok()?) {
+        LocalResult::Single(time) => time,
+        _ => return None,
+    };
+    Some(match zone {
+        Zone::Utc => time.to_rfc3339_opts(SecondsFormat::Millis, true),
+        Zone::Local => time
+            .with_timezone(&Local)
+            .to_rfc3339_opts(SecondsFormat::Millis, true),
+        #[cfg(feature = "tz")]
+        Zone::Named(tz) => time
+            .with_timezone(&tz)
+            .to_rfc3339_opts(SecondsFormat::Millis, true),
+    })
+}
+
+pub fn to_json(unix_ms: u64, format: Format, zone: Zone) -> Value {
+    match format {
+        Format::Iso => json!(iso(unix_ms, zone)),
+        Format::EpochMs => json!(unix_ms),
+        Format::EpochS => json!(unix_ms / 1000),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-02T03:04:05.678Z
+    const UNIX_MS: u64 = 1_704_164_645_678;
+
+    #[test]
+    fn iso_in_utc() {
+        assert_eq!(
+            to_json(UNIX_MS, Format::Iso, Zone::Utc),
+            json!("2024-01-02T03:04:05.678Z")
+        );
+    }
+
+    #[test]
+    fn iso_keeps_milliseconds_when_zero() {
+        assert_eq!(
+            to_json(1_704_164_645_000, Format::Iso, Zone::Utc),
+            json!("2024-01-02T03:04:05.000Z")
+        );
+    }
+
+    #[test]
+    fn epoch_forms() {
+        assert_eq!(to_json(UNIX_MS, Format::EpochMs, Zone::Utc), json!(UNIX_MS));
+        assert_eq!(
+            to_json(UNIX_MS, Format::EpochS, Zone::Utc),
+            json!(1_704_164_645u64)
+        );
+    }
+
+    #[test]
+    fn iso_out_of_range_is_null() {
+        assert_eq!(to_json(u64::MAX, Format::Iso, Zone::Utc), Value::Null);
+    }
+}
@@ -0,0 +1,89 @@
+//! TLS for the data socket, optionally requiring client certificates signed by a given CA.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use log::{info, warn};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {:?}", path).into());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(format!("no private key in {:?}", path).into())
+}
+
+/// Server config from PEM files. With `client_ca` clients have to present a certificate signed
+/// by one of the CA certificates in it, and those that don't are refused in the handshake.
+pub fn acceptor(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in load_certs(path)? {
+                roots.add(&ca)?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Completes the handshake, logging the subject of the client certificate if there is one.
+/// `None` if the handshake fails or takes too long.
+pub async fn accept(acceptor: &TlsAcceptor, socket: TcpStream) -> Option<TlsStream<TcpStream>> {
+    let peer = socket.peer_addr().ok();
+    let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            warn!("TLS handshake with {:?} failed: {}", peer, e);
+            return None;
+        }
+        Err(_) => {
+            warn!("TLS handshake with {:?} timed out", peer);
+            return None;
+        }
+    };
+    let subject = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|cert| x509_parser::parse_x509_certificate(&cert.0).ok())
+        .map(|(_, cert)| cert.subject().to_string());
+    if let Some(subject) = subject {
+        info!("TLS client {:?} authenticated as {}", peer, subject);
+    }
+    Some(stream)
+}
@@ -0,0 +1,382 @@
+//! Per-tag state kept by the scan loop across readings.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use ruuvi_sensor_protocol::{
+    Humidity, MacAddress, MeasurementSequenceNumber, Pressure, SensorValues, Temperature,
+};
+
+use crate::lru::MacLru;
+use crate::mac;
+use crate::metrics::{self, Buckets, Histogram};
+use crate::outlier::{self, Baseline, Limit};
+use crate::reading::{unix_millis, Reading};
+
+/// Weight of the newest inter-arrival time in the advertising interval estimate
+const INTERVAL_EMA_ALPHA: f64 = 0.2;
+/// Inter-arrival times averaged before the advertising interval estimate is given out
+const INTERVAL_MIN_SAMPLES: u32 = 5;
+/// How close to the end of its range a measurement sequence number has to be, and the next one
+/// to the start, for the drop between them to be the counter wrapping around
+const SEQUENCE_WRAP_MARGIN: u32 = 1024;
+
+/// Exponential moving average; the first sample seeds it
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ema {
+    value: Option<f64>,
+}
+
+impl Ema {
+    pub fn update(&mut self, alpha: f64, sample: f64) -> f64 {
+        let next = match self.value {
+            Some(previous) => alpha * sample + (1.0 - alpha) * previous,
+            None => sample,
+        };
+        self.value = Some(next);
+        next
+    }
+}
+
+/// First-order low-pass filter with a time constant. Readings arrive irregularly, so the
+/// smoothing factor comes from the time actually elapsed since the previous sample.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LowPass {
+    state: Option<(f64, Instant)>,
+}
+
+impl LowPass {
+    pub fn update(&mut self, tau_secs: f64, sample: f64, now: Instant) -> f64 {
+        let next = match self.state {
+            Some((previous, at)) => {
+                let dt = now.duration_since(at).as_secs_f64();
+                let alpha = 1.0 - (-dt / tau_secs).exp();
+                previous + alpha * (sample - previous)
+            }
+            None => sample,
+        };
+        self.state = Some((next, now));
+        next
+    }
+}
+
+/// The previous temperature and pressure of a tag, and the rates of change derived so far
+#[derive(Debug, Default)]
+struct Rates {
+    previous: Option<(Option<i32>, Option<u32>, Instant)>,
+    temperature_ema: Ema,
+    pressure_ema: Ema,
+}
+
+/// Change per minute from `previous` to `current`; `None` unless both are there
+fn rate_per_minute(previous: Option<f64>, current: Option<f64>, minutes: f64) -> Option<f64> {
+    Some((current? - previous?) / minutes)
+}
+
+/// Whether the measurement sequence going from `previous` to `current` means the tag restarted.
+/// Sequence numbers are 16 bits, so a drop from near the top of the range to near the bottom is
+/// the counter wrapping around instead.
+fn sequence_restarted(previous: u32, current: u32) -> bool {
+    let wrapped =
+        previous >= u32::from(u16::MAX) - SEQUENCE_WRAP_MARGIN && current < SEQUENCE_WRAP_MARGIN;
+    current < previous && !wrapped
+}
+
+#[derive(Debug, Default)]
+struct Smoothing {
+    temperature_ema: Ema,
+    humidity_ema: Ema,
+    temperature_lowpass: LowPass,
+    rates: Rates,
+}
+
+#[derive(Debug)]
+struct TagState {
+    /// Kept for the lifetime of the process, whatever gaps there are in between
+    first_seen_unix_ms: u64,
+    /// Readings received for the tag, whether broadcast or dropped
+    seen_count: u64,
+    last_seen: Instant,
+    /// Average time between the tag's readings and how many went into it
+    interval_ema: Ema,
+    interval_samples: u32,
+    last_sequence: Option<u32>,
+    /// Reset whenever the tag's measurement sequence restarts, i.e. the tag rebooted
+    smoothing: Smoothing,
+    outlier_baseline: Baseline,
+}
+
+pub struct Config {
+    pub interarrival_buckets: Buckets,
+    /// Weight of the newest sample in the temperature and humidity EMAs
+    pub ema_alpha: Option<f64>,
+    /// Time constant of the temperature low-pass filter
+    pub lowpass_tau_secs: Option<f64>,
+    /// Derive the temperature and pressure change rates, smoothed with `ema_alpha` if given
+    pub change_rates: bool,
+    /// Readings outside these limits are dropped; empty to keep everything
+    pub outlier_limits: Vec<Limit>,
+    pub max_tracked_macs: usize,
+}
+
+/// When a tag was first seen and how many of its readings have been received
+#[derive(Clone, Copy, Debug)]
+pub struct Seen {
+    pub first_seen_unix_ms: u64,
+    pub seen_count: u64,
+}
+
+/// `Seen` per tag, kept up to date by the tracker for `GET /stats`
+pub type SeenTags = Arc<Mutex<MacLru<Seen>>>;
+
+/// How a reading arrived relative to the tag's previous ones
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Arrival {
+    first_seen_unix_ms: Option<u64>,
+    seen_count: Option<u64>,
+    since_previous: Option<Duration>,
+    estimated_interval_secs: Option<f64>,
+}
+
+pub struct Tracker {
+    tags: MacLru<TagState>,
+    /// The same tags as `tags`, evicted along with them
+    seen: SeenTags,
+    config: Config,
+}
+
+impl Tracker {
+    pub fn new(config: Config) -> Tracker {
+        Tracker {
+            tags: MacLru::new(config.max_tracked_macs),
+            seen: Arc::new(Mutex::new(MacLru::new(config.max_tracked_macs))),
+            config,
+        }
+    }
+
+    pub fn seen(&self) -> SeenTags {
+        self.seen.clone()
+    }
+
+    /// Counts a reading of the tag and times it against the previous one. Call it for every
+    /// reading received, before any are dropped, so the counts and intervals go by what the tag
+    /// actually sent. Readings without a MAC address can't be attributed to a tag.
+    pub fn arrive(&mut self, sv: &SensorValues, now: Instant) -> Arrival {
+        let mac = match sv.mac_address() {
+            Some(mac) => mac,
+            None => return Arrival::default(),
+        };
+        let sequence = sv.measurement_sequence_number();
+
+        let mut since_previous = None;
+        let mut estimated_interval_secs = None;
+        let state = match self.tags.get_mut(&mac) {
+            Some(state) => {
+                since_previous = Some(now.duration_since(state.last_seen));
+                let elapsed = now.duration_since(state.last_seen).as_secs_f64();
+                metrics::METRICS
+                    .interarrival
+                    .lock()
+                    .unwrap()
+                    .entry(mac)
+                    .or_insert_with(|| Histogram::new(&self.config.interarrival_buckets))
+                    .observe(elapsed);
+                let interval = state.interval_ema.update(INTERVAL_EMA_ALPHA, elapsed);
+                state.interval_samples = state.interval_samples.saturating_add(1);
+                if state.interval_samples >= INTERVAL_MIN_SAMPLES {
+                    estimated_interval_secs = Some(interval);
+                }
+
+                if let (Some(previous), Some(current)) = (state.last_sequence, sequence) {
+                    if sequence_restarted(previous, current) {
+                        state.smoothing = Smoothing::default();
+                    }
+                }
+                state.last_seen = now;
+                state.last_sequence = sequence;
+                state
+            }
+            None => {
+                metrics::inc(&metrics::METRICS.tags_seen);
+                let state = TagState {
+                    first_seen_unix_ms: unix_millis(),
+                    seen_count: 0,
+                    last_seen: now,
+                    interval_ema: Ema::default(),
+                    interval_samples: 0,
+                    last_sequence: sequence,
+                    smoothing: Smoothing::default(),
+                    outlier_baseline: Baseline::default(),
+                };
+                if let Some((evicted, _)) = self.tags.insert(mac, state) {
+                    debug!("Forgetting {}, too many tags", mac::format(&evicted));
+                    self.seen.lock().unwrap().remove(&evicted);
+                    metrics::METRICS
+                        .interarrival
+                        .lock()
+                        .unwrap()
+                        .remove(&evicted);
+                }
+                self.tags.get_mut(&mac).expect("just inserted")
+            }
+        };
+        state.seen_count += 1;
+        let seen = Seen {
+            first_seen_unix_ms: state.first_seen_unix_ms,
+            seen_count: state.seen_count,
+        };
+        self.seen.lock().unwrap().insert(mac, seen);
+
+        Arrival {
+            first_seen_unix_ms: Some(state.first_seen_unix_ms),
+            seen_count: Some(state.seen_count),
+            since_previous,
+            estimated_interval_secs,
+        }
+    }
+
+    /// Derives the per-tag fields of a reading whose `arrival` was recorded, or returns `None`
+    /// if the reading is rejected as an outlier. Readings without a MAC address are passed
+    /// through as is.
+    pub fn process(&mut self, sv: SensorValues, arrival: Arrival, now: Instant) -> Option<Reading> {
+        if let Err(offending) = outlier::check_bounds(&self.config.outlier_limits, &sv) {
+            debug!(
+                "Dropping outlier from {:?}: {}",
+                sv.mac_address(),
+                offending
+            );
+            return None;
+        }
+
+        let mut reading = Reading::new(sv, now);
+        reading.first_seen_unix_ms = arrival.first_seen_unix_ms;
+        reading.seen_count = arrival.seen_count;
+        reading.since_previous_ms = arrival.since_previous.map(|d| d.as_millis() as u64);
+        reading.estimated_interval_ms = arrival
+            .estimated_interval_secs
+            .map(|secs| (secs * 1000.0).round() as u64);
+
+        let mac = match reading.values.mac_address() {
+            Some(mac) => mac,
+            None => return Some(reading),
+        };
+        let state = match self.tags.get_mut(&mac) {
+            Some(state) => state,
+            None => return Some(reading),
+        };
+
+        if let Err(offending) = state
+            .outlier_baseline
+            .check_jumps(&self.config.outlier_limits, &reading.values)
+        {
+            debug!("Dropping outlier from {:?}: {}", mac, offending);
+            return None;
+        }
+
+        if let Some(alpha) = self.config.ema_alpha {
+            reading.temperature_ema_millicelsius =
+                reading.values.temperature_as_millicelsius().map(|t| {
+                    state
+                        .smoothing
+                        .temperature_ema
+                        .update(alpha, f64::from(t))
+                        .round() as i32
+                });
+            reading.humidity_ema_ppm = reading.values.humidity_as_ppm().map(|h| {
+                state
+                    .smoothing
+                    .humidity_ema
+                    .update(alpha, f64::from(h))
+                    .round() as u32
+            });
+        }
+
+        if let Some(tau) = self.config.lowpass_tau_secs {
+            reading.temperature_filtered_millicelsius =
+                reading.values.temperature_as_millicelsius().map(|t| {
+                    let filtered =
+                        state
+                            .smoothing
+                            .temperature_lowpass
+                            .update(tau, f64::from(t), now);
+                    filtered.round() as i32
+                });
+        }
+
+        if self.config.change_rates {
+            let temperature = reading.values.temperature_as_millicelsius();
+            let pressure = reading.values.pressure_as_pascals();
+            let rates = &mut state.smoothing.rates;
+            if let Some((previous_temperature, previous_pressure, at)) = rates.previous {
+                let minutes = now.duration_since(at).as_secs_f64() / 60.0;
+                let alpha = self.config.ema_alpha;
+                let smooth = |ema: &mut Ema, rate: f64| match alpha {
+                    Some(alpha) => ema.update(alpha, rate),
+                    None => rate,
+                };
+                // Two readings at the same instant have no rate between them
+                if minutes > 0.0 {
+                    reading.temperature_rate_mc_per_min = rate_per_minute(
+                        previous_temperature.map(f64::from),
+                        temperature.map(f64::from),
+                        minutes,
+                    )
+                    .map(|rate| smooth(&mut rates.temperature_ema, rate));
+                    reading.pressure_rate_pa_per_min = rate_per_minute(
+                        previous_pressure.map(f64::from),
+                        pressure.map(f64::from),
+                        minutes,
+                    )
+                    .map(|rate| smooth(&mut rates.pressure_ema, rate));
+                }
+            }
+            rates.previous = Some((temperature, pressure, now));
+        }
+
+        Some(reading)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ema_is_seeded_by_first_sample() {
+        let mut ema = Ema::default();
+        assert_eq!(ema.update(0.2, 10.0), 10.0);
+    }
+
+    #[test]
+    fn ema_weights_newest_sample_by_alpha() {
+        let mut ema = Ema::default();
+        ema.update(0.25, 10.0);
+        assert_eq!(ema.update(0.25, 20.0), 12.5);
+        assert_eq!(ema.update(0.25, 12.5), 12.5);
+        assert_eq!(ema.update(0.25, 4.5), 10.5);
+    }
+
+    #[test]
+    fn ema_with_alpha_one_follows_samples() {
+        let mut ema = Ema::default();
+        ema.update(1.0, 10.0);
+        assert_eq!(ema.update(1.0, -3.0), -3.0);
+    }
+
+    #[test]
+    fn sequence_restarted_on_drop() {
+        assert!(sequence_restarted(500, 3));
+        assert!(sequence_restarted(60_000, 0));
+        assert!(!sequence_restarted(500, 501));
+        assert!(!sequence_restarted(500, 500));
+    }
+
+    #[test]
+    fn sequence_wrapping_is_not_a_restart() {
+        assert!(!sequence_restarted(65_534, 0));
+        assert!(!sequence_restarted(65_530, 5));
+        assert!(!sequence_restarted(u32::from(u16::MAX), 1));
+    }
+}
@@ -0,0 +1,78 @@
+//! Sanity gate against the documented RuuviTag operating ranges. Values outside them can only
+//! come from corrupted advertisements or a broken sensor.
+
+use std::str::FromStr;
+
+use ruuvi_sensor_protocol::SensorValues;
+
+use crate::fields;
+use crate::outlier::Range;
+
+/// Operating temperature of the RuuviTag,
+/// https://ruuvi.com/ruuvitag/ ("Operating temperature -40 °C ... +85 °C")
+pub const TEMPERATURE_C: Range = Range {
+    min: -40.0,
+    max: 85.0,
+};
+
+/// Relative humidity, https://docs.ruuvi.com/ruuvi-sensor-protocols/dataformat-05
+pub const HUMIDITY_PCT: Range = Range {
+    min: 0.0,
+    max: 100.0,
+};
+
+/// Measuring range of the BME280 pressure sensor, 300 ... 1100 hPa,
+/// https://docs.ruuvi.com/ruuvitag/ruuvitag-hardware
+pub const PRESSURE_PA: Range = Range {
+    min: 30_000.0,
+    max: 110_000.0,
+};
+
+/// Battery voltage range representable in data format 5, 1.6 ... 3.646 V,
+/// https://docs.ruuvi.com/ruuvi-sensor-protocols/dataformat-05
+pub const BATTERY_MV: Range = Range {
+    min: 1600.0,
+    max: 3646.0,
+};
+
+const RANGES: &[(&str, Range)] = &[
+    ("temperature_c", TEMPERATURE_C),
+    ("humidity_pct", HUMIDITY_PCT),
+    ("pressure_pa", PRESSURE_PA),
+    ("battery_mv", BATTERY_MV),
+];
+
+/// What to do with a reading outside the ranges
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnInvalid {
+    Drop,
+    /// Pass it on marked with `"suspect": true`
+    Flag,
+}
+
+impl FromStr for OnInvalid {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(OnInvalid::Drop),
+            "flag" => Ok(OnInvalid::Flag),
+            _ => Err(format!("expected drop or flag, got {:?}", s)),
+        }
+    }
+}
+
+/// Returns a description of the first value outside its range
+pub fn check(sv: &SensorValues) -> Result<(), String> {
+    for (metric, range) in RANGES {
+        if let Some(value) = fields::value(sv, metric) {
+            if !range.contains(value) {
+                return Err(format!(
+                    "{} {} outside {}..{}",
+                    metric, value, range.min, range.max
+                ));
+            }
+        }
+    }
+    Ok(())
+}
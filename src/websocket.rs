@@ -0,0 +1,137 @@
+//! WebSocket server streaming the readings, one message per reading. The encoding is picked
+//! with the `Sec-WebSocket-Protocol` handshake header: `ruuvi.json` for JSON text messages and
+//! `ruuvi.msgpack` for MessagePack binary messages. Clients offering neither get JSON.
+
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use log::{debug, info, trace, warn};
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::reading::Reading;
+use crate::{metrics, output, overflow};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Subprotocol {
+    Json,
+    MessagePack,
+}
+
+impl Subprotocol {
+    fn name(self) -> &'static str {
+        match self {
+            Subprotocol::Json => "ruuvi.json",
+            Subprotocol::MessagePack => "ruuvi.msgpack",
+        }
+    }
+
+    /// The first recognized subprotocol in the client's order of preference
+    fn negotiate(request: &Request) -> Option<Subprotocol> {
+        request
+            .headers()
+            .get_all("Sec-WebSocket-Protocol")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(|name| match name.trim() {
+                "ruuvi.json" => Some(Subprotocol::Json),
+                "ruuvi.msgpack" => Some(Subprotocol::MessagePack),
+                _ => None,
+            })
+    }
+
+    fn encode(self, object: serde_json::Map<String, Value>) -> Option<Message> {
+        let value = Value::Object(object);
+        match self {
+            Subprotocol::Json => Some(Message::Text(value.to_string())),
+            Subprotocol::MessagePack => match rmp_serde::to_vec_named(&value) {
+                Ok(bytes) => Some(Message::Binary(bytes)),
+                Err(e) => {
+                    warn!("Failed to encode reading as MessagePack: {}", e);
+                    None
+                }
+            },
+        }
+    }
+}
+
+pub async fn handle_websocket(
+    socket: TcpStream,
+    mut receiver: broadcast::Receiver<Reading>,
+    options: Arc<output::Options>,
+    mut shutdown: broadcast::Receiver<()>,
+    _shutdown_complete: mpsc::Sender<()>,
+) {
+    let peer = socket
+        .peer_addr()
+        .map_or_else(|_| "unknown peer".to_string(), |peer| peer.to_string());
+    let mut subprotocol = None;
+    let negotiate = |request: &Request, mut response: Response| {
+        subprotocol = Subprotocol::negotiate(request);
+        if let Some(chosen) = subprotocol {
+            // Echoing a subprotocol the client didn't offer would fail its handshake
+            response.headers_mut().insert(
+                "Sec-WebSocket-Protocol",
+                HeaderValue::from_static(chosen.name()),
+            );
+        }
+        Ok::<Response, ErrorResponse>(response)
+    };
+    let stream = match tokio_tungstenite::accept_hdr_async(socket, negotiate).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            debug!("WebSocket handshake failed: {:?}", e);
+            return;
+        }
+    };
+    let subprotocol = subprotocol.unwrap_or(Subprotocol::Json);
+    info!(
+        "New WebSocket connection from {} speaking {}",
+        peer,
+        subprotocol.name()
+    );
+    let _dropped_guard = metrics::DroppedGuard::new(&peer);
+
+    let (mut sink, mut incoming) = stream.split();
+    loop {
+        tokio::select! {
+            reading = overflow::recv(&mut receiver) => match reading {
+                Ok(reading) => {
+                    let message = match subprotocol.encode(output::to_json(&reading, &options)) {
+                        Some(message) => message,
+                        None => continue,
+                    };
+                    if let Err(e) = sink.send(message).await {
+                        info!("Closing WebSocket: {:?}", e);
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("WebSocket client {} lagged, skipped {} readings", peer, n);
+                    metrics::inc_broadcast_skipped(n);
+                    metrics::inc_client_dropped(&peer, n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            message = incoming.next() => match message {
+                // Pings are answered by tungstenite, anything else from the client is ignored
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(message)) => trace!("Ignoring WebSocket message {:?}", message),
+                Some(Err(e)) => {
+                    debug!("WebSocket read failed: {:?}", e);
+                    break;
+                }
+            },
+            _ = shutdown.recv() => {
+                let _ = sink.send(Message::Close(None)).await;
+                break;
+            }
+        }
+    }
+    let _ = sink.close().await;
+}